@@ -0,0 +1,117 @@
+use beatmap_parser::borrowed::{self, BorrowedDifficulty};
+use beatmap_parser::info::info::difficulty_beatmap_set::{
+    difficulty_beatmap::DifficultyRank, BeatmapCharacteristic,
+};
+use beatmap_parser::parse::ParseMode;
+use beatmap_parser::source::MemorySource;
+use beatmap_parser::Beatmap;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+/// Builds a dense difficulty file, with custom data on every tenth object
+fn difficulty_file(notes: usize) -> Vec<u8> {
+    let custom_data = |i: usize| {
+        if i.is_multiple_of(10) {
+            json!({ "_color": [1, 0.5, 0], "_position": [i % 4, 1] })
+        } else {
+            json!(null)
+        }
+    };
+    let object = |mut object: serde_json::Value, i: usize| {
+        if i.is_multiple_of(10) {
+            object["_customData"] = custom_data(i);
+        }
+        object
+    };
+
+    let notes: Vec<_> = (0..notes)
+        .map(|i| {
+            let note = json!({
+                "_time": i as f64 / 4.0,
+                "_lineIndex": i % 4,
+                "_lineLayer": i % 3,
+                "_type": i % 2,
+                "_cutDirection": i % 9,
+            });
+            object(note, i)
+        })
+        .collect();
+    let events: Vec<_> = (0..notes.len() / 2)
+        .map(|i| {
+            let event = json!({ "_time": i as f64 / 2.0, "_type": i % 5, "_value": i % 8 });
+            object(event, i)
+        })
+        .collect();
+    let obstacles: Vec<_> = (0..notes.len() / 20)
+        .map(|i| {
+            let obstacle = json!({
+                "_time": i as f64 * 5.0,
+                "_lineIndex": i % 4,
+                "_type": i % 2,
+                "_duration": 1,
+                "_width": 1,
+            });
+            object(obstacle, i)
+        })
+        .collect();
+
+    serde_json::to_vec(&json!({
+        "_version": "2.0.0",
+        "_BPMChanges": [],
+        "_events": events,
+        "_notes": notes,
+        "_obstacles": obstacles,
+        "_bookmarks": [{ "_time": 0, "_name": "Start" }],
+    }))
+    .unwrap()
+}
+
+/// Builds a map around the test `info.dat`, with the given data as its Expert difficulty
+fn map_source(data: &[u8]) -> MemorySource {
+    let test_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test");
+    let mut source = MemorySource::new();
+    for name in ["info.dat", "song.egg"] {
+        source.insert(name, fs::read(test_dir.join(name)).unwrap());
+    }
+    source.insert("Expert.dat", data);
+    source
+}
+
+fn parse(c: &mut Criterion) {
+    let data = difficulty_file(10_000);
+    let source = map_source(&data);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+
+    // Goes through the strict parse with path tracking, like Beatmap does
+    group.bench_function("owned", |b| {
+        b.iter_batched(
+            || Beatmap::from_source_lazy(source.clone(), ParseMode::Strict).unwrap(),
+            |mut beatmap| {
+                beatmap
+                    .difficulty(BeatmapCharacteristic::Standard, DifficultyRank::Expert)
+                    .unwrap()
+                    .unwrap()
+                    .notes
+                    .len()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| BorrowedDifficulty::from_slice(black_box(&data)).unwrap())
+    });
+    group.bench_function("stream_notes", |b| {
+        b.iter(|| {
+            let mut count = 0;
+            borrowed::for_each_note(black_box(&data), |_| count += 1).unwrap();
+            count
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,581 @@
+use crate::info::info::difficulty_beatmap_set::{
+    difficulty_beatmap::DifficultyRank, BeatmapCharacteristic,
+};
+use crate::source::MapSource;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io;
+
+/// Size of the start of an audio file first read for its headers, in bytes
+pub(crate) const HEAD_SIZE: u64 = 64 * 1024;
+/// Size of the end of an audio file read for its last page, in bytes, larger than any OGG page
+pub(crate) const TAIL_SIZE: u64 = 128 * 1024;
+
+/// Represents an audio codec
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum Codec {
+    Vorbis,
+    Opus,
+}
+
+/// Represents the identification header of the first logical stream of an OGG file
+#[derive(Clone, Debug)]
+pub(crate) struct OggStream {
+    /// Codec of the stream
+    pub codec: Codec,
+    /// Serial number of the stream
+    pub serial: u32,
+    /// Number of channels
+    pub channels: u8,
+    /// Sample rate of the granule positions, always 48kHz for Opus
+    pub sample_rate: u32,
+    /// Nominal bitrate advertised by the encoder, only provided by Vorbis
+    pub nominal_bitrate: Option<u32>,
+    /// Number of samples to discard at the start of the stream, only used by Opus
+    pub pre_skip: u16,
+}
+
+/// Represents an OGG page header
+#[derive(Clone, Debug)]
+pub(crate) struct OggPage {
+    /// Granule position, `-1` when no packet ends on the page
+    pub granule_position: i64,
+    /// Serial number of the stream the page belongs to
+    pub serial: u32,
+    /// Segment table, giving the size of each packet segment
+    pub segments: Vec<u8>,
+    /// Offset of the page body in the file
+    pub body_offset: usize,
+}
+
+impl OggPage {
+    /// Size of a page header without its segment table
+    const HEADER_SIZE: usize = 27;
+
+    /// Parses the page starting at the given offset
+    pub fn parse(data: &[u8], offset: usize) -> Option<OggPage> {
+        let header = data.get(offset..offset + OggPage::HEADER_SIZE)?;
+        if &header[0..4] != b"OggS" || header[4] != 0 {
+            return None;
+        }
+        let segment_count = header[26] as usize;
+        let body_offset = offset + OggPage::HEADER_SIZE + segment_count;
+        let segments = data
+            .get(offset + OggPage::HEADER_SIZE..body_offset)?
+            .to_vec();
+        if data.len() < body_offset + segments.iter().map(|&s| s as usize).sum::<usize>() {
+            return None;
+        }
+
+        Some(OggPage {
+            granule_position: i64::from_le_bytes(read_array(header, 6)?),
+            serial: u32::from_le_bytes(read_array(header, 14)?),
+            segments,
+            body_offset,
+        })
+    }
+
+    /// Returns the size of the page body
+    pub fn body_size(&self) -> usize {
+        self.segments.iter().map(|&s| s as usize).sum()
+    }
+
+    /// Returns the offset of the next page in the file
+    pub fn next_offset(&self) -> usize {
+        self.body_offset + self.body_size()
+    }
+}
+
+/// Reads a fixed size array at the given offset
+pub(crate) fn read_array<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    let mut array = [0; N];
+    array.copy_from_slice(data.get(offset..offset + N)?);
+    Some(array)
+}
+
+/// Returns an error for unreadable audio data
+pub(crate) fn invalid_data(message: &str) -> Box<dyn Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Converts an audio error to an IO error, keeping the kind of IO errors
+#[cfg(feature = "async")]
+pub(crate) fn into_io_error(error: Box<dyn Error>) -> io::Error {
+    match error.downcast::<io::Error>() {
+        Ok(error) => *error,
+        Err(error) => io::Error::new(io::ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+/// Returns the length of the start of an OGG file to read next, when the given start of a
+/// file of the given size doesn't hold both its identification and comment headers
+pub(crate) fn next_head_length(head: &[u8], file_size: u64) -> Option<u64> {
+    if head.len() as u64 >= file_size {
+        return None;
+    }
+    // Invalid files are reported when parsing the metadata
+    let stream = OggStream::parse(head).ok()?;
+    if read_packets(head, stream.serial, 2).len() == 2 {
+        return None;
+    }
+    Some((head.len() as u64 * 4).max(HEAD_SIZE).min(file_size))
+}
+
+impl OggStream {
+    /// Parses the identification header of the first stream of an OGG file
+    pub fn parse(data: &[u8]) -> Result<OggStream, Box<dyn Error>> {
+        if !data.starts_with(b"OggS") {
+            return Err(invalid_data("Unsupported audio format"));
+        }
+        let page = OggPage::parse(data, 0).ok_or_else(|| invalid_data("Invalid OGG page"))?;
+        let packet = &data[page.body_offset..page.next_offset()];
+
+        if packet.starts_with(b"\x01vorbis") {
+            let header = packet
+                .get(7..28)
+                .ok_or_else(|| invalid_data("Truncated Vorbis header"))?;
+            let nominal_bitrate =
+                i32::from_le_bytes([header[13], header[14], header[15], header[16]]);
+            Ok(OggStream {
+                codec: Codec::Vorbis,
+                serial: page.serial,
+                channels: header[4],
+                sample_rate: u32::from_le_bytes([header[5], header[6], header[7], header[8]]),
+                nominal_bitrate: if nominal_bitrate > 0 {
+                    Some(nominal_bitrate as u32)
+                } else {
+                    None
+                },
+                pre_skip: 0,
+            })
+        } else if packet.starts_with(b"OpusHead") {
+            let header = packet
+                .get(8..12)
+                .ok_or_else(|| invalid_data("Truncated Opus header"))?;
+            Ok(OggStream {
+                codec: Codec::Opus,
+                serial: page.serial,
+                channels: header[1],
+                sample_rate: 48000,
+                nominal_bitrate: None,
+                pre_skip: u16::from_le_bytes([header[2], header[3]]),
+            })
+        } else {
+            Err(invalid_data("Unsupported OGG codec"))
+        }
+    }
+
+    /// Returns the comment tags of the stream, with uppercase keys
+    fn tags(&self, data: &[u8]) -> Vec<(String, String)> {
+        let magic: &[u8] = match self.codec {
+            Codec::Vorbis => b"\x03vorbis",
+            Codec::Opus => b"OpusTags",
+        };
+        read_packets(data, self.serial, 2)
+            .get(1)
+            .filter(|packet| packet.starts_with(magic))
+            .and_then(|packet| parse_comments(&packet[magic.len()..]))
+            .unwrap_or_default()
+    }
+
+    /// Returns the granule position of the last page of the stream
+    pub(crate) fn last_granule_position(&self, data: &[u8]) -> Option<i64> {
+        let mut end = data.len();
+        while end >= 4 {
+            let offset = data[..end]
+                .windows(4)
+                .rposition(|window| window == b"OggS")?;
+            if let Some(page) = OggPage::parse(data, offset) {
+                if page.serial == self.serial && page.granule_position >= 0 {
+                    return Some(page.granule_position);
+                }
+            }
+            end = offset + 3;
+        }
+        None
+    }
+
+    /// Returns the duration of the stream, in seconds
+    pub fn duration(&self, data: &[u8]) -> Result<f64, Box<dyn Error>> {
+        if self.sample_rate == 0 {
+            return Err(invalid_data("Invalid sample rate"));
+        }
+        let granule_position = self
+            .last_granule_position(data)
+            .ok_or_else(|| invalid_data("No granule position in OGG stream"))?;
+        let pre_skip = match self.codec {
+            Codec::Vorbis => 0,
+            Codec::Opus => i64::from(self.pre_skip),
+        };
+        let samples = (granule_position - pre_skip).max(0);
+
+        Ok(samples as f64 / f64::from(self.sample_rate))
+    }
+}
+
+/// Returns the first packets of the stream with the given serial number
+pub(crate) fn read_packets(data: &[u8], serial: u32, count: usize) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut offset = 0;
+    while packets.len() < count {
+        let page = match OggPage::parse(data, offset) {
+            Some(page) => page,
+            None => break,
+        };
+        if page.serial == serial {
+            let mut position = page.body_offset;
+            for &segment in &page.segments {
+                packet.extend_from_slice(&data[position..position + segment as usize]);
+                position += segment as usize;
+                if segment < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                    if packets.len() == count {
+                        break;
+                    }
+                }
+            }
+        }
+        offset = page.next_offset();
+    }
+    packets
+}
+
+/// Parses the body of a Vorbis comment header, following its magic
+fn parse_comments(body: &[u8]) -> Option<Vec<(String, String)>> {
+    let vendor_length = u32::from_le_bytes(read_array(body, 0)?) as usize;
+    let mut offset = 4 + vendor_length;
+    let count = u32::from_le_bytes(read_array(body, offset)?);
+    offset += 4;
+
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        let length = u32::from_le_bytes(read_array(body, offset)?) as usize;
+        offset += 4;
+        let comment = String::from_utf8_lossy(body.get(offset..offset + length)?);
+        offset += length;
+
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.push((key.to_uppercase(), value.to_string()));
+        }
+    }
+    Some(tags)
+}
+
+/// Represents the metadata of an audio file
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct AudioInfo {
+    /// Codec of the audio stream
+    pub codec: Codec,
+    /// Sample rate, in Hz, always 48kHz for Opus
+    pub sample_rate: u32,
+    /// Number of channels
+    pub channels: u8,
+    /// Nominal bitrate advertised by the encoder, in bits per second
+    pub nominal_bitrate: Option<u32>,
+    /// Average bitrate over the whole file, in bits per second
+    pub average_bitrate: f64,
+    /// Duration, in seconds
+    pub duration: f64,
+    /// Comment tags, with uppercase keys
+    pub tags: Vec<(String, String)>,
+    /// File size, in bytes
+    pub file_size: u64,
+}
+
+impl AudioInfo {
+    /// Returns the metadata of an OGG Vorbis or Opus file
+    ///
+    /// The format is detected from the contents rather than the extension, so `.egg` files are
+    /// handled like any other OGG file. Other formats return an error.
+    pub fn from_bytes(data: &[u8]) -> Result<AudioInfo, Box<dyn Error>> {
+        AudioInfo::from_parts(data, data, data.len() as u64)
+    }
+
+    /// Returns the metadata of an audio file of a map source, reading only its start and end
+    ///
+    /// See `AudioInfo::from_bytes` for the supported formats.
+    pub fn from_source(
+        source: &mut dyn MapSource,
+        name: &str,
+    ) -> Result<AudioInfo, Box<dyn Error>> {
+        let file_size = source.size(name)?;
+        let mut head = source.read_range(name, 0, HEAD_SIZE)?;
+        while let Some(length) = next_head_length(&head, file_size) {
+            head = source.read_range(name, 0, length)?;
+        }
+        let tail_offset = file_size.saturating_sub(TAIL_SIZE);
+        let tail = source.read_range(name, tail_offset, TAIL_SIZE)?;
+        AudioInfo::from_parts(&head, &tail, file_size)
+    }
+
+    /// Returns the metadata of an OGG file, given its start holding the headers and its end
+    /// holding the last page
+    pub(crate) fn from_parts(
+        head: &[u8],
+        tail: &[u8],
+        file_size: u64,
+    ) -> Result<AudioInfo, Box<dyn Error>> {
+        let stream = OggStream::parse(head)?;
+        let duration = stream.duration(tail)?;
+
+        Ok(AudioInfo {
+            codec: stream.codec,
+            sample_rate: stream.sample_rate,
+            channels: stream.channels,
+            nominal_bitrate: stream.nominal_bitrate,
+            average_bitrate: if duration > 0.0 {
+                file_size as f64 * 8.0 / duration
+            } else {
+                0.0
+            },
+            duration,
+            tags: stream.tags(head),
+            file_size,
+        })
+    }
+
+    /// Returns the first value of the given tag, ignoring case
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        let key = key.to_uppercase();
+        self.tags
+            .iter()
+            .find(|(tag, _)| *tag == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the title tag
+    pub fn title(&self) -> Option<&str> {
+        self.tag("TITLE")
+    }
+
+    /// Returns the artist tag
+    pub fn artist(&self) -> Option<&str> {
+        self.tag("ARTIST")
+    }
+}
+
+/// Represents an inconsistency between a beatmap and its audio file
+#[derive(PartialEq, Clone, Debug)]
+pub enum AudioIssue {
+    /// The preview ends after the audio, in seconds
+    PreviewOutOfBounds { preview_end: f64, duration: f64 },
+    /// The last object of a difficulty ends after the audio, in seconds
+    ObjectAfterEnd {
+        characteristic: BeatmapCharacteristic,
+        rank: DifficultyRank,
+        time: f64,
+        duration: f64,
+    },
+}
+
+/// Returns the duration of an OGG Vorbis or Opus file, in seconds
+///
+/// The format is detected from the contents rather than the extension, so `.egg` files are
+/// handled like any other OGG file. Other formats return an error.
+pub fn duration(data: &[u8]) -> Result<f64, Box<dyn Error>> {
+    OggStream::parse(data)?.duration(data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{duration, next_head_length, AudioInfo, Codec, HEAD_SIZE, TAIL_SIZE};
+    use crate::source::{MapSource, MemorySource};
+    use std::io;
+
+    /// Computes the CRC used by OGG pages
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for &byte in data {
+            crc ^= u32::from(byte) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    /// Builds an OGG page containing a single packet
+    pub fn ogg_page(
+        header_type: u8,
+        granule_position: i64,
+        sequence: u32,
+        packet: &[u8],
+    ) -> Vec<u8> {
+        let mut segments = vec![255; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+
+        let mut page = b"OggS\x00".to_vec();
+        page.push(header_type);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(packet);
+
+        let checksum = crc(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    /// Builds an OGG Vorbis file with the given comments, containing no actual audio
+    pub fn vorbis_file(channels: u8, sample_rate: u32, samples: i64, comments: &[&str]) -> Vec<u8> {
+        let mut identification = b"\x01vorbis".to_vec();
+        identification.extend_from_slice(&0u32.to_le_bytes());
+        identification.push(channels);
+        identification.extend_from_slice(&sample_rate.to_le_bytes());
+        identification.extend_from_slice(&0i32.to_le_bytes());
+        identification.extend_from_slice(&160_000i32.to_le_bytes());
+        identification.extend_from_slice(&0i32.to_le_bytes());
+        identification.extend_from_slice(&[0xb8, 0x01]);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        let vendor = b"beatmap-parser";
+        comment.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        comment.extend_from_slice(vendor);
+        comment.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for tag in comments {
+            comment.extend_from_slice(&(tag.len() as u32).to_le_bytes());
+            comment.extend_from_slice(tag.as_bytes());
+        }
+        comment.push(1);
+
+        let mut file = ogg_page(2, 0, 0, &identification);
+        file.extend(ogg_page(0, 0, 1, &comment));
+        file.extend(ogg_page(0, samples / 2, 2, &[0; 64]));
+        file.extend(ogg_page(4, samples, 3, &[0; 64]));
+        file
+    }
+
+    /// Builds the identification and comment header pages of an OGG Opus file
+    pub fn opus_headers(channels: u8, pre_skip: u16) -> Vec<u8> {
+        let mut identification = b"OpusHead\x01".to_vec();
+        identification.push(channels);
+        identification.extend_from_slice(&pre_skip.to_le_bytes());
+        identification.extend_from_slice(&44100u32.to_le_bytes());
+        identification.extend_from_slice(&[0, 0, 0]);
+
+        let mut file = ogg_page(2, 0, 0, &identification);
+        file.extend(ogg_page(
+            0,
+            0,
+            1,
+            b"OpusTags\x00\x00\x00\x00\x00\x00\x00\x00",
+        ));
+        file
+    }
+
+    /// Builds an OGG Opus file containing no actual audio
+    pub fn opus_file(channels: u8, pre_skip: u16, samples: i64) -> Vec<u8> {
+        let mut file = opus_headers(channels, pre_skip);
+        file.extend(ogg_page(4, samples + i64::from(pre_skip), 2, &[0; 64]));
+        file
+    }
+
+    #[test]
+    fn vorbis_mono() {
+        let file = vorbis_file(1, 44100, 44100 * 3, &[]);
+        assert_eq!(duration(&file).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn vorbis_stereo() {
+        let file = vorbis_file(2, 22050, 22050 * 5, &[]);
+        assert_eq!(duration(&file).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn opus() {
+        let file = opus_file(2, 312, 48000 * 2);
+        assert_eq!(duration(&file).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn audio_info() {
+        let file = vorbis_file(2, 44100, 44100 * 4, &["title=Song", "ARTIST=Someone"]);
+        let info = AudioInfo::from_bytes(&file).unwrap();
+
+        assert_eq!(info.codec, Codec::Vorbis);
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.nominal_bitrate, Some(160_000));
+        assert_eq!(info.file_size, file.len() as u64);
+        assert_eq!(info.average_bitrate, file.len() as f64 * 2.0);
+        assert_eq!(info.title(), Some("Song"));
+        assert_eq!(info.artist(), Some("Someone"));
+
+        let info = AudioInfo::from_bytes(&opus_file(1, 0, 48000)).unwrap();
+        assert_eq!(info.codec, Codec::Opus);
+        assert_eq!(info.channels, 1);
+        assert_eq!(info.nominal_bitrate, None);
+    }
+
+    #[test]
+    fn from_source() {
+        /// Counts the bytes read from a map held in memory, without allowing whole reads
+        struct RangeSource {
+            inner: MemorySource,
+            read: u64,
+        }
+
+        impl MapSource for RangeSource {
+            fn read(&mut self, _: &str) -> io::Result<Vec<u8>> {
+                panic!("The whole audio file was read");
+            }
+
+            fn exists(&mut self, name: &str) -> bool {
+                self.inner.exists(name)
+            }
+
+            fn size(&mut self, name: &str) -> io::Result<u64> {
+                self.inner.size(name)
+            }
+
+            fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+                let contents = self.inner.read_range(name, offset, length)?;
+                self.read += contents.len() as u64;
+                Ok(contents)
+            }
+        }
+
+        // About 4 MiB of audio pages between the headers and the last page
+        let header = vorbis_file(2, 44100, 0, &["TITLE=Long"]);
+        let mut file = header[..header.len() - 2 * ogg_page(0, 0, 0, &[0; 64]).len()].to_vec();
+        for i in 0..1024 {
+            file.extend(ogg_page(0, i * 1024, 2 + i as u32, &[0; 4096]));
+        }
+        file.extend(ogg_page(4, 44100 * 60, 1026, &[0; 64]));
+
+        let mut source = RangeSource {
+            inner: MemorySource::new(),
+            read: 0,
+        };
+        source.inner.insert("song.egg", file.clone());
+        let info = AudioInfo::from_source(&mut source, "song.egg").unwrap();
+
+        assert_eq!(info, AudioInfo::from_bytes(&file).unwrap());
+        assert_eq!(info.duration, 60.0);
+        assert_eq!(info.title(), Some("Long"));
+        assert!(source.read <= HEAD_SIZE + TAIL_SIZE);
+
+        // The start is read further while it doesn't hold the comment header
+        assert_eq!(next_head_length(&file[..100], 100), None);
+        assert_eq!(
+            next_head_length(&file[..100], file.len() as u64),
+            Some(HEAD_SIZE)
+        );
+        assert_eq!(next_head_length(&header, file.len() as u64), None);
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(duration(b"RIFF\x00\x00\x00\x00WAVE").is_err());
+        assert!(duration(&ogg_page(2, 0, 0, b"\x7fFLAC")).is_err());
+    }
+}
@@ -0,0 +1,1373 @@
+use crate::downloads::{CacheEntry, DownloadCache};
+use crate::info::info::difficulty_beatmap_set::difficulty_beatmap::Difficulty;
+use crate::source::{ZipError, ZipLimits, ZipSource};
+use crate::Beatmap;
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// Represents how failed requests are retried
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after each one
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Represents the options of a BeatSaver client
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ClientOptions {
+    /// Base URL of the API, without a trailing slash
+    pub base_url: String,
+    /// Base URL of the CDN the zips are downloaded from, without a trailing slash
+    pub cdn_url: String,
+    /// User agent sent with every request
+    pub user_agent: String,
+    /// Timeout of each request
+    pub timeout: Duration,
+    /// Retry policy of the requests failing with a connection error or a server error
+    pub retry: RetryPolicy,
+    /// Limits the downloaded zips must fit in to be parsed
+    pub zip_limits: ZipLimits,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            base_url: String::from("https://api.beatsaver.com"),
+            cdn_url: String::from("https://r2cdn.beatsaver.com"),
+            user_agent: format!("beatmap-parser/{}", env!("CARGO_PKG_VERSION")),
+            timeout: Duration::from_secs(120),
+            retry: RetryPolicy::default(),
+            zip_limits: ZipLimits::default(),
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Returns the API URL of the map with the given key
+    pub(crate) fn map_url(&self, id: &str) -> String {
+        format!("{}/maps/id/{}", self.base_url, id)
+    }
+
+    /// Returns the API URL of the map with a version of the given level hash
+    pub(crate) fn map_hash_url(&self, hash: &str) -> String {
+        format!("{}/maps/hash/{}", self.base_url, hash)
+    }
+
+    /// Returns the CDN download URL of the version with the given level hash
+    pub(crate) fn download_url(&self, hash: &str) -> String {
+        format!("{}/{}.zip", self.cdn_url, hash.to_lowercase())
+    }
+}
+
+/// Represents a BeatSaver user, like the uploader of a map
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    /// Identifier of the user
+    pub id: u64,
+    /// Name of the user
+    pub name: String,
+    /// URL of the avatar of the user
+    #[serde(default)]
+    pub avatar: String,
+    /// Whether the user is a verified mapper
+    #[serde(default)]
+    pub verified_mapper: bool,
+    /// Whether the user is a curator
+    #[serde(default)]
+    pub curator: bool,
+}
+
+/// Represents the song metadata of a map, as computed by BeatSaver
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MapMetadata {
+    /// Beats per minute of the song
+    pub bpm: f64,
+    /// Duration of the song, in seconds
+    pub duration: u32,
+    /// Name of the song
+    pub song_name: String,
+    /// Sub name of the song
+    pub song_sub_name: String,
+    /// Author of the song
+    pub song_author_name: String,
+    /// Author of the map
+    pub level_author_name: String,
+}
+
+/// Represents the stats of a map
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MapStats {
+    /// Number of plays
+    pub plays: u64,
+    /// Number of downloads
+    pub downloads: u64,
+    /// Number of upvotes
+    pub upvotes: u64,
+    /// Number of downvotes
+    pub downvotes: u64,
+    /// Rating, between 0 and 1
+    pub score: f64,
+    /// Number of reviews
+    pub reviews: u64,
+}
+
+/// Represents the parity errors found by BeatSaver in a difficulty
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct ParitySummary {
+    /// Number of parity errors
+    pub errors: u32,
+    /// Number of parity warnings
+    pub warns: u32,
+    /// Number of resets
+    pub resets: u32,
+}
+
+/// Represents the summary of a difficulty of a version, as computed by BeatSaver
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DifficultySummary {
+    /// Characteristic name, like `Standard` or `360Degree`
+    pub characteristic: String,
+    /// Difficulty name
+    pub difficulty: Difficulty,
+    /// Note jump speed
+    #[serde(default)]
+    pub njs: f64,
+    /// Note jump start beat offset
+    #[serde(default)]
+    pub offset: f64,
+    /// Number of notes
+    #[serde(default)]
+    pub notes: u32,
+    /// Number of bombs
+    #[serde(default)]
+    pub bombs: u32,
+    /// Number of obstacles
+    #[serde(default)]
+    pub obstacles: u32,
+    /// Number of events
+    #[serde(default)]
+    pub events: u32,
+    /// Notes per second
+    #[serde(default)]
+    pub nps: f64,
+    /// Length, in beats
+    #[serde(default)]
+    pub length: f64,
+    /// Length, in seconds
+    #[serde(default)]
+    pub seconds: f64,
+    /// Parity errors of the difficulty
+    #[serde(default)]
+    pub parity_summary: ParitySummary,
+    /// ScoreSaber star rating, for ranked maps
+    #[serde(default)]
+    pub stars: Option<f64>,
+    /// Custom difficulty label
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Whether the difficulty requires Chroma
+    #[serde(default)]
+    pub chroma: bool,
+    /// Whether the difficulty requires Mapping Extensions
+    #[serde(default)]
+    pub me: bool,
+    /// Whether the difficulty requires Noodle Extensions
+    #[serde(default)]
+    pub ne: bool,
+    /// Whether the difficulty requires Cinema
+    #[serde(default)]
+    pub cinema: bool,
+}
+
+/// Represents a map as returned by the BeatSaver API
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MapDetail {
+    /// Key of the map, like `3cf5`
+    pub id: String,
+    /// Name of the map
+    pub name: String,
+    /// Description of the map
+    #[serde(default)]
+    pub description: String,
+    /// Uploader of the map
+    #[serde(default)]
+    pub uploader: User,
+    /// Song metadata of the map
+    #[serde(default)]
+    pub metadata: MapMetadata,
+    /// Stats of the map
+    #[serde(default)]
+    pub stats: MapStats,
+    /// Upload date, as an ISO 8601 timestamp
+    #[serde(default)]
+    pub uploaded: String,
+    /// Whether the map was generated by an automapper
+    #[serde(default)]
+    pub automapper: bool,
+    /// Whether the map is ranked on ScoreSaber
+    #[serde(default)]
+    pub ranked: bool,
+    /// Whether the map is qualified on ScoreSaber
+    #[serde(default)]
+    pub qualified: bool,
+    /// Whether the map is ranked on BeatLeader
+    #[serde(default)]
+    pub bl_ranked: bool,
+    /// Whether the map is qualified on BeatLeader
+    #[serde(default)]
+    pub bl_qualified: bool,
+    /// Curator of the map, for curated maps
+    #[serde(default)]
+    pub curator: Option<User>,
+    /// Curation date, as an ISO 8601 timestamp
+    #[serde(default)]
+    pub curated_at: Option<String>,
+    /// Tags of the map, like `dance` or `pop`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Uploaded versions of the map, the latest one last
+    #[serde(default)]
+    pub versions: Vec<MapVersion>,
+}
+
+/// Represents an uploaded version of a map
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MapVersion {
+    /// Level hash of the version, as lowercase hex
+    pub hash: String,
+    /// Publication state, like `Published`
+    #[serde(default)]
+    pub state: String,
+    /// Creation date, as an ISO 8601 timestamp
+    #[serde(default)]
+    pub created_at: String,
+    /// Score given by BeatSaver's automapper detection
+    #[serde(default)]
+    pub sage_score: Option<i32>,
+    /// Summaries of the difficulties of the version
+    #[serde(default)]
+    pub diffs: Vec<DifficultySummary>,
+    /// URL the zip of the version is downloaded from
+    #[serde(rename = "downloadURL")]
+    pub download_url: String,
+    /// URL of the cover image of the version
+    #[serde(rename = "coverURL", default)]
+    pub cover_url: String,
+    /// URL of the preview audio of the version
+    #[serde(rename = "previewURL", default)]
+    pub preview_url: String,
+}
+
+impl MapDetail {
+    /// Returns the latest version of the map
+    pub fn latest_version(&self) -> Option<&MapVersion> {
+        self.versions.last()
+    }
+
+    /// Returns the version with the given level hash, ignoring case
+    pub fn version(&self, hash: &str) -> Option<&MapVersion> {
+        self.versions
+            .iter()
+            .find(|version| version.hash.eq_ignore_ascii_case(hash))
+    }
+
+    /// Returns whether the map was curated
+    pub fn is_curated(&self) -> bool {
+        self.curated_at.is_some()
+    }
+}
+
+/// Represents the BeatSaver metadata of a beatmap fetched through a client
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct BeatSaverInfo {
+    /// Key of the map, like `3cf5`
+    pub key: String,
+    /// Level hash of the downloaded version
+    pub hash: String,
+    /// Map as returned by the API
+    pub map: MapDetail,
+}
+
+impl BeatSaverInfo {
+    /// Returns the downloaded version of the map
+    pub fn version(&self) -> Option<&MapVersion> {
+        self.map.version(&self.hash)
+    }
+}
+
+/// Represents a reference to a BeatSaver map, parsed from a key, a hash or a URL
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+pub enum BeatSaverRef {
+    /// Key of the map, as lowercase hex, like `3cf5`
+    Key(String),
+    /// Level hash of a version of the map, as lowercase hex
+    Hash(String),
+}
+
+impl BeatSaverRef {
+    /// Parses a key, a level hash, or a BeatSaver, BeastSaber or CDN URL
+    ///
+    /// Accepted URLs include `beatsaver://{key}`, `https://beatsaver.com/maps/{key}`,
+    /// `https://beatsaver.com/beatmap/{key}`, `https://beatsaver.com/api/download/key/{key}`,
+    /// `https://bsaber.com/songs/{key}` and `https://r2cdn.beatsaver.com/{hash}.zip`. The scheme,
+    /// `www.`, trailing slashes, query strings and fragments are optional.
+    pub fn parse(input: &str) -> Result<BeatSaverRef, io::Error> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Not a BeatSaver key, hash or url: {}", input),
+            )
+        };
+        let input = input.trim();
+
+        // Strip the scheme, query string and fragment
+        let lowercase = input.to_ascii_lowercase();
+        let (custom_scheme, rest) = if lowercase.starts_with("beatsaver://") {
+            (true, &input["beatsaver://".len()..])
+        } else if lowercase.starts_with("https://") {
+            (false, &input["https://".len()..])
+        } else if lowercase.starts_with("http://") {
+            (false, &input["http://".len()..])
+        } else {
+            (false, input)
+        };
+        let rest = rest.split(['?', '#']).next().unwrap_or("");
+        let mut segments = rest.split('/').filter(|segment| !segment.is_empty());
+
+        if custom_scheme {
+            return match (segments.next(), segments.next()) {
+                (Some(key), None) => BeatSaverRef::key(key).ok_or_else(invalid),
+                _ => Err(invalid()),
+            };
+        }
+        let host = segments.next().ok_or_else(invalid)?.to_ascii_lowercase();
+        let path: Vec<_> = segments.collect();
+
+        // A bare key or hash
+        if path.is_empty() && !rest.contains('/') {
+            return BeatSaverRef::key(&host)
+                .or_else(|| BeatSaverRef::hash(&host))
+                .ok_or_else(invalid);
+        }
+
+        let host = host.strip_prefix("www.").unwrap_or(&host);
+        let path: Vec<_> = path
+            .iter()
+            .map(|segment| segment.to_ascii_lowercase())
+            .collect();
+        let path: Vec<_> = path.iter().map(String::as_str).collect();
+        let reference = if host == "bsaber.com" {
+            match path.as_slice() {
+                ["songs", key] => BeatSaverRef::key(key),
+                _ => None,
+            }
+        } else if host == "beatsaver.com" || host.ends_with(".beatsaver.com") {
+            match path.as_slice() {
+                ["maps", key]
+                | ["beatmap", key]
+                | ["maps", "id", key]
+                | ["api", "maps", "id", key]
+                | ["api", "download", "key", key]
+                | ["download", "key", key] => BeatSaverRef::key(key),
+                ["maps", "hash", hash] | ["api", "maps", "hash", hash] => BeatSaverRef::hash(hash),
+                [.., file] => file.strip_suffix(".zip").and_then(BeatSaverRef::hash),
+                [] => None,
+            }
+        } else {
+            None
+        };
+        reference.ok_or_else(invalid)
+    }
+
+    /// Returns a key reference if the given string is a valid key
+    fn key(key: &str) -> Option<BeatSaverRef> {
+        let valid = (1..=8).contains(&key.len()) && key.chars().all(|c| c.is_ascii_hexdigit());
+        Some(BeatSaverRef::Key(key.to_ascii_lowercase())).filter(|_| valid)
+    }
+
+    /// Returns a hash reference if the given string is a valid level hash
+    fn hash(hash: &str) -> Option<BeatSaverRef> {
+        let valid = hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit());
+        Some(BeatSaverRef::Hash(hash.to_ascii_lowercase())).filter(|_| valid)
+    }
+}
+
+impl std::str::FromStr for BeatSaverRef {
+    type Err = io::Error;
+
+    fn from_str(input: &str) -> Result<BeatSaverRef, io::Error> {
+        BeatSaverRef::parse(input)
+    }
+}
+
+/// Represents the sort order of search results
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub enum SortOrder {
+    /// Most recently uploaded first
+    Latest,
+    /// Best match of the search terms first
+    #[default]
+    Relevance,
+    /// Highest rated first
+    Rating,
+    /// Most recently curated first
+    Curated,
+    /// Random order
+    Random,
+    /// Sorted by song duration
+    Duration,
+}
+
+/// Represents the filters of a map search
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct SearchQuery {
+    /// Text searched in the name, description and metadata of the maps
+    pub query: String,
+    /// Sort order of the results
+    pub order: SortOrder,
+    /// Only keeps ranked maps, or unranked maps when false
+    pub ranked: Option<bool>,
+    /// Only keeps curated maps, or uncurated maps when false
+    pub curated: Option<bool>,
+    /// Smallest notes per second of any difficulty
+    pub min_nps: Option<f64>,
+    /// Largest notes per second of any difficulty
+    pub max_nps: Option<f64>,
+    /// Shortest song duration, in seconds
+    pub min_duration: Option<u32>,
+    /// Longest song duration, in seconds
+    pub max_duration: Option<u32>,
+    /// Tags every map must have, like `dance` or `pop`
+    pub tags: Vec<String>,
+}
+
+impl SearchQuery {
+    /// Returns the query string parameters of the search
+    fn parameters(&self) -> Vec<(&'static str, String)> {
+        let mut parameters = vec![
+            ("q", self.query.clone()),
+            ("order", format!("{:?}", self.order)),
+        ];
+        let mut push = |name, value: Option<String>| {
+            if let Some(value) = value {
+                parameters.push((name, value));
+            }
+        };
+        push("ranked", self.ranked.map(|ranked| ranked.to_string()));
+        push("curated", self.curated.map(|curated| curated.to_string()));
+        push("minNps", self.min_nps.map(|nps| nps.to_string()));
+        push("maxNps", self.max_nps.map(|nps| nps.to_string()));
+        push("minDuration", self.min_duration.map(|d| d.to_string()));
+        push("maxDuration", self.max_duration.map(|d| d.to_string()));
+        if !self.tags.is_empty() {
+            parameters.push(("tags", self.tags.join(",")));
+        }
+        parameters
+    }
+}
+
+/// Represents the totals of a paged listing
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct PageInfo {
+    /// Number of pages
+    pub pages: u32,
+    /// Number of maps
+    pub total: u32,
+}
+
+/// Represents a page of maps
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Page {
+    /// Maps of the page
+    pub docs: Vec<MapDetail>,
+    /// Totals of the listing, only returned by searches
+    pub info: Option<PageInfo>,
+}
+
+/// Represents the position of a page in a listing
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Cursor {
+    /// Page number, starting at 0
+    Page(u32),
+    /// Upload date the maps of the page precede, none for the first page
+    Before(Option<String>),
+}
+
+/// Represents a paged listing of maps
+#[derive(PartialEq, Clone, Debug)]
+pub(crate) enum Listing {
+    Search(SearchQuery),
+    Latest,
+    Uploader(u64),
+    Collaborations(u64),
+}
+
+impl Listing {
+    /// Returns the URL and query string parameters of the page at the given cursor
+    pub(crate) fn request(
+        &self,
+        base_url: &str,
+        cursor: &Cursor,
+    ) -> (String, Vec<(&'static str, String)>) {
+        let (url, mut parameters) = match self {
+            Listing::Search(query) => (format!("{}/search/text", base_url), query.parameters()),
+            Listing::Latest => (format!("{}/maps/latest", base_url), Vec::new()),
+            Listing::Uploader(id) => (format!("{}/maps/uploader/{}", base_url, id), Vec::new()),
+            Listing::Collaborations(id) => (
+                format!("{}/maps/collaborations/{}", base_url, id),
+                Vec::new(),
+            ),
+        };
+        let url = match cursor {
+            Cursor::Page(number) => format!("{}/{}", url, number),
+            Cursor::Before(before) => {
+                if let Some(before) = before {
+                    parameters.push(("before", before.clone()));
+                }
+                url
+            }
+        };
+        (url, parameters)
+    }
+}
+
+impl Page {
+    /// Returns the cursor of the page following this one, requested at the given cursor
+    pub fn next_cursor(&self, cursor: &Cursor) -> Option<Cursor> {
+        match cursor {
+            _ if self.docs.is_empty() => None,
+            Cursor::Page(number) => match self.info {
+                Some(info) if number + 1 >= info.pages => None,
+                _ => Some(Cursor::Page(number + 1)),
+            },
+            // Maps uploaded at the same time as the cursor would be requested again forever
+            Cursor::Before(_) => self
+                .docs
+                .last()
+                .map(|map| Cursor::Before(Some(map.uploaded.clone())))
+                .filter(|next| next != cursor),
+        }
+    }
+}
+
+/// Represents an iterator over the pages of a listing
+///
+/// Stops after the last page, or after the first request failing.
+pub struct Pages<'a> {
+    client: &'a BeatSaverClient,
+    listing: Listing,
+    cursor: Option<Cursor>,
+}
+
+impl<'a> Pages<'a> {
+    /// Returns the cursor of the next page, if any
+    pub fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+}
+
+impl<'a> Iterator for Pages<'a> {
+    type Item = Result<Page, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Result<Page, Box<dyn Error>>> {
+        let cursor = self.cursor.take()?;
+        let page = match self.client.page(&self.listing, &cursor) {
+            Ok(page) => page,
+            Err(error) => return Some(Err(error)),
+        };
+
+        self.cursor = page.next_cursor(&cursor);
+        Some(Ok(page))
+    }
+}
+
+/// Represents a client of the BeatSaver API
+#[derive(Clone, Debug)]
+pub struct BeatSaverClient {
+    options: ClientOptions,
+    client: Client,
+    cache: Option<Arc<Mutex<DownloadCache>>>,
+}
+
+impl BeatSaverClient {
+    /// Returns a new BeatSaverClient with the given options
+    pub fn new(options: ClientOptions) -> Result<BeatSaverClient, Box<dyn Error>> {
+        let client = Client::builder()
+            .user_agent(options.user_agent.as_str())
+            .timeout(options.timeout)
+            .build()?;
+
+        Ok(BeatSaverClient {
+            options,
+            client,
+            cache: None,
+        })
+    }
+
+    /// Returns the client with the default options, built on first use and shared afterwards
+    pub(crate) fn shared() -> Result<&'static BeatSaverClient, Box<dyn Error>> {
+        static CLIENT: OnceLock<BeatSaverClient> = OnceLock::new();
+        if let Some(client) = CLIENT.get() {
+            return Ok(client);
+        }
+        let client = BeatSaverClient::new(ClientOptions::default())?;
+        Ok(CLIENT.get_or_init(|| client))
+    }
+
+    /// Returns the client, downloading maps through the given cache
+    ///
+    /// When the cache is offline, only cached maps are served and no request is sent.
+    pub fn with_cache(self, cache: DownloadCache) -> BeatSaverClient {
+        BeatSaverClient {
+            cache: Some(Arc::new(Mutex::new(cache))),
+            ..self
+        }
+    }
+
+    /// Returns the download cache of the client, locked
+    pub fn cache(&self) -> Option<MutexGuard<'_, DownloadCache>> {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.lock().unwrap_or_else(|error| error.into_inner()))
+    }
+
+    /// Returns whether the client only serves cached maps
+    pub fn is_offline(&self) -> bool {
+        self.cache().is_some_and(|cache| cache.is_offline())
+    }
+
+    /// Returns the options of the client
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// Returns the map with the given key
+    pub fn map_by_id(&self, id: &str) -> Result<MapDetail, Box<dyn Error>> {
+        Ok(self.get(&self.options.map_url(id))?.json()?)
+    }
+
+    /// Returns the map with a version of the given level hash
+    pub fn map_by_hash(&self, hash: &str) -> Result<MapDetail, Box<dyn Error>> {
+        Ok(self.get(&self.options.map_hash_url(hash))?.json()?)
+    }
+
+    /// Returns the CDN download URL of the version with the given level hash
+    pub fn download_url(&self, hash: &str) -> String {
+        self.options.download_url(hash)
+    }
+
+    /// Downloads a zip from the given URL to a temporary file
+    ///
+    /// The download stops once it's larger than the archive size limit of the client.
+    pub fn download(&self, url: &str) -> Result<std::fs::File, Box<dyn Error>> {
+        let mut temp_file = tempfile::tempfile()?;
+        self.download_to(url, &mut temp_file)?;
+        Ok(temp_file)
+    }
+
+    /// Downloads a zip from the given URL, rejecting it once it's larger than the archive size
+    /// limit
+    fn download_to<W: io::Write>(&self, url: &str, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        let max = self.options.zip_limits.max_archive_size;
+        let response = self.get(url)?;
+        if response.content_length().is_some_and(|length| length > max) {
+            return Err(Box::new(ZipError::ArchiveTooLarge { max }));
+        }
+        if io::copy(&mut io::Read::take(response, max + 1), writer)? > max {
+            return Err(Box::new(ZipError::ArchiveTooLarge { max }));
+        }
+        Ok(())
+    }
+
+    /// Downloads and parses the zip at the given URL
+    pub fn beatmap_from_url(&self, url: &str) -> Result<Beatmap, Box<dyn Error>> {
+        Beatmap::from_source(ZipSource::with_limits(
+            self.download(url)?,
+            self.options.zip_limits,
+        )?)
+    }
+
+    /// Downloads and parses the latest version of a map
+    ///
+    /// The zip is read from the download cache of the client when it's cached there.
+    pub fn beatmap(&self, map: &MapDetail) -> Result<Beatmap, Box<dyn Error>> {
+        let version = map.latest_version().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Map {} has no version", map.id),
+            )
+        })?;
+
+        self.beatmap_version(map, &version.hash, &version.download_url)
+    }
+
+    /// Downloads and parses the latest version of the map with the given key
+    ///
+    /// When the client is offline, the most recently used cached version of the map is parsed.
+    pub fn beatmap_by_id(&self, id: &str) -> Result<Beatmap, Box<dyn Error>> {
+        if self.is_offline() {
+            let entry = match self.cache() {
+                Some(mut cache) => cache.get_by_key(id).cloned(),
+                None => None,
+            };
+            let entry = entry.ok_or_else(|| not_cached(id))?;
+            return self.cached_beatmap(&entry);
+        }
+        self.beatmap(&self.map_by_id(id)?)
+    }
+
+    /// Downloads and parses the version of the map with the given level hash
+    pub fn beatmap_by_hash(&self, hash: &str) -> Result<Beatmap, Box<dyn Error>> {
+        let entry = match self.cache() {
+            Some(mut cache) => cache.get(hash).cloned(),
+            None => None,
+        };
+        match entry {
+            Some(entry) => self.cached_beatmap(&entry),
+            None if self.is_offline() => Err(not_cached(hash)),
+            None => {
+                let map = self.map_by_hash(hash)?;
+                self.beatmap_version(&map, hash, &self.download_url(hash))
+            }
+        }
+    }
+
+    /// Downloads and parses the map referenced by a key, a level hash or a URL
+    pub fn beatmap_by_ref(&self, reference: &BeatSaverRef) -> Result<Beatmap, Box<dyn Error>> {
+        match reference {
+            BeatSaverRef::Key(key) => self.beatmap_by_id(key),
+            BeatSaverRef::Hash(hash) => self.beatmap_by_hash(hash),
+        }
+    }
+
+    /// Downloads and parses a version of a map, through the download cache if any
+    fn beatmap_version(
+        &self,
+        map: &MapDetail,
+        hash: &str,
+        url: &str,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        let hash = hash.to_lowercase();
+        let mut beatmap = match self.cache() {
+            None => {
+                let beatmap = self.beatmap_from_url(url)?;
+                check_level_hash(&beatmap, &hash)?;
+                beatmap
+            }
+            Some(mut cache) => {
+                if let Some(entry) = cache.get(&hash).cloned() {
+                    drop(cache);
+                    return self.cached_beatmap(&entry);
+                }
+                if cache.is_offline() {
+                    return Err(not_cached(&hash));
+                }
+                drop(cache);
+
+                // The cache isn't locked during the download
+                let mut data = Vec::new();
+                self.download_to(url, &mut data)?;
+                let source =
+                    ZipSource::with_limits(io::Cursor::new(&data), self.options.zip_limits)?;
+                if let Some(mut cache) = self.cache() {
+                    cache.insert(&map.id, &hash, Some(map.clone()), &data)?;
+                }
+                Beatmap::from_source(source)?
+            }
+        };
+
+        beatmap.beatsaver = Some(BeatSaverInfo {
+            key: map.id.clone(),
+            hash,
+            map: map.clone(),
+        });
+        Ok(beatmap)
+    }
+
+    /// Parses a cached zip, removing it from the cache if its level hash changed
+    fn cached_beatmap(&self, entry: &CacheEntry) -> Result<Beatmap, Box<dyn Error>> {
+        let path = match self.cache() {
+            Some(cache) => cache.path(&entry.hash),
+            None => return Err(not_cached(&entry.hash)),
+        };
+        let mut beatmap = Beatmap::from_source(ZipSource::with_limits(
+            std::fs::File::open(path)?,
+            self.options.zip_limits,
+        )?)?;
+
+        if beatmap.hash.as_deref() != Some(entry.hash.as_str()) {
+            if let Some(mut cache) = self.cache() {
+                cache.remove(&entry.hash)?;
+            }
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cached zip of {} doesn't match its level hash", entry.key),
+            )));
+        }
+        beatmap.beatsaver = entry.map.clone().map(|map| BeatSaverInfo {
+            key: entry.key.clone(),
+            hash: entry.hash.clone(),
+            map,
+        });
+        Ok(beatmap)
+    }
+
+    /// Returns a page of the maps matching a search
+    pub fn search(&self, query: &SearchQuery, page: u32) -> Result<Page, Box<dyn Error>> {
+        self.page(&Listing::Search(query.clone()), &Cursor::Page(page))
+    }
+
+    /// Returns an iterator over the pages of the maps matching a search
+    pub fn search_pages(&self, query: &SearchQuery) -> Pages<'_> {
+        self.pages(Listing::Search(query.clone()), Cursor::Page(0))
+    }
+
+    /// Returns a page of the latest maps, uploaded before the given date when provided
+    pub fn latest(&self, before: Option<&str>) -> Result<Page, Box<dyn Error>> {
+        let cursor = Cursor::Before(before.map(String::from));
+        self.page(&Listing::Latest, &cursor)
+    }
+
+    /// Returns an iterator over the pages of the latest maps
+    pub fn latest_pages(&self) -> Pages<'_> {
+        self.pages(Listing::Latest, Cursor::Before(None))
+    }
+
+    /// Returns a page of the maps uploaded by the given user
+    pub fn maps_by_uploader(&self, user_id: u64, page: u32) -> Result<Page, Box<dyn Error>> {
+        self.page(&Listing::Uploader(user_id), &Cursor::Page(page))
+    }
+
+    /// Returns an iterator over the pages of the maps uploaded by the given user
+    pub fn uploader_pages(&self, user_id: u64) -> Pages<'_> {
+        self.pages(Listing::Uploader(user_id), Cursor::Page(0))
+    }
+
+    /// Returns a page of the maps the given user collaborated on, uploaded before the given
+    /// date when provided
+    pub fn collaborations(
+        &self,
+        user_id: u64,
+        before: Option<&str>,
+    ) -> Result<Page, Box<dyn Error>> {
+        let cursor = Cursor::Before(before.map(String::from));
+        self.page(&Listing::Collaborations(user_id), &cursor)
+    }
+
+    /// Returns an iterator over the pages of the maps the given user collaborated on
+    pub fn collaboration_pages(&self, user_id: u64) -> Pages<'_> {
+        self.pages(Listing::Collaborations(user_id), Cursor::Before(None))
+    }
+
+    fn pages(&self, listing: Listing, cursor: Cursor) -> Pages<'_> {
+        Pages {
+            client: self,
+            listing,
+            cursor: Some(cursor),
+        }
+    }
+
+    /// Requests the page of a listing at the given cursor
+    fn page(&self, listing: &Listing, cursor: &Cursor) -> Result<Page, Box<dyn Error>> {
+        let (url, parameters) = listing.request(&self.options.base_url, cursor);
+        Ok(self.get_with_query(&url, &parameters)?.json()?)
+    }
+
+    /// Sends a GET request, retrying it according to the retry policy
+    fn get(&self, url: &str) -> Result<Response, Box<dyn Error>> {
+        self.get_with_query(url, &[])
+    }
+
+    /// Sends a GET request with the given query string parameters, retrying it according to the
+    /// retry policy
+    fn get_with_query(
+        &self,
+        url: &str,
+        parameters: &[(&str, String)],
+    ) -> Result<Response, Box<dyn Error>> {
+        if self.is_offline() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Client is offline",
+            )));
+        }
+
+        let mut backoff = self.options.retry.backoff;
+        let mut retries = 0;
+        loop {
+            let result = self.client.get(url).query(parameters).send();
+            let status = result.as_ref().map(Response::status);
+            if !is_retryable(status) || retries >= self.options.retry.max_retries {
+                return Ok(result?.error_for_status()?);
+            }
+
+            thread::sleep(backoff);
+            backoff *= 2;
+            retries += 1;
+        }
+    }
+}
+
+/// Returns whether a request failed in a way worth retrying
+///
+/// Connection errors, timeouts, server errors and rate limiting are retried.
+pub(crate) fn is_retryable(status: Result<StatusCode, &reqwest::Error>) -> bool {
+    match status {
+        Ok(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        Err(error) => error.is_connect() || error.is_timeout(),
+    }
+}
+
+/// Checks that a downloaded beatmap has the level hash of the requested version
+pub(crate) fn check_level_hash(beatmap: &Beatmap, hash: &str) -> io::Result<()> {
+    match beatmap.hash.as_deref() {
+        Some(computed) if computed.eq_ignore_ascii_case(hash) => Ok(()),
+        computed => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Downloaded zip has level hash {}, expected {}",
+                computed.unwrap_or("none"),
+                hash
+            ),
+        )),
+    }
+}
+
+/// Returns the error of a map missing from the cache of an offline client
+fn not_cached(id: &str) -> Box<dyn Error> {
+    Box::new(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Map {} isn't cached", id),
+    ))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        BeatSaverClient, BeatSaverRef, ClientOptions, Cursor, Difficulty, MapDetail, Page,
+        RetryPolicy, SearchQuery, SortOrder, ZipError,
+    };
+    use crate::downloads::{DownloadCache, DownloadCacheOptions};
+    use proptest::prelude::*;
+    use std::io;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Serves the responses of the handler on a local port, returning the base URL
+    pub fn mock_server<F>(handler: F) -> String
+    where
+        F: Fn(&tiny_http::Request) -> (u16, Vec<u8>) + Send + 'static,
+    {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let (status, body) = handler(&request);
+                let response = tiny_http::Response::from_data(body).with_status_code(status);
+                let _ = request.respond(response);
+            }
+        });
+        url
+    }
+
+    /// Returns the test map as a zip
+    pub fn test_zip() -> Vec<u8> {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test");
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for name in &["info.dat", "Expert.dat", "song.egg"] {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(&std::fs::read(resources.join(name)).unwrap())
+                .unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Returns client options pointing at a mock server
+    pub fn test_options(url: &str) -> ClientOptions {
+        ClientOptions {
+            base_url: url.to_string(),
+            cdn_url: format!("{}/cdn", url),
+            retry: RetryPolicy {
+                max_retries: 2,
+                backoff: Duration::from_millis(1),
+            },
+            ..ClientOptions::default()
+        }
+    }
+
+    /// Returns a recorded API response, with the CDN URLs pointing at the mock server
+    pub fn fixture(name: &str, request: &tiny_http::Request) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/test/beatsaver")
+            .join(name);
+        let host = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Host"))
+            .map(|header| header.value.to_string())
+            .unwrap_or_default();
+
+        std::fs::read_to_string(path)
+            .unwrap()
+            .replace(
+                "https://r2cdn.beatsaver.com",
+                &format!("http://{}/cdn", host),
+            )
+            .into_bytes()
+    }
+
+    #[test]
+    fn map_detail() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test/beatsaver/map.json");
+        let map: MapDetail = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+
+        assert_eq!(map.uploader.name, "Mapper");
+        assert!(map.uploader.verified_mapper);
+        assert_eq!(map.metadata.duration, 10);
+        assert_eq!((map.stats.upvotes, map.stats.downvotes), (1024, 16));
+        assert_eq!(map.tags, vec!["dance", "pop"]);
+        assert!(map.ranked && !map.bl_ranked);
+        assert!(map.is_curated());
+        assert_eq!(map.curator.as_ref().unwrap().name, "Curator");
+
+        let version = map.latest_version().unwrap();
+        assert_eq!(version.sage_score, Some(2));
+        let summary = &version.diffs[0];
+        assert_eq!(summary.difficulty, Difficulty::Expert);
+        assert_eq!(summary.parity_summary.warns, 1);
+        assert_eq!(summary.stars, Some(4.25));
+    }
+
+    #[test]
+    fn beatmap_by_id() {
+        let url = mock_server(|request| {
+            let user_agent = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("User-Agent"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            assert!(user_agent.starts_with("beatmap-parser/"));
+
+            match request.url() {
+                "/maps/id/3cf5" | "/maps/hash/5812661BF5320A04E14933D861B56FA4D9706104" => {
+                    (200, fixture("map.json", request))
+                }
+                "/cdn/5812661bf5320a04e14933d861b56fa4d9706104.zip" => (200, test_zip()),
+                _ => (404, Vec::new()),
+            }
+        });
+        let client = BeatSaverClient::new(test_options(&url)).unwrap();
+
+        let beatmap = client.beatmap_by_id("3cf5").unwrap();
+        let beatsaver = beatmap.beatsaver.unwrap();
+        assert_eq!(beatsaver.key, "3cf5");
+        assert_eq!(beatsaver.map.uploader.name, "Mapper");
+        assert_eq!(beatsaver.version().unwrap().diffs.len(), 1);
+        assert_eq!(beatmap.info.song_name, "Test Song");
+
+        let hash = "5812661BF5320A04E14933D861B56FA4D9706104";
+        let beatmap = client.beatmap_by_hash(hash).unwrap();
+        assert_eq!(beatmap.beatsaver.unwrap().hash, hash.to_lowercase());
+        assert!(client.map_by_id("0000").is_err());
+    }
+
+    #[test]
+    fn shared_client() {
+        let client = BeatSaverClient::shared().unwrap();
+        assert!(std::ptr::eq(client, BeatSaverClient::shared().unwrap()));
+        assert_eq!(client.options(), &ClientOptions::default());
+    }
+
+    #[test]
+    fn paging() {
+        let url = mock_server(|request| {
+            let url = request.url();
+            let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+            match path {
+                "/search/text/0" | "/search/text/1" => {
+                    for parameter in &["q=test+song", "order=Rating", "ranked=true", "minNps=1.5"] {
+                        assert!(query.contains(parameter), "{} in {}", parameter, query);
+                    }
+                    assert!(query.contains("tags=dance%2Cpop"));
+                    let name = format!("search_{}.json", &path[13..]);
+                    (200, fixture(&name, request))
+                }
+                "/maps/latest" | "/maps/collaborations/4284201" if query.contains("before=") => {
+                    (200, br#"{"docs":[]}"#.to_vec())
+                }
+                "/maps/latest" => (200, fixture("latest.json", request)),
+                "/maps/collaborations/4284201" => (200, fixture("collaborations.json", request)),
+                "/maps/uploader/4284201/0" => (200, fixture("search_1.json", request)),
+                "/maps/uploader/4284201/1" => (200, br#"{"docs":[]}"#.to_vec()),
+                _ => (404, Vec::new()),
+            }
+        });
+        let client = BeatSaverClient::new(test_options(&url)).unwrap();
+
+        let query = SearchQuery {
+            query: String::from("test song"),
+            order: SortOrder::Rating,
+            ranked: Some(true),
+            min_nps: Some(1.5),
+            tags: vec![String::from("dance"), String::from("pop")],
+            ..SearchQuery::default()
+        };
+        let page = client.search(&query, 0).unwrap();
+        assert_eq!(page.info.unwrap().total, 3);
+        let ids: Vec<_> = client
+            .search_pages(&query)
+            .flat_map(|page| page.unwrap().docs)
+            .map(|map| map.id)
+            .collect();
+        assert_eq!(ids, vec!["3cf5", "1fef", "1a2b"]);
+
+        // Pages requested with an upload date cursor stop at the first empty page
+        let mut pages = client.latest_pages();
+        assert_eq!(pages.next().unwrap().unwrap().docs.len(), 2);
+        assert_eq!(
+            pages.cursor(),
+            Some(&Cursor::Before(Some(String::from(
+                "2021-07-19T22:15:00.000Z"
+            ))))
+        );
+        assert!(pages.next().unwrap().unwrap().docs.is_empty());
+        assert!(pages.next().is_none());
+
+        assert_eq!(client.uploader_pages(4284201).count(), 2);
+        let collaborations = client.collaborations(4284201, None).unwrap();
+        assert_eq!(collaborations.docs[0].id, "2e9a");
+        assert_eq!(client.collaboration_pages(4284201).count(), 2);
+
+        let mut pages = client.uploader_pages(1);
+        assert!(pages.next().unwrap().is_err());
+        assert!(pages.next().is_none());
+    }
+
+    #[test]
+    fn next_cursor_stuck() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test/beatsaver/map.json");
+        let map: MapDetail = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        let page = Page {
+            docs: vec![map.clone()],
+            info: None,
+        };
+
+        let next = page.next_cursor(&Cursor::Before(None));
+        assert_eq!(next, Some(Cursor::Before(Some(map.uploaded.clone()))));
+        // A page only holding maps uploaded at the cursor date doesn't move it
+        assert_eq!(page.next_cursor(&next.unwrap()), None);
+    }
+
+    #[test]
+    fn download_cache() {
+        let hash = "5812661bf5320a04e14933d861b56fa4d9706104";
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let url = mock_server(move |request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match request.url() {
+                "/maps/id/3cf5" => (200, fixture("map.json", request)),
+                "/cdn/5812661bf5320a04e14933d861b56fa4d9706104.zip" => (200, test_zip()),
+                _ => (404, Vec::new()),
+            }
+        });
+        let root = std::env::temp_dir().join(format!(
+            "beatmap-parser-client-cache-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+
+        // The zip is only downloaded once
+        let cache = DownloadCache::open(&root, DownloadCacheOptions::default()).unwrap();
+        let client = BeatSaverClient::new(test_options(&url))
+            .unwrap()
+            .with_cache(cache);
+        let beatmap = client.beatmap_by_id("3cf5").unwrap();
+        assert_eq!(beatmap.hash.as_deref(), Some(hash));
+        client.beatmap_by_id("3cf5").unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(client.cache().unwrap().len(), 1);
+
+        // An offline client doesn't send any request
+        let options = DownloadCacheOptions {
+            offline: true,
+            ..DownloadCacheOptions::default()
+        };
+        let cache = DownloadCache::open(&root, options).unwrap();
+        let client = BeatSaverClient::new(test_options(&url))
+            .unwrap()
+            .with_cache(cache);
+        let beatmap = client.beatmap_by_id("3cf5").unwrap();
+        assert_eq!(beatmap.beatsaver.unwrap().map.uploader.name, "Mapper");
+        assert!(client.beatmap_by_hash(hash).is_ok());
+        assert!(client.beatmap_by_id("1fef").is_err());
+        assert!(client.map_by_id("3cf5").is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn download_limit() {
+        let url = mock_server(|request| match request.url() {
+            "/maps/id/3cf5" => (200, fixture("map.json", request)),
+            "/cdn/5812661bf5320a04e14933d861b56fa4d9706104.zip" => (200, test_zip()),
+            _ => (404, Vec::new()),
+        });
+        let mut options = test_options(&url);
+        options.zip_limits.max_archive_size = 64;
+        let is_too_large = |error: Box<dyn std::error::Error>| {
+            matches!(
+                error.downcast_ref::<ZipError>(),
+                Some(ZipError::ArchiveTooLarge { max: 64 })
+            )
+        };
+
+        let client = BeatSaverClient::new(options.clone()).unwrap();
+        assert!(is_too_large(client.beatmap_by_id("3cf5").unwrap_err()));
+
+        // Nothing is cached either
+        let root = std::env::temp_dir().join(format!(
+            "beatmap-parser-client-limit-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        let cache = DownloadCache::open(&root, DownloadCacheOptions::default()).unwrap();
+        let client = BeatSaverClient::new(options).unwrap().with_cache(cache);
+        assert!(is_too_large(client.beatmap_by_id("3cf5").unwrap_err()));
+        assert!(client.cache().unwrap().is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn parse_refs() {
+        let key = |key: &str| Ok(BeatSaverRef::Key(key.to_string()));
+        let hash = "5812661bf5320a04e14933d861b56fa4d9706104";
+        let parse = |input: &str| BeatSaverRef::parse(input).map_err(|error| error.kind());
+
+        assert_eq!(parse("3cf5"), key("3cf5"));
+        assert_eq!(parse(" 3CF5\n"), key("3cf5"));
+        assert_eq!(parse("beatsaver://3cf5/"), key("3cf5"));
+        assert_eq!(parse("https://beatsaver.com/maps/3cf5/"), key("3cf5"));
+        assert_eq!(parse("beatsaver.com/beatmap/1fef"), key("1fef"));
+        assert_eq!(parse("https://www.bsaber.com/songs/1fef/"), key("1fef"));
+        assert_eq!(
+            parse("https://beatsaver.com/api/download/key/3cf5/"),
+            key("3cf5")
+        );
+        assert_eq!(
+            parse(&format!("https://api.beatsaver.com/maps/hash/{}", hash)),
+            Ok(BeatSaverRef::Hash(hash.to_string()))
+        );
+        assert_eq!(
+            parse(&format!("https://r2cdn.beatsaver.com/{}.zip?v=2#top", hash)),
+            Ok(BeatSaverRef::Hash(hash.to_string()))
+        );
+
+        for input in &[
+            "",
+            "beatsaver://",
+            "https://beatsaver.com/maps/",
+            "https://beatsaver.com/maps/xyz",
+            "https://example.com/maps/3cf5",
+            "https://beatsaver.com/3cf5.zip",
+            "3cf5/",
+        ] {
+            assert_eq!(parse(input), Err(io::ErrorKind::InvalidInput), "{}", input);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn parse_key_urls(
+            key in "[0-9a-fA-F]{1,6}",
+            prefix in prop::sample::select(vec![
+                "",
+                "beatsaver://",
+                "https://beatsaver.com/maps/",
+                "http://www.beatsaver.com/beatmap/",
+                "beatsaver.com/api/download/key/",
+                "https://api.beatsaver.com/maps/id/",
+                "https://bsaber.com/songs/",
+            ]),
+            trailing_slash in any::<bool>(),
+        ) {
+            let slash = if trailing_slash && !prefix.is_empty() { "/" } else { "" };
+            let input = format!("{}{}{}", prefix, key, slash);
+            prop_assert_eq!(
+                BeatSaverRef::parse(&input).unwrap(),
+                BeatSaverRef::Key(key.to_lowercase())
+            );
+        }
+
+        #[test]
+        fn parse_hash_urls(
+            hash in "[0-9a-fA-F]{40}",
+            host in "(r2cdn|cdn|eu\\.cdn)\\.beatsaver\\.com",
+            query in "(\\?[a-z]{1,8}=[a-z0-9]{0,8})?",
+        ) {
+            let expected = BeatSaverRef::Hash(hash.to_lowercase());
+            let input = format!("https://{}/{}.zip{}", host, hash, query);
+            prop_assert_eq!(BeatSaverRef::parse(&input).unwrap(), expected.clone());
+            prop_assert_eq!(BeatSaverRef::parse(&hash).unwrap(), expected);
+        }
+
+        #[test]
+        fn parse_never_panics(input in "\\PC*") {
+            let _ = BeatSaverRef::parse(&input);
+        }
+    }
+
+    #[test]
+    fn retry() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let url = mock_server(move |_| match counter.fetch_add(1, Ordering::SeqCst) {
+            0 => (503, Vec::new()),
+            1 => (429, Vec::new()),
+            _ => (200, br#"{"id":"1","name":"Retried"}"#.to_vec()),
+        });
+
+        let client = BeatSaverClient::new(test_options(&url)).unwrap();
+        assert_eq!(client.map_by_id("1").unwrap().name, "Retried");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Gives up once the retries are exhausted
+        let url = mock_server(|_| (500, Vec::new()));
+        let client = BeatSaverClient::new(test_options(&url)).unwrap();
+        assert!(client.map_by_id("1").is_err());
+    }
+}
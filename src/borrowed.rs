@@ -0,0 +1,397 @@
+use crate::difficulty::difficulty::{
+    self, note, obstacle::ObstacleType, BPMChange, Bookmark, Event, LineIndex, Note, Obstacle,
+};
+use crate::difficulty::Difficulty;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Represents an event borrowing its custom data from the parsed file
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct BorrowedEvent<'a> {
+    /// Time of the event in beats
+    #[serde(rename = "_time")]
+    pub time: f64,
+    /// Raw type of the event
+    #[serde(rename = "_type", deserialize_with = "difficulty::event_type")]
+    pub event_type: u8,
+    /// Raw value of the event
+    #[serde(rename = "_value", deserialize_with = "difficulty::event_value")]
+    pub value: u32,
+    /// Custom data used by mods, left unparsed
+    #[serde(rename = "_customData", default, borrow)]
+    pub custom_data: Option<&'a RawValue>,
+}
+
+/// Represents a note borrowing its custom data from the parsed file
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct BorrowedNote<'a> {
+    /// Time of the note in beats
+    #[serde(rename = "_time")]
+    pub time: f64,
+    /// Horizontal position of the note
+    #[serde(rename = "_lineIndex")]
+    pub line_index: LineIndex,
+    /// Vertical position of the note
+    #[serde(rename = "_lineLayer")]
+    pub line_layer: note::LineLayer,
+    /// Type of the note
+    #[serde(rename = "_type")]
+    pub note_type: note::NoteType,
+    /// Cut direction of the note
+    #[serde(rename = "_cutDirection")]
+    pub cut_direction: note::CutDirection,
+    /// Custom data used by mods, left unparsed
+    #[serde(rename = "_customData", default, borrow)]
+    pub custom_data: Option<&'a RawValue>,
+}
+
+/// Represents an obstacle borrowing its custom data from the parsed file
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct BorrowedObstacle<'a> {
+    /// Time of the obstacle in beats
+    #[serde(rename = "_time")]
+    pub time: f64,
+    /// Horizontal position of the obstacle
+    #[serde(rename = "_lineIndex")]
+    pub line_index: LineIndex,
+    /// Type of the obstacle
+    #[serde(rename = "_type")]
+    pub obstacle_type: ObstacleType,
+    /// Length of the obstacle, in beats
+    #[serde(rename = "_duration")]
+    pub duration: f64,
+    /// Width of the obstacle in lines, extending to the right
+    #[serde(rename = "_width", deserialize_with = "difficulty::obstacle_width")]
+    pub width: i32,
+    /// Custom data used by mods, left unparsed
+    #[serde(rename = "_customData", default, borrow)]
+    pub custom_data: Option<&'a RawValue>,
+}
+
+/// Represents an editor bookmark borrowing its name from the parsed file
+#[derive(Deserialize, Clone, Debug)]
+pub struct BorrowedBookmark<'a> {
+    /// Time of the bookmark, in beats
+    #[serde(rename = "_time")]
+    pub time: f64,
+    /// Name of the bookmark, only owned when it contains escapes
+    #[serde(rename = "_name", default, borrow)]
+    pub name: Cow<'a, str>,
+}
+
+/// Represents a difficulty file borrowing its strings and custom data from the parsed file
+///
+/// Parsing is cheaper than for `Difficulty` since custom data is kept as raw JSON and strings
+/// are only copied when they contain escapes.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BorrowedDifficulty<'a> {
+    /// Format version
+    #[serde(rename = "_version", borrow)]
+    pub version: Cow<'a, str>,
+    /// BPM changes
+    #[serde(rename = "_BPMChanges", default)]
+    pub bpm_changes: Vec<BPMChange>,
+    /// Lighting and other various events
+    #[serde(rename = "_events", borrow)]
+    pub events: Vec<BorrowedEvent<'a>>,
+    /// Notes
+    #[serde(rename = "_notes", borrow)]
+    pub notes: Vec<BorrowedNote<'a>>,
+    /// Walls and ceilings
+    #[serde(rename = "_obstacles", borrow)]
+    pub obstacles: Vec<BorrowedObstacle<'a>>,
+    /// Bookmarks, used by editors
+    #[serde(rename = "_bookmarks", default, borrow)]
+    pub bookmarks: Vec<BorrowedBookmark<'a>>,
+}
+
+/// Parses raw custom data
+fn parse_custom_data(custom_data: Option<&RawValue>) -> serde_json::Result<Option<Value>> {
+    custom_data
+        .map(|custom_data| serde_json::from_str(custom_data.get()))
+        .transpose()
+}
+
+impl BorrowedEvent<'_> {
+    /// Returns an owned copy of the event
+    pub fn to_owned(&self) -> serde_json::Result<Event> {
+        Ok(Event {
+            time: self.time,
+            event_type: self.event_type,
+            value: self.value,
+            custom_data: parse_custom_data(self.custom_data)?,
+        })
+    }
+}
+
+impl BorrowedNote<'_> {
+    /// Returns an owned copy of the note
+    pub fn to_owned(&self) -> serde_json::Result<Note> {
+        Ok(Note {
+            time: self.time,
+            line_index: self.line_index,
+            line_layer: self.line_layer,
+            note_type: self.note_type,
+            cut_direction: self.cut_direction,
+            custom_data: parse_custom_data(self.custom_data)?,
+        })
+    }
+}
+
+impl BorrowedObstacle<'_> {
+    /// Returns an owned copy of the obstacle
+    pub fn to_owned(&self) -> serde_json::Result<Obstacle> {
+        Ok(Obstacle {
+            time: self.time,
+            line_index: self.line_index,
+            obstacle_type: self.obstacle_type,
+            duration: self.duration,
+            width: self.width,
+            custom_data: parse_custom_data(self.custom_data)?,
+        })
+    }
+}
+
+impl<'a> BorrowedDifficulty<'a> {
+    /// Parses a difficulty file without copying its strings and custom data
+    pub fn from_slice(data: &'a [u8]) -> serde_json::Result<BorrowedDifficulty<'a>> {
+        serde_json::from_slice(data)
+    }
+
+    /// Returns an owned copy of the difficulty
+    pub fn to_owned(&self) -> Result<Difficulty, Box<dyn Error>> {
+        Ok(Difficulty {
+            version: self.version.parse()?,
+            bpm_changes: self.bpm_changes.clone(),
+            events: self
+                .events
+                .iter()
+                .map(BorrowedEvent::to_owned)
+                .collect::<Result<_, _>>()?,
+            notes: self
+                .notes
+                .iter()
+                .map(BorrowedNote::to_owned)
+                .collect::<Result<_, _>>()?,
+            obstacles: self
+                .obstacles
+                .iter()
+                .map(BorrowedObstacle::to_owned)
+                .collect::<Result<_, _>>()?,
+            bookmarks: self
+                .bookmarks
+                .iter()
+                .map(|bookmark| Bookmark {
+                    time: bookmark.time,
+                    name: bookmark.name.to_string(),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Represents a visitor of the objects of a difficulty file, called in file order
+pub trait DifficultyVisitor<'a> {
+    /// Visits an event
+    fn visit_event(&mut self, _event: BorrowedEvent<'a>) {}
+
+    /// Visits a note
+    fn visit_note(&mut self, _note: BorrowedNote<'a>) {}
+
+    /// Visits an obstacle
+    fn visit_obstacle(&mut self, _obstacle: BorrowedObstacle<'a>) {}
+}
+
+/// Parses a difficulty file, passing each object to the visitor instead of collecting them
+///
+/// Other fields are skipped without being parsed.
+pub fn stream<'a, V: DifficultyVisitor<'a>>(
+    data: &'a [u8],
+    visitor: &mut V,
+) -> serde_json::Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_slice(data);
+    deserializer.deserialize_map(StreamVisitor { visitor })?;
+    deserializer.end()
+}
+
+/// Parses a difficulty file, passing each note to the closure instead of collecting them
+pub fn for_each_note<'a>(
+    data: &'a [u8],
+    f: impl FnMut(BorrowedNote<'a>),
+) -> serde_json::Result<()> {
+    struct NoteVisitor<F>(F);
+
+    impl<'a, F: FnMut(BorrowedNote<'a>)> DifficultyVisitor<'a> for NoteVisitor<F> {
+        fn visit_note(&mut self, note: BorrowedNote<'a>) {
+            (self.0)(note)
+        }
+    }
+
+    stream(data, &mut NoteVisitor(f))
+}
+
+/// Represents the streamed fields of a difficulty file
+#[derive(Deserialize)]
+enum Field {
+    #[serde(rename = "_events")]
+    Events,
+    #[serde(rename = "_notes")]
+    Notes,
+    #[serde(rename = "_obstacles")]
+    Obstacles,
+    #[serde(other)]
+    Other,
+}
+
+struct StreamVisitor<'v, V> {
+    visitor: &'v mut V,
+}
+
+impl<'de, V: DifficultyVisitor<'de>> Visitor<'de> for StreamVisitor<'_, V> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a difficulty file")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(field) = map.next_key()? {
+            match field {
+                Field::Events => map.next_value_seed(ListSeed {
+                    visitor: &mut *self.visitor,
+                    visit: |visitor: &mut V, event| visitor.visit_event(event),
+                    element: PhantomData,
+                })?,
+                Field::Notes => map.next_value_seed(ListSeed {
+                    visitor: &mut *self.visitor,
+                    visit: |visitor: &mut V, note| visitor.visit_note(note),
+                    element: PhantomData,
+                })?,
+                Field::Obstacles => map.next_value_seed(ListSeed {
+                    visitor: &mut *self.visitor,
+                    visit: |visitor: &mut V, obstacle| visitor.visit_obstacle(obstacle),
+                    element: PhantomData,
+                })?,
+                Field::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Passes each element of a list to a visitor
+struct ListSeed<'v, V, F, T> {
+    visitor: &'v mut V,
+    visit: F,
+    element: PhantomData<T>,
+}
+
+impl<'de, V, F, T> DeserializeSeed<'de> for ListSeed<'_, V, F, T>
+where
+    F: FnMut(&mut V, T),
+    T: Deserialize<'de>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, V, F, T> Visitor<'de> for ListSeed<'_, V, F, T>
+where
+    F: FnMut(&mut V, T),
+    T: Deserialize<'de>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element()? {
+            (self.visit)(self.visitor, element);
+        }
+        Ok(())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{for_each_note, stream, BorrowedDifficulty, BorrowedNote, DifficultyVisitor};
+    use crate::difficulty::difficulty::note::NoteType;
+    use crate::difficulty::Difficulty;
+    use crate::test_util::test_difficulty_bytes;
+    use std::borrow::Cow;
+
+    #[test]
+    fn borrowed() {
+        let data = test_difficulty_bytes();
+        let borrowed = BorrowedDifficulty::from_slice(&data).unwrap();
+        assert!(matches!(borrowed.version, Cow::Borrowed("2.0.0")));
+        assert!(matches!(borrowed.bookmarks[0].name, Cow::Borrowed("Start")));
+
+        let owned: Difficulty = serde_json::from_slice(&data).unwrap();
+        assert_eq!(borrowed.to_owned().unwrap(), owned);
+
+        let data = br#"{ "_version": "2.0.0", "_events": [], "_obstacles": [], "_notes": [
+            { "_time": 1, "_lineIndex": 1, "_lineLayer": 0, "_type": 0, "_cutDirection": 1,
+              "_customData": { "_position": [0.5, 1] } }
+        ] }"#;
+        let borrowed = BorrowedDifficulty::from_slice(data).unwrap();
+        let custom_data = borrowed.notes[0].custom_data.unwrap();
+        assert_eq!(custom_data.get(), r#"{ "_position": [0.5, 1] }"#);
+        let note = borrowed.notes[0].to_owned().unwrap();
+        assert_eq!(note.custom_data.unwrap()["_position"][0], 0.5);
+    }
+
+    #[test]
+    fn streaming() {
+        #[derive(Default)]
+        struct Counts {
+            events: usize,
+            bombs: usize,
+            obstacles: usize,
+        }
+
+        impl<'a> DifficultyVisitor<'a> for Counts {
+            fn visit_event(&mut self, _: super::BorrowedEvent<'a>) {
+                self.events += 1;
+            }
+
+            fn visit_note(&mut self, note: BorrowedNote<'a>) {
+                if note.note_type == NoteType::Bomb {
+                    self.bombs += 1;
+                }
+            }
+
+            fn visit_obstacle(&mut self, _: super::BorrowedObstacle<'a>) {
+                self.obstacles += 1;
+            }
+        }
+
+        let data = test_difficulty_bytes();
+        let mut counts = Counts::default();
+        stream(&data, &mut counts).unwrap();
+        assert_eq!((counts.events, counts.bombs, counts.obstacles), (2, 1, 2));
+
+        let mut times = Vec::new();
+        for_each_note(&data, |note| times.push(note.time)).unwrap();
+        assert_eq!(times, vec![1.0, 1.0, 2.0, 2.0, 3.0, 4.0, 4.5]);
+
+        assert!(for_each_note(b"{ \"_notes\": [{}] }", |_| ()).is_err());
+    }
+}
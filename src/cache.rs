@@ -0,0 +1,262 @@
+use crate::info::Info;
+use crate::parse::ParseWarning;
+use crate::source::MapSource;
+use crate::{Beatmap, DifficultyHashMap};
+use serde::{Deserialize, Serialize};
+use sha1_smol::Digest;
+use std::error::Error;
+use std::fmt;
+
+#[cfg(feature = "audio")]
+use crate::audio::AudioInfo;
+#[cfg(feature = "beatsaver")]
+use crate::beatsaver::BeatSaverInfo;
+
+/// Version of the cache format, bumped whenever the cached types change
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Bytes every cache starts with
+const MAGIC: &[u8; 8] = b"BMPCACHE";
+
+/// Length of the header, made of the magic bytes, the schema version and the source hash
+const HEADER_LENGTH: usize = 8 + 4 + 20;
+
+/// Represents the header of a cache
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Header {
+    /// Version of the cache format
+    pub schema_version: u32,
+    /// Hash of the map files the beatmap was parsed from, see `Beatmap::hash`
+    pub source_hash: String,
+}
+
+/// Represents an error while writing or reading a cache
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum CacheError {
+    /// The beatmap has no source hash, because its difficulties were loaded lazily
+    Unhashed,
+    /// The data doesn't start with a valid header
+    InvalidHeader,
+    /// The cache was written with another version of the format
+    SchemaMismatch { found: u32, expected: u32 },
+    /// The cache was written from other map files
+    Stale { cached: String, current: String },
+    /// The beatmap couldn't be serialized or deserialized
+    Corrupt(String),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheError::Unhashed => write!(f, "Beatmap has no source hash"),
+            CacheError::InvalidHeader => write!(f, "Invalid cache header"),
+            CacheError::SchemaMismatch { found, expected } => write!(
+                f,
+                "Cache schema version {} doesn't match version {}",
+                found, expected
+            ),
+            CacheError::Stale { cached, current } => write!(
+                f,
+                "Cache of {} is stale, the map files hash to {}",
+                cached, current
+            ),
+            CacheError::Corrupt(message) => write!(f, "Corrupt cache: {}", message),
+        }
+    }
+}
+
+impl Error for CacheError {}
+
+/// Represents the cached fields of a beatmap, borrowed for serialization
+#[derive(Serialize)]
+struct CachedBeatmapRef<'a> {
+    info: &'a Info,
+    difficulties: &'a DifficultyHashMap,
+    warnings: &'a [ParseWarning],
+    #[cfg(feature = "beatsaver")]
+    beatsaver: &'a Option<BeatSaverInfo>,
+    #[cfg(feature = "audio")]
+    audio: &'a AudioInfo,
+}
+
+/// Represents the cached fields of a beatmap
+#[derive(Deserialize)]
+struct CachedBeatmap {
+    info: Info,
+    difficulties: DifficultyHashMap,
+    warnings: Vec<ParseWarning>,
+    #[cfg(feature = "beatsaver")]
+    beatsaver: Option<BeatSaverInfo>,
+    #[cfg(feature = "audio")]
+    audio: AudioInfo,
+}
+
+/// Serializes a beatmap to the cache format
+///
+/// The beatmap must have been parsed with every difficulty up front, so that it has a source
+/// hash. Transforms applied since parsing are kept, the hash still refers to the map files.
+pub fn to_bytes(beatmap: &Beatmap) -> Result<Vec<u8>, CacheError> {
+    let hash = beatmap.hash.as_ref().ok_or(CacheError::Unhashed)?;
+    let digest: Digest = hash
+        .parse()
+        .map_err(|_| CacheError::Corrupt(format!("Invalid source hash {}", hash)))?;
+
+    let mut data = Vec::with_capacity(HEADER_LENGTH);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    data.extend_from_slice(&digest.bytes());
+
+    let cached = CachedBeatmapRef {
+        info: &beatmap.info,
+        difficulties: &beatmap.difficulties,
+        warnings: &beatmap.warnings,
+        #[cfg(feature = "beatsaver")]
+        beatsaver: &beatmap.beatsaver,
+        #[cfg(feature = "audio")]
+        audio: &beatmap.audio,
+    };
+    rmp_serde::encode::write_named(&mut data, &cached)
+        .map_err(|error| CacheError::Corrupt(error.to_string()))?;
+    Ok(data)
+}
+
+/// Reads the header of a cache
+pub fn header(data: &[u8]) -> Result<Header, CacheError> {
+    if data.len() < HEADER_LENGTH || !data.starts_with(MAGIC) {
+        return Err(CacheError::InvalidHeader);
+    }
+    let mut schema_version = [0; 4];
+    schema_version.copy_from_slice(&data[8..12]);
+
+    let source_hash = data[12..HEADER_LENGTH]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(Header {
+        schema_version: u32::from_le_bytes(schema_version),
+        source_hash,
+    })
+}
+
+/// Returns a beatmap from the cache format, given the current hash of its map files
+///
+/// Caches written with another schema version or from other map files are rejected. The
+/// current hash can be computed with `source_hash`.
+pub fn from_bytes(data: &[u8], current_hash: &str) -> Result<Beatmap, CacheError> {
+    let header = header(data)?;
+    if header.schema_version != SCHEMA_VERSION {
+        return Err(CacheError::SchemaMismatch {
+            found: header.schema_version,
+            expected: SCHEMA_VERSION,
+        });
+    }
+    if !header.source_hash.eq_ignore_ascii_case(current_hash) {
+        return Err(CacheError::Stale {
+            cached: header.source_hash,
+            current: current_hash.to_string(),
+        });
+    }
+
+    let cached: CachedBeatmap = rmp_serde::from_slice(&data[HEADER_LENGTH..])
+        .map_err(|error| CacheError::Corrupt(error.to_string()))?;
+    Ok(Beatmap {
+        info: cached.info,
+        difficulties: cached.difficulties,
+        warnings: cached.warnings,
+        hash: Some(header.source_hash),
+        lazy: None,
+        #[cfg(feature = "beatsaver")]
+        beatsaver: cached.beatsaver,
+        #[cfg(feature = "audio")]
+        audio: cached.audio,
+    })
+}
+
+/// Returns the hash of the map files of a source, as found in `Beatmap::hash`
+///
+/// See `source::level_hash`.
+pub fn source_hash(source: &mut dyn MapSource) -> Result<String, Box<dyn Error>> {
+    crate::source::level_hash(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_bytes, header, source_hash, to_bytes, CacheError, SCHEMA_VERSION};
+    use crate::info::info::difficulty_beatmap_set::{
+        difficulty_beatmap::DifficultyRank, BeatmapCharacteristic,
+    };
+    use crate::parse::ParseMode;
+    use crate::source::{DirectorySource, MemorySource};
+    use crate::test_util::test_dir;
+    use crate::Beatmap;
+
+    #[test]
+    fn round_trip() {
+        let mut beatmap = Beatmap::from_source(DirectorySource::new(test_dir())).unwrap();
+        beatmap.shift(1.0).unwrap();
+        let hash = beatmap.hash.clone().unwrap();
+        assert_eq!(
+            hash,
+            source_hash(&mut DirectorySource::new(test_dir())).unwrap()
+        );
+
+        let data = to_bytes(&beatmap).unwrap();
+        let header = header(&data).unwrap();
+        assert_eq!(header.schema_version, SCHEMA_VERSION);
+        assert_eq!(header.source_hash, hash);
+
+        let cached = from_bytes(&data, &hash).unwrap();
+        let (characteristic, rank) = (BeatmapCharacteristic::Standard, DifficultyRank::Expert);
+        assert_eq!(cached.info.song_name, beatmap.info.song_name);
+        assert_eq!(
+            cached.difficulties[&characteristic][&rank],
+            beatmap.difficulties[&characteristic][&rank]
+        );
+        assert_eq!(cached.warnings, beatmap.warnings);
+        assert_eq!(cached.hash, Some(hash));
+        assert!(cached.is_loaded());
+    }
+
+    #[test]
+    fn rejected() {
+        let beatmap = Beatmap::from_source(DirectorySource::new(test_dir())).unwrap();
+        let hash = beatmap.hash.clone().unwrap();
+        let mut data = to_bytes(&beatmap).unwrap();
+
+        // The map files changed since the cache was written
+        let mut source = MemorySource::new();
+        for name in &["info.dat", "Expert.dat"] {
+            source.insert(*name, std::fs::read(test_dir().join(name)).unwrap());
+        }
+        source.insert("Expert.dat", b"{}".to_vec());
+        let current = source_hash(&mut source).unwrap();
+        assert!(matches!(
+            from_bytes(&data, &current),
+            Err(CacheError::Stale { .. })
+        ));
+
+        data[8] += 1;
+        assert_eq!(
+            from_bytes(&data, &hash).err(),
+            Some(CacheError::SchemaMismatch {
+                found: SCHEMA_VERSION + 1,
+                expected: SCHEMA_VERSION
+            })
+        );
+        data[8] -= 1;
+
+        let length = data.len();
+        assert!(matches!(
+            from_bytes(&data[..length - 8], &hash),
+            Err(CacheError::Corrupt(_))
+        ));
+        assert_eq!(
+            from_bytes(b"{}", &hash).err(),
+            Some(CacheError::InvalidHeader)
+        );
+
+        let filename = test_dir().join("info.dat");
+        let lazy = Beatmap::from_file_dat_lazy(filename.to_str().unwrap(), ParseMode::Strict);
+        assert_eq!(to_bytes(&lazy.unwrap()).err(), Some(CacheError::Unhashed));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::info::info::custom_data::Contributor;
+use crate::info::Info;
+use crate::source::MapSource;
+use std::error::Error;
+use std::io::{self, Cursor};
+use std::path::Path;
+
+/// Represents a cover image format
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    /// Detects the format of an image from its contents
+    pub fn detect(data: &[u8]) -> Option<ImageFormat> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(ImageFormat::Jpeg)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether the given file extension is used by the format, ignoring case
+    pub fn matches_extension(self, extension: &str) -> bool {
+        let extension = extension.to_lowercase();
+        match self {
+            ImageFormat::Jpeg => extension == "jpg" || extension == "jpeg",
+            ImageFormat::Png => extension == "png",
+        }
+    }
+
+    fn to_image_format(self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::Png => image::ImageFormat::Png,
+        }
+    }
+}
+
+/// Represents a cover image
+#[derive(PartialEq, Clone, Debug)]
+pub struct Cover {
+    /// Format of the image, detected from its contents
+    pub format: ImageFormat,
+    /// Width of the image, in pixels
+    pub width: u32,
+    /// Height of the image, in pixels
+    pub height: u32,
+    /// File size, in bytes
+    pub file_size: u64,
+    /// Contents of the file
+    pub data: Vec<u8>,
+}
+
+/// Represents a problem with the cover image of a map
+#[derive(PartialEq, Clone, Debug)]
+pub enum CoverIssue {
+    /// The cover file doesn't exist
+    Missing,
+    /// The cover file isn't a readable JPEG or PNG image
+    Unreadable(String),
+    /// The cover isn't square
+    NotSquare { width: u32, height: u32 },
+    /// The cover file is larger than the limit, in bytes
+    Oversize { file_size: u64, max_file_size: u64 },
+    /// The file extension doesn't match the image format
+    ExtensionMismatch {
+        extension: String,
+        format: ImageFormat,
+    },
+}
+
+/// Represents the icon of a contributor, along with its problems
+#[derive(PartialEq, Clone, Debug)]
+pub struct ContributorIcon {
+    /// Contributor the icon belongs to
+    pub contributor: Contributor,
+    /// Icon image, if it could be read
+    pub icon: Option<Cover>,
+    /// Problems of the icon
+    pub issues: Vec<CoverIssue>,
+}
+
+/// Represents the limits a cover image is checked against
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct CoverLimits {
+    /// Largest accepted file size, in bytes
+    pub max_file_size: u64,
+}
+
+impl Default for CoverLimits {
+    fn default() -> CoverLimits {
+        CoverLimits {
+            max_file_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Cover {
+    /// Returns a new Cover from the contents of a JPEG or PNG file
+    ///
+    /// The whole image is decoded, so that truncated or corrupt files are rejected.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Cover, Box<dyn Error>> {
+        let format = ImageFormat::detect(&data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Unsupported image format")
+        })?;
+        let image = image::load_from_memory_with_format(&data, format.to_image_format())?;
+        let (width, height) = (image.width(), image.height());
+
+        Ok(Cover {
+            format,
+            width,
+            height,
+            file_size: data.len() as u64,
+            data,
+        })
+    }
+
+    /// Returns the ratio of the width over the height
+    pub fn aspect_ratio(&self) -> f64 {
+        f64::from(self.width) / f64::from(self.height.max(1))
+    }
+
+    /// Returns the problems of the cover image, given its file name
+    pub fn issues(&self, filename: &str, limits: &CoverLimits) -> Vec<CoverIssue> {
+        let mut issues = Vec::new();
+        if self.width != self.height {
+            issues.push(CoverIssue::NotSquare {
+                width: self.width,
+                height: self.height,
+            });
+        }
+        if self.file_size > limits.max_file_size {
+            issues.push(CoverIssue::Oversize {
+                file_size: self.file_size,
+                max_file_size: limits.max_file_size,
+            });
+        }
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+        if !self.format.matches_extension(extension) {
+            issues.push(CoverIssue::ExtensionMismatch {
+                extension: extension.to_string(),
+                format: self.format,
+            });
+        }
+        issues
+    }
+
+    /// Returns a thumbnail fitting in a square of the given size, encoded in the given format
+    ///
+    /// The aspect ratio of the cover is preserved.
+    pub fn thumbnail(&self, size: u32, format: ImageFormat) -> Result<Vec<u8>, Box<dyn Error>> {
+        let image = image::load_from_memory_with_format(&self.data, self.format.to_image_format())?;
+        let thumbnail = image.thumbnail(size, size);
+        let thumbnail = match format {
+            ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(thumbnail.to_rgb8()),
+            ImageFormat::Png => thumbnail,
+        };
+
+        let mut data = Cursor::new(Vec::new());
+        thumbnail.write_to(&mut data, format.to_image_format())?;
+        Ok(data.into_inner())
+    }
+}
+
+/// Loads the cover image referenced by `info.dat` and checks it
+///
+/// The cover is returned along with its problems when it can be read, otherwise the problems
+/// explain why it couldn't.
+pub fn inspect(
+    source: &mut dyn MapSource,
+    info: &Info,
+    limits: &CoverLimits,
+) -> (Option<Cover>, Vec<CoverIssue>) {
+    inspect_file(source, &info.cover_image_filename, limits)
+}
+
+/// Loads the icons of the contributors listed in `info.dat` and checks them
+///
+/// Contributors without an icon are skipped.
+pub fn inspect_icons(
+    source: &mut dyn MapSource,
+    info: &Info,
+    limits: &CoverLimits,
+) -> Vec<ContributorIcon> {
+    info.custom_data
+        .contributors
+        .iter()
+        .filter(|contributor| contributor.has_icon())
+        .map(|contributor| {
+            let (icon, issues) = inspect_file(source, &contributor.icon_path, limits);
+            ContributorIcon {
+                contributor: contributor.clone(),
+                icon,
+                issues,
+            }
+        })
+        .collect()
+}
+
+fn inspect_file(
+    source: &mut dyn MapSource,
+    filename: &str,
+    limits: &CoverLimits,
+) -> (Option<Cover>, Vec<CoverIssue>) {
+    if !source.exists(filename) {
+        return (None, vec![CoverIssue::Missing]);
+    }
+    let cover = source
+        .read(filename)
+        .map_err(|error| error.to_string())
+        .and_then(|data| Cover::from_bytes(data).map_err(|error| error.to_string()));
+
+    match cover {
+        Ok(cover) => {
+            let issues = cover.issues(filename, limits);
+            (Some(cover), issues)
+        }
+        Err(error) => (None, vec![CoverIssue::Unreadable(error)]),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{inspect, inspect_icons, Cover, CoverIssue, CoverLimits, ImageFormat};
+    use crate::source::MemorySource;
+    use crate::test_util::test_info;
+    use std::io::Cursor;
+
+    /// Builds an image of the given size, encoded in the given format
+    pub fn image_file(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+        let image = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        });
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn inspect_cover() {
+        let info = test_info();
+        let mut source = MemorySource::new();
+
+        let (cover, issues) = inspect(&mut source, &info, &CoverLimits::default());
+        assert!(cover.is_none());
+        assert_eq!(issues, vec![CoverIssue::Missing]);
+
+        source.insert("cover.png", image_file(256, 256, image::ImageFormat::Png));
+        let (cover, issues) = inspect(&mut source, &info, &CoverLimits::default());
+        let cover = cover.unwrap();
+        assert_eq!(cover.format, ImageFormat::Png);
+        assert_eq!((cover.width, cover.height), (256, 256));
+        assert_eq!(cover.aspect_ratio(), 1.0);
+        assert!(issues.is_empty());
+
+        source.insert("cover.png", b"not an image".to_vec());
+        let (_, issues) = inspect(&mut source, &info, &CoverLimits::default());
+        assert!(matches!(issues[0], CoverIssue::Unreadable(_)));
+
+        // The header alone is valid, but the pixels are missing
+        let mut truncated = image_file(256, 256, image::ImageFormat::Png);
+        truncated.truncate(truncated.len() / 2);
+        source.insert("cover.png", truncated);
+        let (cover, issues) = inspect(&mut source, &info, &CoverLimits::default());
+        assert!(cover.is_none());
+        assert!(matches!(issues[0], CoverIssue::Unreadable(_)));
+    }
+
+    #[test]
+    fn inspect_contributor_icons() {
+        let mut info = test_info();
+        let mut source = MemorySource::new();
+        let mut contributor = info.custom_data.contributors[0].clone();
+        contributor.icon_path = String::new();
+        info.custom_data.contributors.push(contributor);
+
+        let icons = inspect_icons(&mut source, &info, &CoverLimits::default());
+        assert_eq!(icons.len(), 1);
+        assert_eq!(icons[0].contributor.name, "beatmap-parser");
+        assert_eq!(icons[0].issues, vec![CoverIssue::Missing]);
+
+        source.insert("icon.png", image_file(64, 64, image::ImageFormat::Jpeg));
+        let icons = inspect_icons(&mut source, &info, &CoverLimits::default());
+        assert_eq!(icons[0].icon.as_ref().unwrap().width, 64);
+        assert!(matches!(
+            icons[0].issues[0],
+            CoverIssue::ExtensionMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn cover_issues() {
+        let data = image_file(300, 200, image::ImageFormat::Jpeg);
+        let cover = Cover::from_bytes(data).unwrap();
+        let limits = CoverLimits { max_file_size: 16 };
+        let issues = cover.issues("cover.png", &limits);
+
+        assert_eq!(cover.format, ImageFormat::Jpeg);
+        assert_eq!(
+            issues[0],
+            CoverIssue::NotSquare {
+                width: 300,
+                height: 200
+            }
+        );
+        assert!(matches!(issues[1], CoverIssue::Oversize { .. }));
+        assert_eq!(
+            issues[2],
+            CoverIssue::ExtensionMismatch {
+                extension: "png".to_string(),
+                format: ImageFormat::Jpeg
+            }
+        );
+        assert!(cover.issues("cover.JPG", &CoverLimits::default()).len() == 1);
+    }
+
+    #[test]
+    fn thumbnail() {
+        let cover = Cover::from_bytes(image_file(300, 200, image::ImageFormat::Png)).unwrap();
+        let thumbnail = Cover::from_bytes(cover.thumbnail(64, ImageFormat::Jpeg).unwrap()).unwrap();
+
+        assert_eq!(thumbnail.format, ImageFormat::Jpeg);
+        assert_eq!((thumbnail.width, thumbnail.height), (64, 43));
+    }
+}
@@ -0,0 +1,476 @@
+use crate::audio::{invalid_data, read_packets, Codec, OggStream};
+use crate::difficulty::difficulty::note::NoteType;
+use crate::difficulty::Difficulty;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::io::Cursor;
+
+/// Number of samples analyzed by each spectral frame
+const FRAME_SIZE: usize = 1024;
+/// Number of samples between two spectral frames
+const HOP_SIZE: usize = 512;
+/// Number of frames on each side used to compute the onset threshold
+const THRESHOLD_WINDOW: usize = 10;
+/// Number of frames on each side an onset must be the maximum of
+const PEAK_WINDOW: usize = 3;
+/// Minimum time between two onsets, in seconds
+const MIN_ONSET_GAP: f64 = 0.05;
+/// Largest number of samples per channel of an Opus packet, 120ms at 48kHz
+const MAX_OPUS_FRAME: usize = 5760;
+
+/// Represents decoded audio, downmixed to mono
+#[derive(PartialEq, Clone, Debug)]
+pub struct DecodedAudio {
+    /// Samples, between -1 and 1
+    pub samples: Vec<f32>,
+    /// Sample rate, in Hz
+    pub sample_rate: u32,
+}
+
+/// Represents a bucket of a waveform envelope
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct WaveformBucket {
+    /// Lowest sample of the bucket
+    pub min: f32,
+    /// Highest sample of the bucket
+    pub max: f32,
+    /// Root mean square of the bucket
+    pub rms: f32,
+}
+
+/// Represents the spectral flux of a song, used to detect onsets
+#[derive(PartialEq, Clone, Debug)]
+pub struct OnsetEnvelope {
+    /// Spectral flux of each frame
+    pub values: Vec<f32>,
+    /// Number of frames per second
+    pub frame_rate: f64,
+    /// Time of the center of the first frame, in seconds
+    pub offset: f64,
+}
+
+/// Represents how well the notes of a difficulty follow the onsets of its song
+#[derive(PartialEq, Clone, Debug)]
+pub struct OnsetAlignment {
+    /// Number of distinct note times
+    pub notes: usize,
+    /// Number of distinct note times close enough to an onset
+    pub matched_notes: usize,
+    /// Mean time between matched notes and their onset, in seconds, positive when notes are late
+    pub mean_offset: f64,
+    /// Mean absolute time between matched notes and their onset, in seconds
+    pub mean_error: f64,
+}
+
+impl OnsetAlignment {
+    /// Returns the ratio of note times matching an onset
+    pub fn match_ratio(&self) -> f64 {
+        if self.notes == 0 {
+            0.0
+        } else {
+            self.matched_notes as f64 / self.notes as f64
+        }
+    }
+}
+
+impl DecodedAudio {
+    /// Decodes an OGG Vorbis, OGG Opus or WAV file
+    ///
+    /// The format is detected from the contents, other formats return an error. Only mono and
+    /// stereo Opus streams are supported.
+    pub fn from_bytes(data: &[u8]) -> Result<DecodedAudio, Box<dyn Error>> {
+        if data.starts_with(b"OggS") {
+            let stream = OggStream::parse(data)?;
+            match stream.codec {
+                Codec::Vorbis => DecodedAudio::from_vorbis(data, &stream),
+                Codec::Opus => DecodedAudio::from_opus(data, &stream),
+            }
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+            DecodedAudio::from_wav(data)
+        } else {
+            Err(invalid_data("Unsupported audio format"))
+        }
+    }
+
+    fn from_vorbis(data: &[u8], stream: &OggStream) -> Result<DecodedAudio, Box<dyn Error>> {
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(Cursor::new(data))?;
+        let channels = usize::from(reader.ident_hdr.audio_channels.max(1));
+        let sample_rate = reader.ident_hdr.audio_sample_rate;
+
+        let mut samples = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl()? {
+            samples.extend(downmix(
+                packet.iter().map(|&s| f32::from(s) / 32768.0),
+                channels,
+            ));
+        }
+
+        // The last packet is padded, the granule position of the last page gives the length
+        if let Some(end) = stream.last_granule_position(data) {
+            samples.truncate(end.max(0) as usize);
+        }
+        Ok(DecodedAudio {
+            samples,
+            sample_rate,
+        })
+    }
+
+    fn from_opus(data: &[u8], stream: &OggStream) -> Result<DecodedAudio, Box<dyn Error>> {
+        let channels = match stream.channels {
+            1 => ropus::Channels::Mono,
+            2 => ropus::Channels::Stereo,
+            _ => return Err(invalid_data("Multichannel Opus streams are not supported")),
+        };
+        let mut decoder = ropus::Decoder::new(stream.sample_rate, channels)?;
+
+        // The first two packets are the identification and comment headers
+        let mut samples = Vec::new();
+        let mut buffer = vec![0.0; MAX_OPUS_FRAME * channels.count()];
+        for packet in read_packets(data, stream.serial, usize::MAX).iter().skip(2) {
+            let length = decoder.decode_float(packet, &mut buffer, ropus::DecodeMode::Normal)?;
+            samples.extend(downmix(
+                buffer[..length * channels.count()].iter().cloned(),
+                channels.count(),
+            ));
+        }
+
+        // The granule position of the last page gives the length once the pre-skip is removed
+        let pre_skip = usize::from(stream.pre_skip).min(samples.len());
+        samples.drain(..pre_skip);
+        if let Some(end) = stream.last_granule_position(data) {
+            let length = (end - i64::from(stream.pre_skip)).max(0) as usize;
+            samples.truncate(length);
+        }
+        Ok(DecodedAudio {
+            samples,
+            sample_rate: stream.sample_rate,
+        })
+    }
+
+    fn from_wav(data: &[u8]) -> Result<DecodedAudio, Box<dyn Error>> {
+        let mut reader = hound::WavReader::new(Cursor::new(data))?;
+        let spec = reader.spec();
+        let channels = usize::from(spec.channels.max(1));
+
+        let interleaved: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|s| s as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        Ok(DecodedAudio {
+            samples: downmix(interleaved.into_iter(), channels).collect(),
+            sample_rate: spec.sample_rate,
+        })
+    }
+
+    /// Returns the duration of the audio, in seconds
+    pub fn duration(&self) -> f64 {
+        self.samples.len() as f64 / f64::from(self.sample_rate)
+    }
+
+    /// Returns a waveform envelope made of the given number of buckets
+    pub fn waveform(&self, buckets: usize) -> Vec<WaveformBucket> {
+        if buckets == 0 || self.samples.is_empty() {
+            return Vec::new();
+        }
+        let bucket_size = self.samples.len() as f64 / buckets as f64;
+
+        (0..buckets)
+            .map(|i| {
+                let start = (i as f64 * bucket_size) as usize;
+                let end = (((i + 1) as f64 * bucket_size) as usize)
+                    .max(start + 1)
+                    .min(self.samples.len());
+                let bucket = &self.samples[start.min(end - 1)..end];
+
+                let (min, max, sum) = bucket
+                    .iter()
+                    .fold((f32::MAX, f32::MIN, 0.0), |(min, max, sum), &s| {
+                        (min.min(s), max.max(s), sum + s * s)
+                    });
+                WaveformBucket {
+                    min,
+                    max,
+                    rms: (sum / bucket.len() as f32).sqrt(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the spectral flux envelope of the audio
+    pub fn onset_envelope(&self) -> OnsetEnvelope {
+        let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+        let window: Vec<f32> = (0..FRAME_SIZE)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / FRAME_SIZE as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        let mut values = Vec::new();
+        let mut previous = vec![0.0; FRAME_SIZE / 2];
+        let mut buffer = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+        let mut start = 0;
+        while start + FRAME_SIZE <= self.samples.len() {
+            for (i, value) in buffer.iter_mut().enumerate() {
+                *value = Complex::new(self.samples[start + i] * window[i], 0.0);
+            }
+            fft.process(&mut buffer);
+
+            let mut flux = 0.0;
+            for (bin, previous) in buffer.iter().zip(previous.iter_mut()) {
+                let magnitude = (1.0 + 10.0 * bin.norm()).ln();
+                flux += (magnitude - *previous).max(0.0);
+                *previous = magnitude;
+            }
+            values.push(flux);
+            start += HOP_SIZE;
+        }
+
+        let sample_rate = f64::from(self.sample_rate);
+        OnsetEnvelope {
+            values,
+            frame_rate: sample_rate / HOP_SIZE as f64,
+            offset: FRAME_SIZE as f64 / 2.0 / sample_rate,
+        }
+    }
+
+    /// Returns the times of the detected onsets, in seconds
+    pub fn onsets(&self) -> Vec<f64> {
+        self.onset_envelope().peaks()
+    }
+}
+
+impl OnsetEnvelope {
+    /// Returns the time of the center of the given frame, in seconds
+    pub fn frame_time(&self, frame: usize) -> f64 {
+        self.offset + frame as f64 / self.frame_rate
+    }
+
+    /// Returns the times of the peaks standing out of their neighbourhood, in seconds
+    pub fn peaks(&self) -> Vec<f64> {
+        let values = &self.values;
+        let max = values.iter().cloned().fold(0.0, f32::max);
+        let delta = max * 0.1;
+
+        let mut peaks: Vec<f64> = Vec::new();
+        for (i, &value) in values.iter().enumerate() {
+            let around = |window: usize| {
+                &values[i.saturating_sub(window)..(i + window + 1).min(values.len())]
+            };
+            let neighbourhood = around(THRESHOLD_WINDOW);
+            let threshold = neighbourhood.iter().sum::<f32>() / neighbourhood.len() as f32 + delta;
+            let is_peak = around(PEAK_WINDOW).iter().all(|&other| other <= value);
+
+            let time = self.frame_time(i);
+            if value > threshold
+                && is_peak
+                && peaks
+                    .last()
+                    .is_none_or(|&last| time - last >= MIN_ONSET_GAP)
+            {
+                peaks.push(time);
+            }
+        }
+        peaks
+    }
+}
+
+/// Measures how well the notes of a difficulty follow the given onsets
+///
+/// A note matches an onset when they are less than `tolerance` seconds apart. Notes hit at the
+/// same time count once, and bombs are ignored.
+pub fn align(onsets: &[f64], difficulty: &Difficulty, bpm: f64, tolerance: f64) -> OnsetAlignment {
+    let mut onsets = onsets.to_vec();
+    onsets.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    let mut times: Vec<f64> = difficulty
+        .notes
+        .iter()
+        .filter(|note| note.note_type != NoteType::Bomb)
+        .map(|note| difficulty.beat_to_seconds(note.time, bpm))
+        .collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    times.dedup_by(|a, b| (*a - *b).abs() < 1e-3);
+
+    let offsets: Vec<f64> = times
+        .iter()
+        .filter_map(|&time| {
+            let index = onsets.partition_point(|&onset| onset < time);
+            let before = index.checked_sub(1).map(|i| time - onsets[i]);
+            let after = onsets.get(index).map(|&onset| time - onset);
+            let nearest = match (before, after) {
+                (Some(before), Some(after)) if after.abs() < before.abs() => after,
+                (Some(before), _) => before,
+                (None, after) => after?,
+            };
+            if nearest.abs() <= tolerance {
+                Some(nearest)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let matched_notes = offsets.len();
+    let mean = |values: &mut dyn Iterator<Item = f64>| {
+        if matched_notes == 0 {
+            0.0
+        } else {
+            values.sum::<f64>() / matched_notes as f64
+        }
+    };
+    OnsetAlignment {
+        notes: times.len(),
+        matched_notes,
+        mean_offset: mean(&mut offsets.iter().cloned()),
+        mean_error: mean(&mut offsets.iter().map(|offset| offset.abs())),
+    }
+}
+
+/// Averages interleaved samples into a single channel
+fn downmix(samples: impl Iterator<Item = f32>, channels: usize) -> impl Iterator<Item = f32> {
+    let samples: Vec<f32> = samples.collect();
+    (0..samples.len() / channels).map(move |i| {
+        samples[i * channels..(i + 1) * channels]
+            .iter()
+            .sum::<f32>()
+            / channels as f32
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{align, DecodedAudio};
+    use crate::audio::tests::{ogg_page, opus_file, opus_headers};
+    use crate::test_util::{test_difficulty, test_dir};
+    use std::io::Cursor;
+
+    /// Builds a stereo 16-bit WAV file with a short percussive burst at each of the given times
+    pub fn clicks_wav(sample_rate: u32, duration: f64, clicks: &[f64]) -> Vec<u8> {
+        let length = (duration * f64::from(sample_rate)) as usize;
+        let mut samples = vec![0.0f32; length];
+        for &click in clicks {
+            let start = (click * f64::from(sample_rate)) as usize;
+            for i in 0..2000.min(length.saturating_sub(start)) {
+                let t = i as f32 / sample_rate as f32;
+                samples[start + i] +=
+                    (2.0 * std::f32::consts::PI * 1000.0 * t).sin() * (-t * 60.0).exp() * 0.8;
+            }
+        }
+
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut data, spec).unwrap();
+        for sample in samples {
+            let sample = (sample * 32767.0) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn decode_wav() {
+        let audio = DecodedAudio::from_bytes(&clicks_wav(22050, 2.0, &[0.5])).unwrap();
+
+        assert_eq!(audio.sample_rate, 22050);
+        assert_eq!(audio.duration(), 2.0);
+
+        let waveform = audio.waveform(4);
+        assert_eq!(waveform.len(), 4);
+        assert_eq!(waveform[0].max, 0.0);
+        assert!(waveform[1].max > 0.5);
+        assert!(waveform[1].rms > 0.0);
+    }
+
+    #[test]
+    fn decode_vorbis() {
+        // Clicks at 0.5, 1 and 1.5 seconds, encoded with `preview::encode_ogg`
+        let data = std::fs::read(test_dir().join("clicks.ogg")).unwrap();
+        let audio = DecodedAudio::from_bytes(&data).unwrap();
+
+        assert_eq!(audio.sample_rate, 22050);
+        assert_eq!(audio.duration(), 2.0);
+        let onsets = audio.onsets();
+        assert_eq!(onsets.len(), 3);
+        for (onset, click) in onsets.iter().zip([0.5, 1.0, 1.5].iter()) {
+            assert!((onset - click).abs() < 0.05, "{} != {}", onset, click);
+        }
+    }
+
+    #[test]
+    fn decode_opus() {
+        let clicks = [0.5, 1.0, 1.5];
+        let wav = DecodedAudio::from_bytes(&clicks_wav(48000, 2.0, &clicks)).unwrap();
+        let mut encoder =
+            ropus::Encoder::builder(48000, ropus::Channels::Mono, ropus::Application::Audio)
+                .build()
+                .unwrap();
+        let pre_skip = encoder.lookahead() as u16;
+
+        // One 20ms packet per page, padded with silence to flush the encoder
+        let mut data = opus_headers(1, pre_skip);
+        let mut samples = wav.samples.clone();
+        samples.resize(samples.len() + 2 * 960, 0.0);
+        let mut packet = vec![0; 4000];
+        for (i, frame) in samples.chunks_exact(960).enumerate() {
+            let length = encoder.encode_float(frame, &mut packet).unwrap();
+            let granule_position = ((i + 1) * 960) as i64;
+            let end = granule_position.min(wav.samples.len() as i64) + i64::from(pre_skip);
+            data.extend(ogg_page(0, end, 2 + i as u32, &packet[..length]));
+        }
+        let audio = DecodedAudio::from_bytes(&data).unwrap();
+
+        assert_eq!(audio.sample_rate, 48000);
+        assert_eq!(audio.duration(), 2.0);
+        let onsets = audio.onsets();
+        assert_eq!(onsets.len(), clicks.len());
+        for (onset, click) in onsets.iter().zip(clicks.iter()) {
+            assert!((onset - click).abs() < 0.05, "{} != {}", onset, click);
+        }
+    }
+
+    #[test]
+    fn decode_unsupported() {
+        assert!(DecodedAudio::from_bytes(&opus_file(6, 312, 48000)).is_err());
+        assert!(DecodedAudio::from_bytes(b"ID3\x04").is_err());
+    }
+
+    #[test]
+    fn onsets() {
+        let clicks = [0.5, 1.0, 1.5, 2.25, 3.0];
+        let audio = DecodedAudio::from_bytes(&clicks_wav(22050, 4.0, &clicks)).unwrap();
+        let onsets = audio.onsets();
+
+        assert_eq!(onsets.len(), clicks.len());
+        for (onset, click) in onsets.iter().zip(clicks.iter()) {
+            assert!((onset - click).abs() < 0.05, "{} != {}", onset, click);
+        }
+    }
+
+    #[test]
+    fn alignment() {
+        let difficulty = test_difficulty();
+
+        // Notes at beats 1, 2, 4 and 4.5 at 120 BPM, the bomb at beat 3 is ignored
+        let alignment = align(&[0.51, 1.0, 2.0, 3.0], &difficulty, 120.0, 0.05);
+        assert_eq!(alignment.notes, 4);
+        assert_eq!(alignment.matched_notes, 3);
+        assert!((alignment.mean_offset + 0.01 / 3.0).abs() < 1e-9);
+        assert_eq!(alignment.match_ratio(), 0.75);
+    }
+}
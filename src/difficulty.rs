@@ -1,69 +1,427 @@
 extern crate semver;
 extern crate serde;
+extern crate serde_json;
 extern crate serde_repr;
 
+use crate::lighting::{self, LightingOptions};
+use difficulty::note::{CutDirection, NoteType, SaberColor};
 use semver::Version;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cmp::Ordering;
+
+/// Notes of the other color closer than this to a kept note, in beats, are dropped by
+/// `Difficulty::one_saber`
+const ONE_SABER_GAP: f64 = 0.25;
+/// Notes of a color closer than this to the previous one, in beats, are collapsed by
+/// `Difficulty::no_arrows`
+const NO_ARROWS_GAP: f64 = 0.125;
 
 /// Contains custom types used by Difficulty
 pub mod difficulty {
     use super::{Deserialize, Serialize};
-    use serde_repr::*;
+    use crate::parse;
+    use serde::{Deserializer, Serializer};
+    use serde_json::Value;
 
     /// Represents a BPM change
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
     pub struct BPMChange {
         /// New BPM
-        #[serde(rename = "_BPM")]
+        #[serde(rename = "_BPM", deserialize_with = "bpm")]
         pub bpm: f64,
         /// Time of the BPM change, in beats
         #[serde(rename = "_time")]
         pub time: f64,
-        #[serde(rename = "_beatsPerBar")]
+        #[serde(
+            rename = "_beatsPerBar",
+            default = "default_beats_per_bar",
+            deserialize_with = "beats_per_bar"
+        )]
         pub beats_per_bar: u32,
-        #[serde(rename = "_metronomeOffset")]
+        #[serde(
+            rename = "_metronomeOffset",
+            default = "default_metronome_offset",
+            deserialize_with = "metronome_offset"
+        )]
         pub metronome_offset: u32,
     }
 
+    fn bpm<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        parse::float(deserializer, "_BPM")
+    }
+
+    fn beats_per_bar<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        parse::integer(deserializer, "_beatsPerBar")
+    }
+
+    fn metronome_offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        parse::integer(deserializer, "_metronomeOffset")
+    }
+
+    /// Beats per bar used by the editors when absent
+    fn default_beats_per_bar() -> u32 {
+        4
+    }
+
+    /// Metronome offset used by the editors when absent
+    fn default_metronome_offset() -> u32 {
+        4
+    }
+
+    /// Contains custom types used by difficulty::Event
+    pub mod event {
+        use serde_repr::*;
+
+        /// Represents a known event type
+        #[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy, Hash, Debug)]
+        #[repr(u8)]
+        pub enum EventType {
+            BackLasers = 0,
+            RingLights = 1,
+            LeftLasers = 2,
+            RightLasers = 3,
+            CenterLights = 4,
+            BoostColors = 5,
+            RingSpin = 8,
+            RingZoom = 9,
+            LeftLasersSpeed = 12,
+            RightLasersSpeed = 13,
+            EarlyRotation = 14,
+            LateRotation = 15,
+        }
+
+        impl EventType {
+            /// Returns an event type from its raw value
+            pub fn from_raw(value: u8) -> Option<EventType> {
+                match value {
+                    0 => Some(EventType::BackLasers),
+                    1 => Some(EventType::RingLights),
+                    2 => Some(EventType::LeftLasers),
+                    3 => Some(EventType::RightLasers),
+                    4 => Some(EventType::CenterLights),
+                    5 => Some(EventType::BoostColors),
+                    8 => Some(EventType::RingSpin),
+                    9 => Some(EventType::RingZoom),
+                    12 => Some(EventType::LeftLasersSpeed),
+                    13 => Some(EventType::RightLasersSpeed),
+                    14 => Some(EventType::EarlyRotation),
+                    15 => Some(EventType::LateRotation),
+                    _ => None,
+                }
+            }
+
+            /// Returns whether the event type controls a group of lights
+            pub fn is_light(self) -> bool {
+                (self as u8) <= 4
+            }
+        }
+
+        /// Represents the value of a light event
+        #[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy, Hash, Debug)]
+        #[repr(u32)]
+        pub enum LightValue {
+            Off = 0,
+            BlueOn = 1,
+            BlueFlash = 2,
+            BlueFade = 3,
+            RedOn = 5,
+            RedFlash = 6,
+            RedFade = 7,
+        }
+    }
+
     /// Represents a lighting event and other various events
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
     pub struct Event {
         /// Time of the event, in beats
         #[serde(rename = "_time")]
         pub time: f64,
         /// Type of the event
-        #[serde(rename = "_type")]
+        #[serde(rename = "_type", deserialize_with = "event_type")]
         pub event_type: u8,
         /// Value of the event
-        #[serde(rename = "_value")]
+        #[serde(rename = "_value", deserialize_with = "event_value")]
         pub value: u32,
+        /// Custom data used by mods such as Chroma and Noodle Extensions
+        #[serde(
+            rename = "_customData",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub custom_data: Option<Value>,
+    }
+
+    pub(crate) fn event_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        parse::integer(deserializer, "_type")
+    }
+
+    pub(crate) fn event_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        parse::integer(deserializer, "_value")
+    }
+
+    impl Event {
+        /// Event type rotating the lanes before the event time
+        pub const EARLY_ROTATION: u8 = event::EventType::EarlyRotation as u8;
+        /// Event type rotating the lanes after the event time
+        pub const LATE_ROTATION: u8 = event::EventType::LateRotation as u8;
+
+        /// Returns a new event of the given type
+        pub fn new(time: f64, event_type: event::EventType, value: u32) -> Event {
+            Event {
+                time,
+                event_type: event_type as u8,
+                value,
+                custom_data: None,
+            }
+        }
+
+        /// Returns a new light event of the given type
+        pub fn light(time: f64, event_type: event::EventType, value: event::LightValue) -> Event {
+            Event::new(time, event_type, value as u32)
+        }
+
+        /// Returns the type of the event if it is known
+        pub fn typed(&self) -> Option<event::EventType> {
+            event::EventType::from_raw(self.event_type)
+        }
+
+        /// Returns whether the event is a lane rotation event
+        pub fn is_rotation(&self) -> bool {
+            self.event_type == Event::EARLY_ROTATION || self.event_type == Event::LATE_ROTATION
+        }
+
+        /// Returns a horizontally mirrored copy of the event
+        ///
+        /// Only rotation events are affected, their direction is inverted.
+        pub fn mirrored(&self) -> Event {
+            let mut event = self.clone();
+            if self.is_rotation() {
+                event.value = match self.value {
+                    // Standard values, -60° to 60° without 0°
+                    0..=7 => 7 - self.value,
+                    // Mapping Extensions precise values, 1360 being 0°
+                    1000..=1720 => 2720 - self.value,
+                    _ => self.value,
+                };
+                if let Some(custom_data) = &mut event.custom_data {
+                    negate_number(custom_data, "_rotation");
+                }
+            }
+            event
+        }
     }
 
     /// Represents an horizontal line index
-    #[derive(Serialize_repr, Deserialize_repr, PartialEq, PartialOrd, Eq, Ord, Debug)]
-    #[repr(u8)]
+    ///
+    /// Mapping Extensions precise values (`>= 1000` or `<= -1000`) are kept as is.
+    #[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Hash, Debug)]
     pub enum LineIndex {
-        FarLeft = 0,
-        MidLeft = 1,
-        MidRight = 2,
-        FarRight = 3,
+        FarLeft,
+        MidLeft,
+        MidRight,
+        FarRight,
+        Precise(i32),
+    }
+
+    impl LineIndex {
+        /// Returns the raw value of the line index
+        pub fn to_raw(self) -> i32 {
+            match self {
+                LineIndex::FarLeft => 0,
+                LineIndex::MidLeft => 1,
+                LineIndex::MidRight => 2,
+                LineIndex::FarRight => 3,
+                LineIndex::Precise(value) => value,
+            }
+        }
+
+        /// Returns a line index from its raw value
+        pub fn from_raw(value: i32) -> Option<LineIndex> {
+            match value {
+                0 => Some(LineIndex::FarLeft),
+                1 => Some(LineIndex::MidLeft),
+                2 => Some(LineIndex::MidRight),
+                3 => Some(LineIndex::FarRight),
+                _ if value >= 1000 || value <= -1000 => Some(LineIndex::Precise(value)),
+                _ => None,
+            }
+        }
+
+        /// Returns the position of the left edge of the line, in lanes from the far left
+        pub fn position(self) -> f64 {
+            precise_to_position(self.to_raw())
+        }
+
+        /// Returns the line index whose left edge is at the given position
+        ///
+        /// Positions which aren't on a standard lane are encoded as Mapping Extensions precise values.
+        pub fn from_position(position: f64) -> LineIndex {
+            LineIndex::from_raw(position_to_precise(position)).unwrap_or(LineIndex::FarLeft)
+        }
+
+        /// Returns the line index of an object of the given width once horizontally mirrored
+        pub fn mirrored(self, width: f64) -> LineIndex {
+            match self {
+                LineIndex::Precise(_) => LineIndex::from_position(4.0 - self.position() - width),
+                _ => LineIndex::from_raw(4 - self.to_raw() - width as i32)
+                    .unwrap_or(LineIndex::from_position(4.0 - self.position() - width)),
+            }
+        }
+    }
+
+    impl Serialize for LineIndex {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_i32(self.to_raw())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for LineIndex {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LineIndex, D::Error> {
+            let value = parse::integer(deserializer, "_lineIndex")?;
+            LineIndex::from_raw(value)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid line index {}", value)))
+        }
+    }
+
+    /// Returns the position in lanes of a raw line index or layer
+    fn precise_to_position(value: i32) -> f64 {
+        if value >= 1000 {
+            f64::from(value - 1000) / 1000.0
+        } else if value <= -1000 {
+            f64::from(value + 1000) / 1000.0
+        } else {
+            f64::from(value)
+        }
+    }
+
+    /// Returns the raw line index or layer of a position in lanes
+    fn position_to_precise(position: f64) -> i32 {
+        let rounded = position.round();
+        if (position - rounded).abs() < 1e-6 && (0.0..=3.0).contains(&rounded) {
+            rounded as i32
+        } else if position >= 0.0 {
+            (position * 1000.0).round() as i32 + 1000
+        } else {
+            (position * 1000.0).round() as i32 - 1000
+        }
+    }
+
+    /// Negates a number, or the Y and Z components of an array, stored in a custom data object
+    fn negate_number(custom_data: &mut Value, key: &str) {
+        match custom_data.get_mut(key) {
+            Some(Value::Array(values)) => {
+                for value in values.iter_mut().skip(1) {
+                    if let Some(number) = value.as_f64() {
+                        *value = Value::from(-number);
+                    }
+                }
+            }
+            Some(value) => {
+                if let Some(number) = value.as_f64() {
+                    *value = Value::from(-number);
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Applies a function to a component of an array stored in a custom data object
+    fn map_component(custom_data: &mut Value, key: &str, index: usize, f: impl Fn(f64) -> f64) {
+        if let Some(value) = custom_data
+            .get_mut(key)
+            .and_then(|array| array.get_mut(index))
+        {
+            if let Some(number) = value.as_f64() {
+                *value = Value::from(f(number));
+            }
+        }
+    }
+
+    /// Applies a function to a number stored in a custom data object
+    fn map_number(custom_data: &mut Value, key: &str, f: impl Fn(f64) -> f64) {
+        if let Some(value) = custom_data.get_mut(key) {
+            if let Some(number) = value.as_f64() {
+                *value = Value::from(f(number));
+            }
+        }
     }
 
     /// Contains custom types used by difficulty::Note
     pub mod note {
+        use super::{position_to_precise, precise_to_position};
+        use crate::parse;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
         use serde_repr::*;
 
         /// Represents a vertical line layer
-        #[derive(Serialize_repr, Deserialize_repr, PartialEq, PartialOrd, Eq, Ord, Debug)]
-        #[repr(u8)]
+        ///
+        /// Mapping Extensions precise values (`>= 1000` or `<= -1000`) are kept as is.
+        #[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Hash, Debug)]
         pub enum LineLayer {
-            Bottom = 0,
-            Middle = 1,
-            Top = 2,
+            Bottom,
+            Middle,
+            Top,
+            Precise(i32),
+        }
+
+        impl LineLayer {
+            /// Returns the raw value of the line layer
+            pub fn to_raw(self) -> i32 {
+                match self {
+                    LineLayer::Bottom => 0,
+                    LineLayer::Middle => 1,
+                    LineLayer::Top => 2,
+                    LineLayer::Precise(value) => value,
+                }
+            }
+
+            /// Returns a line layer from its raw value
+            pub fn from_raw(value: i32) -> Option<LineLayer> {
+                match value {
+                    0 => Some(LineLayer::Bottom),
+                    1 => Some(LineLayer::Middle),
+                    2 => Some(LineLayer::Top),
+                    _ if value >= 1000 || value <= -1000 => Some(LineLayer::Precise(value)),
+                    _ => None,
+                }
+            }
+
+            /// Returns the position of the bottom edge of the layer, in layers from the bottom
+            pub fn position(self) -> f64 {
+                precise_to_position(self.to_raw())
+            }
+
+            /// Returns the line layer once vertically mirrored
+            pub fn mirrored(self) -> LineLayer {
+                match self {
+                    LineLayer::Bottom => LineLayer::Top,
+                    LineLayer::Middle => LineLayer::Middle,
+                    LineLayer::Top => LineLayer::Bottom,
+                    LineLayer::Precise(_) => {
+                        let raw = position_to_precise(2.0 - self.position());
+                        LineLayer::from_raw(raw).unwrap_or(LineLayer::Bottom)
+                    }
+                }
+            }
+        }
+
+        impl Serialize for LineLayer {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_i32(self.to_raw())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for LineLayer {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LineLayer, D::Error> {
+                let value = parse::integer(deserializer, "_lineLayer")?;
+                LineLayer::from_raw(value).ok_or_else(|| {
+                    serde::de::Error::custom(format!("invalid line layer {}", value))
+                })
+            }
         }
 
         /// Represents a note type
-        #[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Debug)]
+        #[derive(Serialize_repr, PartialEq, Eq, Clone, Copy, Hash, Debug)]
         #[repr(u8)]
         pub enum NoteType {
             Red = 0,
@@ -71,24 +429,153 @@ pub mod difficulty {
             Bomb = 3,
         }
 
+        /// Represents the color of a saber
+        #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+        pub enum SaberColor {
+            Red,
+            Blue,
+        }
+
+        impl SaberColor {
+            /// Returns the type of the notes cut by the saber
+            pub fn note_type(self) -> NoteType {
+                match self {
+                    SaberColor::Red => NoteType::Red,
+                    SaberColor::Blue => NoteType::Blue,
+                }
+            }
+        }
+
+        impl NoteType {
+            /// Returns the note type with Red and Blue swapped
+            pub fn swapped(self) -> NoteType {
+                match self {
+                    NoteType::Red => NoteType::Blue,
+                    NoteType::Blue => NoteType::Red,
+                    NoteType::Bomb => NoteType::Bomb,
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for NoteType {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<NoteType, D::Error> {
+                match parse::integer(deserializer, "_type")? {
+                    0 => Ok(NoteType::Red),
+                    1 => Ok(NoteType::Blue),
+                    3 => Ok(NoteType::Bomb),
+                    value => Err(serde::de::Error::custom(format!(
+                        "invalid note type {}",
+                        value
+                    ))),
+                }
+            }
+        }
+
         /// Represents a note cut direction
-        #[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Debug)]
-        #[repr(u8)]
+        ///
+        /// Mapping Extensions precise values (`1000` to `1360`, counter-clockwise degrees from down)
+        /// are kept as is.
+        #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
         pub enum CutDirection {
-            Up = 0,
-            Down = 1,
-            Left = 2,
-            Right = 3,
-            UpLeft = 4,
-            UpRight = 5,
-            DownLeft = 6,
-            DownRight = 7,
-            Dot = 8,
+            Up,
+            Down,
+            Left,
+            Right,
+            UpLeft,
+            UpRight,
+            DownLeft,
+            DownRight,
+            Dot,
+            Precise(u16),
+        }
+
+        impl CutDirection {
+            /// Returns the raw value of the cut direction
+            pub fn to_raw(self) -> u16 {
+                match self {
+                    CutDirection::Up => 0,
+                    CutDirection::Down => 1,
+                    CutDirection::Left => 2,
+                    CutDirection::Right => 3,
+                    CutDirection::UpLeft => 4,
+                    CutDirection::UpRight => 5,
+                    CutDirection::DownLeft => 6,
+                    CutDirection::DownRight => 7,
+                    CutDirection::Dot => 8,
+                    CutDirection::Precise(value) => value,
+                }
+            }
+
+            /// Returns a cut direction from its raw value
+            pub fn from_raw(value: u16) -> Option<CutDirection> {
+                match value {
+                    0 => Some(CutDirection::Up),
+                    1 => Some(CutDirection::Down),
+                    2 => Some(CutDirection::Left),
+                    3 => Some(CutDirection::Right),
+                    4 => Some(CutDirection::UpLeft),
+                    5 => Some(CutDirection::UpRight),
+                    6 => Some(CutDirection::DownLeft),
+                    7 => Some(CutDirection::DownRight),
+                    8 => Some(CutDirection::Dot),
+                    1000..=1360 => Some(CutDirection::Precise(value)),
+                    _ => None,
+                }
+            }
+
+            /// Returns the cut direction once horizontally mirrored
+            pub fn mirrored(self) -> CutDirection {
+                match self {
+                    CutDirection::Left => CutDirection::Right,
+                    CutDirection::Right => CutDirection::Left,
+                    CutDirection::UpLeft => CutDirection::UpRight,
+                    CutDirection::UpRight => CutDirection::UpLeft,
+                    CutDirection::DownLeft => CutDirection::DownRight,
+                    CutDirection::DownRight => CutDirection::DownLeft,
+                    CutDirection::Precise(value) => {
+                        CutDirection::Precise(1000 + (360 - (value - 1000)) % 360)
+                    }
+                    _ => self,
+                }
+            }
+
+            /// Returns the cut direction once vertically mirrored
+            pub fn vertically_mirrored(self) -> CutDirection {
+                match self {
+                    CutDirection::Up => CutDirection::Down,
+                    CutDirection::Down => CutDirection::Up,
+                    CutDirection::UpLeft => CutDirection::DownLeft,
+                    CutDirection::UpRight => CutDirection::DownRight,
+                    CutDirection::DownLeft => CutDirection::UpLeft,
+                    CutDirection::DownRight => CutDirection::UpRight,
+                    CutDirection::Precise(value) => {
+                        CutDirection::Precise(1000 + (540 - (value - 1000)) % 360)
+                    }
+                    _ => self,
+                }
+            }
+        }
+
+        impl Serialize for CutDirection {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_u16(self.to_raw())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for CutDirection {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<CutDirection, D::Error> {
+                let value = parse::integer(deserializer, "_cutDirection")?;
+                CutDirection::from_raw(value).ok_or_else(|| {
+                    serde::de::Error::custom(format!("invalid cut direction {}", value))
+                })
+            }
         }
     }
 
     /// Represents a note
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
     pub struct Note {
         /// Time of the note, in beats
         #[serde(rename = "_time")]
@@ -105,22 +592,82 @@ pub mod difficulty {
         /// Cut direction of the note
         #[serde(rename = "_cutDirection")]
         pub cut_direction: note::CutDirection,
+        /// Custom data used by mods such as Chroma and Noodle Extensions
+        #[serde(
+            rename = "_customData",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub custom_data: Option<Value>,
+    }
+
+    impl Note {
+        /// Returns a horizontally mirrored copy of the note, with its color swapped
+        pub fn mirrored(&self) -> Note {
+            let mut note = self.color_swapped();
+            note.line_index = self.line_index.mirrored(1.0);
+            note.cut_direction = self.cut_direction.mirrored();
+            if let Some(custom_data) = &mut note.custom_data {
+                map_component(custom_data, "_position", 0, |x| -x - 1.0);
+                map_component(custom_data, "_flip", 0, |x| -x - 1.0);
+                map_number(custom_data, "_cutDirection", |angle| -angle);
+                negate_number(custom_data, "_rotation");
+                negate_number(custom_data, "_localRotation");
+            }
+            note
+        }
+
+        /// Returns a vertically mirrored copy of the note
+        pub fn vertically_mirrored(&self) -> Note {
+            let mut note = self.clone();
+            note.line_layer = self.line_layer.mirrored();
+            note.cut_direction = self.cut_direction.vertically_mirrored();
+            if let Some(custom_data) = &mut note.custom_data {
+                map_component(custom_data, "_position", 1, |y| 2.0 - y);
+                map_number(custom_data, "_cutDirection", |angle| 180.0 - angle);
+            }
+            note
+        }
+
+        /// Returns a copy of the note with its color swapped
+        pub fn color_swapped(&self) -> Note {
+            let mut note = self.clone();
+            note.note_type = self.note_type.swapped();
+            note
+        }
     }
 
     /// Contains custom types used by difficulty::Obstacle
     pub mod obstacle {
+        use crate::parse;
+        use serde::{Deserialize, Deserializer};
         use serde_repr::*;
 
-        #[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Debug)]
+        #[derive(Serialize_repr, PartialEq, Eq, Clone, Copy, Hash, Debug)]
         #[repr(u8)]
         pub enum ObstacleType {
             Wall = 0,
             Ceiling = 1,
         }
+
+        impl<'de> Deserialize<'de> for ObstacleType {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<ObstacleType, D::Error> {
+                match parse::integer(deserializer, "_type")? {
+                    0 => Ok(ObstacleType::Wall),
+                    1 => Ok(ObstacleType::Ceiling),
+                    value => Err(serde::de::Error::custom(format!(
+                        "invalid obstacle type {}",
+                        value
+                    ))),
+                }
+            }
+        }
     }
 
     /// Represents an obstacle
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
     pub struct Obstacle {
         /// Time of the obstacle in beats
         #[serde(rename = "_time")]
@@ -135,38 +682,670 @@ pub mod difficulty {
         #[serde(rename = "_duration")]
         pub duration: f64,
         /// Width of the obstacle in lines, extending to the right
-        #[serde(rename = "_width")]
-        pub width: u8,
+        ///
+        /// Some editors produce negative widths, which are kept as is.
+        #[serde(rename = "_width", deserialize_with = "obstacle_width")]
+        pub width: i32,
+        /// Custom data used by mods such as Chroma and Noodle Extensions
+        #[serde(
+            rename = "_customData",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub custom_data: Option<Value>,
+    }
+
+    pub(crate) fn obstacle_width<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<i32, D::Error> {
+        parse::integer(deserializer, "_width")
+    }
+
+    impl Obstacle {
+        /// Returns a horizontally mirrored copy of the obstacle
+        ///
+        /// The line index is moved so the obstacle covers the mirrored lanes.
+        pub fn mirrored(&self) -> Obstacle {
+            let mut obstacle = self.clone();
+            obstacle.line_index = self.line_index.mirrored(f64::from(self.width));
+            if let Some(custom_data) = &mut obstacle.custom_data {
+                let width = custom_data
+                    .get("_scale")
+                    .and_then(|scale| scale.get(0))
+                    .and_then(Value::as_f64)
+                    .unwrap_or_else(|| f64::from(self.width));
+                map_component(custom_data, "_position", 0, |x| -x - width);
+                negate_number(custom_data, "_rotation");
+                negate_number(custom_data, "_localRotation");
+            }
+            obstacle
+        }
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    /// Represents an editor bookmark
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
     pub struct Bookmark {
+        /// Time of the bookmark, in beats
         #[serde(rename = "_time")]
-        time: f64,
-        #[serde(rename = "_name")]
-        name: String,
+        pub time: f64,
+        /// Name of the bookmark
+        #[serde(rename = "_name", default)]
+        pub name: String,
     }
 }
 
+fn bpm_changes<'de, D>(deserializer: D) -> Result<Vec<difficulty::BPMChange>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    crate::parse::list(deserializer, "_BPMChanges")
+}
+
+fn events<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<difficulty::Event>, D::Error> {
+    crate::parse::list(deserializer, "_events")
+}
+
+fn notes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<difficulty::Note>, D::Error> {
+    crate::parse::list(deserializer, "_notes")
+}
+
+fn obstacles<'de, D>(deserializer: D) -> Result<Vec<difficulty::Obstacle>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    crate::parse::list(deserializer, "_obstacles")
+}
+
+fn bookmarks<'de, D>(deserializer: D) -> Result<Vec<difficulty::Bookmark>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    crate::parse::list(deserializer, "_bookmarks")
+}
+
 /// Represents a difficulty file
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Difficulty {
     /// Format version
     #[serde(rename = "_version")]
     pub version: Version,
     /// BPM changes
-    #[serde(rename = "_BPMChanges")]
+    #[serde(rename = "_BPMChanges", default, deserialize_with = "bpm_changes")]
     pub bpm_changes: Vec<difficulty::BPMChange>,
     /// Lighting and other various events
-    #[serde(rename = "_events")]
+    #[serde(rename = "_events", deserialize_with = "events")]
     pub events: Vec<difficulty::Event>,
     /// Notes
-    #[serde(rename = "_notes")]
+    #[serde(rename = "_notes", deserialize_with = "notes")]
     pub notes: Vec<difficulty::Note>,
     /// Walls and ceilings
-    #[serde(rename = "_obstacles")]
+    #[serde(rename = "_obstacles", deserialize_with = "obstacles")]
     pub obstacles: Vec<difficulty::Obstacle>,
     /// Bookmarks, used by editors
-    #[serde(rename = "_bookmarks")]
+    #[serde(rename = "_bookmarks", default, deserialize_with = "bookmarks")]
     pub bookmarks: Vec<difficulty::Bookmark>,
 }
+
+impl Difficulty {
+    /// Returns a horizontally mirrored copy of the difficulty
+    ///
+    /// Notes are moved to the opposite lane with their color swapped and their cut direction
+    /// mirrored, obstacles cover the opposite lanes and rotation events turn the other way.
+    pub fn mirrored(&self) -> Difficulty {
+        Difficulty {
+            notes: self.notes.iter().map(difficulty::Note::mirrored).collect(),
+            obstacles: self
+                .obstacles
+                .iter()
+                .map(difficulty::Obstacle::mirrored)
+                .collect(),
+            events: self
+                .events
+                .iter()
+                .map(difficulty::Event::mirrored)
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a vertically mirrored copy of the difficulty
+    ///
+    /// Notes are moved to the opposite layer with their cut direction mirrored, obstacles are kept
+    /// as is since walls and ceilings can't be flipped.
+    pub fn vertically_mirrored(&self) -> Difficulty {
+        Difficulty {
+            notes: self
+                .notes
+                .iter()
+                .map(difficulty::Note::vertically_mirrored)
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the difficulty with the note colors swapped
+    pub fn color_swapped(&self) -> Difficulty {
+        Difficulty {
+            notes: self
+                .notes
+                .iter()
+                .map(difficulty::Note::color_swapped)
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the time in seconds of the given beat, following the BPM changes
+    pub fn beat_to_seconds(&self, beat: f64, bpm: f64) -> f64 {
+        let mut bpm_changes: Vec<&difficulty::BPMChange> = self.bpm_changes.iter().collect();
+        bpm_changes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+
+        let mut seconds = 0.0;
+        let mut current_beat = 0.0;
+        let mut current_bpm = bpm;
+        for bpm_change in bpm_changes {
+            if bpm_change.time >= beat {
+                break;
+            }
+            seconds += (bpm_change.time - current_beat) * 60.0 / current_bpm;
+            current_beat = bpm_change.time;
+            current_bpm = bpm_change.bpm;
+        }
+        seconds + (beat - current_beat) * 60.0 / current_bpm
+    }
+
+    /// Returns the beat at which the last note or obstacle ends
+    pub fn last_object_beat(&self) -> Option<f64> {
+        self.notes
+            .iter()
+            .map(|note| note.time)
+            .chain(
+                self.obstacles
+                    .iter()
+                    .map(|obstacle| obstacle.time + obstacle.duration),
+            )
+            .fold(None, |last: Option<f64>, time| {
+                Some(last.map_or(time, |last| last.max(time)))
+            })
+    }
+
+    /// Returns a copy of the difficulty with every object moved by the given amount of beats
+    pub fn shifted(&self, beats: f64) -> Difficulty {
+        let mut difficulty = self.clone();
+        difficulty.map_times(|time| time + beats);
+        difficulty
+    }
+
+    /// Returns a copy of the difficulty timed for a BPM multiplied by the given factor
+    ///
+    /// Every time and duration in beats is scaled along with the BPM changes, so objects stay
+    /// at the same time in seconds. The `info.dat` BPM must be scaled by the same factor.
+    pub fn rescaled(&self, factor: f64) -> Difficulty {
+        let mut difficulty = self.clone();
+        difficulty.map_times(|time| time * factor);
+        for bpm_change in &mut difficulty.bpm_changes {
+            bpm_change.bpm *= factor;
+        }
+        for obstacle in &mut difficulty.obstacles {
+            obstacle.duration *= factor;
+        }
+        difficulty
+    }
+
+    /// Returns a copy of the difficulty containing only the objects between the given beats
+    ///
+    /// Obstacles overlapping the range are clipped at its boundaries, and the BPM change active
+    /// at the start of the range is moved to its start. Times are not moved.
+    pub fn trimmed(&self, start: f64, end: f64) -> Difficulty {
+        let in_range = |time: f64| time >= start && time <= end;
+
+        let mut bpm_changes: Vec<difficulty::BPMChange> = self
+            .bpm_changes
+            .iter()
+            .filter(|bpm_change| in_range(bpm_change.time))
+            .cloned()
+            .collect();
+        let active = self
+            .bpm_changes
+            .iter()
+            .filter(|bpm_change| bpm_change.time < start)
+            .max_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+        if let Some(active) = active {
+            if !bpm_changes
+                .iter()
+                .any(|bpm_change| bpm_change.time == start)
+            {
+                bpm_changes.insert(
+                    0,
+                    difficulty::BPMChange {
+                        time: start,
+                        ..active.clone()
+                    },
+                );
+            }
+        }
+
+        let obstacles = self
+            .obstacles
+            .iter()
+            .filter_map(|obstacle| {
+                let obstacle_start = obstacle.time.max(start);
+                let obstacle_end = (obstacle.time + obstacle.duration).min(end);
+                if obstacle_start > obstacle_end
+                    || (obstacle_start == obstacle_end && obstacle.duration > 0.0)
+                {
+                    return None;
+                }
+                Some(difficulty::Obstacle {
+                    time: obstacle_start,
+                    duration: obstacle_end - obstacle_start,
+                    ..obstacle.clone()
+                })
+            })
+            .collect();
+
+        Difficulty {
+            version: self.version.clone(),
+            bpm_changes,
+            events: self
+                .events
+                .iter()
+                .filter(|event| in_range(event.time))
+                .cloned()
+                .collect(),
+            notes: self
+                .notes
+                .iter()
+                .filter(|note| in_range(note.time))
+                .cloned()
+                .collect(),
+            obstacles,
+            bookmarks: self
+                .bookmarks
+                .iter()
+                .filter(|bookmark| in_range(bookmark.time))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Returns a OneSaber copy of the difficulty, keeping the notes of the given color
+    ///
+    /// OneSaber is played with the blue saber only, so the difficulty is mirrored first when
+    /// the red notes are kept, turning them into blue notes meant for the right hand. Notes of
+    /// the other color are dropped, or remapped to blue when `remap` is set and they aren't hit
+    /// at the same time as a kept note. Remapped notes swinging in the same direction as the
+    /// previous note become dots.
+    pub fn one_saber(&self, kept: SaberColor, remap: bool) -> Difficulty {
+        if kept == SaberColor::Red {
+            return self.mirrored().one_saber(SaberColor::Blue, remap);
+        }
+        let kept = kept.note_type();
+
+        let sorted = self.sorted_notes();
+        let kept_times: Vec<f64> = sorted
+            .iter()
+            .filter(|note| note.note_type == kept)
+            .map(|note| note.time)
+            .collect();
+
+        let mut notes = Vec::new();
+        let mut last_direction = None;
+        for note in sorted {
+            let note = match note.note_type {
+                NoteType::Bomb => {
+                    notes.push(note);
+                    continue;
+                }
+                note_type if note_type == kept => note,
+                _ if remap => {
+                    let window_start =
+                        kept_times.partition_point(|&time| time <= note.time - ONE_SABER_GAP);
+                    let conflicts = kept_times
+                        .get(window_start)
+                        .is_some_and(|&time| time < note.time + ONE_SABER_GAP);
+                    if conflicts {
+                        continue;
+                    }
+
+                    let mut remapped = note;
+                    remapped.note_type = kept;
+                    if Some(remapped.cut_direction) == last_direction
+                        && remapped.cut_direction != CutDirection::Dot
+                    {
+                        remapped.cut_direction = CutDirection::Dot;
+                    }
+                    remapped
+                }
+                _ => continue,
+            };
+            last_direction = Some(note.cut_direction);
+            notes.push(note);
+        }
+
+        Difficulty {
+            notes,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a NoArrows copy of the difficulty
+    ///
+    /// Every note becomes a dot, and notes of a color following another one of the same color
+    /// closely, such as sliders and stacks, are collapsed into the first one.
+    pub fn no_arrows(&self) -> Difficulty {
+        let mut notes = Vec::new();
+        let mut last_red = None;
+        let mut last_blue = None;
+        for mut note in self.sorted_notes() {
+            let last_time = match note.note_type {
+                NoteType::Red => &mut last_red,
+                NoteType::Blue => &mut last_blue,
+                NoteType::Bomb => {
+                    notes.push(note);
+                    continue;
+                }
+            };
+            let collapsed = last_time.is_some_and(|last| note.time - last < NO_ARROWS_GAP);
+            *last_time = Some(note.time);
+            if !collapsed {
+                note.cut_direction = CutDirection::Dot;
+                notes.push(note);
+            }
+        }
+
+        Difficulty {
+            notes,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the difficulty containing only its events, BPM changes and bookmarks
+    pub fn lightshow(&self) -> Difficulty {
+        Difficulty {
+            notes: Vec::new(),
+            obstacles: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the difficulty using the lighting of another one
+    ///
+    /// Every event of the other difficulty is copied except for rotation events, which affect
+    /// gameplay and are kept from this difficulty.
+    pub fn with_lighting_from(&self, other: &Difficulty) -> Difficulty {
+        let mut events: Vec<difficulty::Event> = other
+            .events
+            .iter()
+            .filter(|event| !event.is_rotation())
+            .chain(self.events.iter().filter(|event| event.is_rotation()))
+            .cloned()
+            .collect();
+        events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+
+        Difficulty {
+            events,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the difficulty with a lightshow generated from its notes
+    ///
+    /// Every event except for rotation events is replaced. See `lighting::generate` for how the
+    /// lightshow is built.
+    pub fn with_generated_lighting(&self, options: &LightingOptions) -> Difficulty {
+        let mut events: Vec<difficulty::Event> = lighting::generate(self, options)
+            .into_iter()
+            .chain(
+                self.events
+                    .iter()
+                    .filter(|event| event.is_rotation())
+                    .cloned(),
+            )
+            .collect();
+        events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+
+        Difficulty {
+            events,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the notes sorted by time
+    pub(crate) fn sorted_notes(&self) -> Vec<difficulty::Note> {
+        let mut notes = self.notes.clone();
+        notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+        notes
+    }
+
+    /// Applies a function to the time of every object
+    fn map_times(&mut self, f: impl Fn(f64) -> f64) {
+        for bpm_change in &mut self.bpm_changes {
+            bpm_change.time = f(bpm_change.time);
+        }
+        for event in &mut self.events {
+            event.time = f(event.time);
+        }
+        for note in &mut self.notes {
+            note.time = f(note.time);
+        }
+        for obstacle in &mut self.obstacles {
+            obstacle.time = f(obstacle.time);
+        }
+        for bookmark in &mut self.bookmarks {
+            bookmark.time = f(bookmark.time);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::difficulty::note::{CutDirection, LineLayer, NoteType, SaberColor};
+    use super::difficulty::{BPMChange, Event, LineIndex};
+    use crate::test_util::test_difficulty;
+
+    #[test]
+    fn mirrored() {
+        let difficulty = test_difficulty();
+        let mirrored = difficulty.mirrored();
+
+        let note = &mirrored.notes[2];
+        assert_eq!(note.line_index, LineIndex::FarRight);
+        assert_eq!(note.note_type, NoteType::Blue);
+        assert_eq!(note.cut_direction, CutDirection::DownRight);
+
+        let obstacle = &mirrored.obstacles[1];
+        assert_eq!(obstacle.line_index, LineIndex::MidLeft);
+
+        let rotation = mirrored.events.iter().find(|e| e.is_rotation()).unwrap();
+        assert_eq!(rotation.value, 5);
+
+        assert_eq!(mirrored.mirrored(), difficulty);
+    }
+
+    #[test]
+    fn vertically_mirrored() {
+        let difficulty = test_difficulty();
+        let mirrored = difficulty.vertically_mirrored();
+
+        let note = &mirrored.notes[4];
+        assert_eq!(note.line_layer, LineLayer::Bottom);
+        assert_eq!(mirrored.notes[0].cut_direction, CutDirection::Up);
+        assert_eq!(mirrored.notes[0].note_type, NoteType::Red);
+
+        assert_eq!(mirrored.vertically_mirrored(), difficulty);
+    }
+
+    #[test]
+    fn color_swapped() {
+        let difficulty = test_difficulty();
+        let swapped = difficulty.color_swapped();
+
+        assert_eq!(swapped.notes[0].note_type, NoteType::Blue);
+        assert_eq!(swapped.notes[0].line_index, difficulty.notes[0].line_index);
+        assert_eq!(swapped.notes[4].note_type, NoteType::Bomb);
+    }
+
+    #[test]
+    fn mirrored_precise() {
+        let note: super::difficulty::Note = serde_json::from_str(
+            r#"{
+                "_time": 0,
+                "_lineIndex": 1500,
+                "_lineLayer": 0,
+                "_type": 0,
+                "_cutDirection": 1045,
+                "_customData": { "_position": [-2, 0], "_cutDirection": 30 }
+            }"#,
+        )
+        .unwrap();
+        let mirrored = note.mirrored();
+
+        // Left edge at 0.5 lanes, mirrored to 4 - 0.5 - 1
+        assert_eq!(mirrored.line_index, LineIndex::Precise(3500));
+        assert_eq!(mirrored.cut_direction, CutDirection::Precise(1315));
+
+        let custom_data = mirrored.custom_data.unwrap();
+        assert_eq!(custom_data["_position"][0], 1.0);
+        assert_eq!(custom_data["_cutDirection"], -30.0);
+
+        let event = Event {
+            time: 0.0,
+            event_type: Event::LATE_ROTATION,
+            value: 1375,
+            custom_data: None,
+        };
+        assert_eq!(event.mirrored().value, 1345);
+    }
+
+    #[test]
+    fn beat_to_seconds() {
+        let mut difficulty = test_difficulty();
+        assert_eq!(difficulty.beat_to_seconds(4.0, 120.0), 2.0);
+
+        difficulty.bpm_changes.push(BPMChange {
+            bpm: 60.0,
+            time: 2.0,
+            beats_per_bar: 4,
+            metronome_offset: 4,
+        });
+        assert_eq!(difficulty.beat_to_seconds(4.0, 120.0), 3.0);
+        assert_eq!(difficulty.last_object_beat(), Some(8.0));
+    }
+
+    #[test]
+    fn shifted() {
+        let difficulty = test_difficulty();
+        let shifted = difficulty.shifted(2.0);
+
+        assert_eq!(shifted.notes[0].time, 3.0);
+        assert_eq!(shifted.obstacles[0].time, 7.0);
+        assert_eq!(shifted.obstacles[0].duration, 1.0);
+        assert_eq!(shifted.bookmarks[0].time, 3.0);
+    }
+
+    #[test]
+    fn rescaled() {
+        let difficulty = test_difficulty();
+        let rescaled = difficulty.rescaled(2.0);
+
+        assert_eq!(rescaled.notes[6].time, 9.0);
+        assert_eq!(rescaled.obstacles[1].time, 12.0);
+        assert_eq!(rescaled.obstacles[1].duration, 4.0);
+        assert_eq!(rescaled.rescaled(0.5), difficulty);
+    }
+
+    #[test]
+    fn trimmed() {
+        let mut difficulty = test_difficulty();
+        difficulty.bpm_changes.push(BPMChange {
+            bpm: 60.0,
+            time: 0.5,
+            beats_per_bar: 4,
+            metronome_offset: 4,
+        });
+        let trimmed = difficulty.trimmed(2.0, 7.0);
+
+        assert_eq!(trimmed.notes.len(), 5);
+        assert!(trimmed.bookmarks.is_empty());
+        assert_eq!(trimmed.bpm_changes[0].time, 2.0);
+        assert_eq!(trimmed.bpm_changes[0].bpm, 60.0);
+
+        // The ceiling from beat 6 to 8 is clipped to end at 7
+        assert_eq!(trimmed.obstacles.len(), 2);
+        assert_eq!(trimmed.obstacles[1].time, 6.0);
+        assert_eq!(trimmed.obstacles[1].duration, 1.0);
+    }
+
+    #[test]
+    fn one_saber() {
+        let difficulty = test_difficulty();
+
+        let dropped = difficulty.one_saber(SaberColor::Blue, false);
+        assert_eq!(dropped.notes.len(), 4);
+        assert!(dropped.notes.iter().all(|n| n.note_type != NoteType::Red));
+
+        // Only the red dot at beat 4 isn't hit along with a blue note
+        let remapped = difficulty.one_saber(SaberColor::Blue, true);
+        assert_eq!(remapped.notes.len(), 5);
+        assert_eq!(remapped.notes[3].time, 4.0);
+        assert_eq!(remapped.notes[3].note_type, NoteType::Blue);
+
+        // Kept red notes are mirrored to blue notes
+        let red = difficulty.one_saber(SaberColor::Red, false);
+        let red_count = difficulty
+            .notes
+            .iter()
+            .filter(|n| n.note_type == NoteType::Red)
+            .count();
+        assert_eq!(
+            red.notes
+                .iter()
+                .filter(|n| n.note_type == NoteType::Blue)
+                .count(),
+            red_count
+        );
+        assert!(red.notes.iter().all(|n| n.note_type != NoteType::Red));
+    }
+
+    #[test]
+    fn no_arrows() {
+        let mut difficulty = test_difficulty();
+        let mut slider = difficulty.notes[6].clone();
+        slider.time += 0.0625;
+        difficulty.notes.push(slider);
+        let no_arrows = difficulty.no_arrows();
+
+        assert_eq!(no_arrows.notes.len(), 7);
+        assert!(no_arrows
+            .notes
+            .iter()
+            .all(|n| n.cut_direction == CutDirection::Dot));
+    }
+
+    #[test]
+    fn with_lighting_from() {
+        let difficulty = test_difficulty();
+        let mut other = difficulty.lightshow();
+        other.events = vec![
+            Event {
+                time: 2.0,
+                event_type: 4,
+                value: 5,
+                custom_data: None,
+            },
+            Event {
+                time: 3.0,
+                event_type: Event::EARLY_ROTATION,
+                value: 0,
+                custom_data: None,
+            },
+        ];
+        let lit = difficulty.with_lighting_from(&other);
+
+        assert_eq!(lit.notes, difficulty.notes);
+        assert_eq!(lit.events.len(), 2);
+        assert_eq!(lit.events[0].event_type, 4);
+        assert_eq!(lit.events[1].value, 2);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::beatsaver::MapDetail;
+use crate::source::{level_hash, ZipSource};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+/// Name of the index file of a download cache
+const INDEX_FILENAME: &str = "index.json";
+
+/// Represents the options of a download cache
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct DownloadCacheOptions {
+    /// Largest total size of the cached zips, in bytes
+    pub max_size: u64,
+    /// Whether to only serve cached maps, without any request
+    pub offline: bool,
+}
+
+impl Default for DownloadCacheOptions {
+    fn default() -> DownloadCacheOptions {
+        DownloadCacheOptions {
+            max_size: 1024 * 1024 * 1024,
+            offline: false,
+        }
+    }
+}
+
+/// Represents a cached map zip
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct CacheEntry {
+    /// Level hash of the map, as lowercase hex
+    pub hash: String,
+    /// Key of the map, like `3cf5`
+    pub key: String,
+    /// Size of the zip, in bytes
+    pub size: u64,
+    /// Map as returned by the API when it was downloaded
+    pub map: Option<MapDetail>,
+    /// Access counter value of the last use, the smallest one is evicted first
+    last_used: u64,
+}
+
+/// Represents the contents of the index file
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    counter: u64,
+    entries: Vec<CacheEntry>,
+}
+
+/// Represents an on-disk cache of map zips, addressed by level hash
+///
+/// Zips are stored as `{hash}.zip` along with an `index.json` file. The least recently used
+/// zips are evicted once the total size goes over the limit. Reads only update the access
+/// counters in memory, which are written to the index on the next change or when the cache is
+/// dropped.
+#[derive(Debug)]
+pub struct DownloadCache {
+    root: PathBuf,
+    options: DownloadCacheOptions,
+    counter: u64,
+    entries: HashMap<String, CacheEntry>,
+    /// Whether access counters changed since the index was last written
+    dirty: bool,
+}
+
+impl DownloadCache {
+    /// Opens the cache stored in the given directory, creating it if needed
+    pub fn open<P: AsRef<Path>>(
+        root: P,
+        options: DownloadCacheOptions,
+    ) -> Result<DownloadCache, Box<dyn Error>> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let index = match fs::read(root.join(INDEX_FILENAME)) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(error) => return Err(Box::new(error)),
+        };
+        let entries = index
+            .entries
+            .into_iter()
+            .filter(|entry| root.join(format!("{}.zip", entry.hash)).is_file())
+            .map(|entry| (entry.hash.clone(), entry))
+            .collect();
+
+        Ok(DownloadCache {
+            root,
+            options,
+            counter: index.counter,
+            entries,
+            dirty: false,
+        })
+    }
+
+    /// Returns the directory the cache is stored in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the options of the cache
+    pub fn options(&self) -> &DownloadCacheOptions {
+        &self.options
+    }
+
+    /// Returns whether the cache only serves cached maps
+    pub fn is_offline(&self) -> bool {
+        self.options.offline
+    }
+
+    /// Returns the number of cached zips
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the total size of the cached zips, in bytes
+    pub fn size(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    /// Returns the path of the zip with the given level hash
+    pub fn path(&self, hash: &str) -> PathBuf {
+        self.root.join(format!("{}.zip", hash.to_lowercase()))
+    }
+
+    /// Returns the entry with the given level hash, marking it as used
+    pub fn get(&mut self, hash: &str) -> Option<&CacheEntry> {
+        let hash = hash.to_lowercase();
+        if !self.entries.contains_key(&hash) {
+            return None;
+        }
+        self.touch(&hash);
+        self.dirty = true;
+        self.entries.get(&hash)
+    }
+
+    /// Returns the most recently used entry of the map with the given key, marking it as used
+    pub fn get_by_key(&mut self, key: &str) -> Option<&CacheEntry> {
+        let hash = self
+            .entries
+            .values()
+            .filter(|entry| entry.key.eq_ignore_ascii_case(key))
+            .max_by_key(|entry| entry.last_used)
+            .map(|entry| entry.hash.clone());
+
+        self.get(&hash?)
+    }
+
+    /// Adds a zip to the cache, after checking that it has the given level hash
+    ///
+    /// The least recently used zips are evicted if the cache goes over its size limit.
+    pub fn insert(
+        &mut self,
+        key: &str,
+        hash: &str,
+        map: Option<MapDetail>,
+        data: &[u8],
+    ) -> Result<&CacheEntry, Box<dyn Error>> {
+        let hash = hash.to_lowercase();
+        verify(data, &hash)?;
+
+        fs::write(self.path(&hash), data)?;
+        self.entries.insert(
+            hash.clone(),
+            CacheEntry {
+                hash: hash.clone(),
+                key: key.to_string(),
+                size: data.len() as u64,
+                map,
+                last_used: 0,
+            },
+        );
+        self.touch(&hash);
+        self.evict(&hash)?;
+        self.save()?;
+
+        Ok(&self.entries[&hash])
+    }
+
+    /// Removes the zip with the given level hash, returning whether it was cached
+    pub fn remove(&mut self, hash: &str) -> Result<bool, Box<dyn Error>> {
+        let hash = hash.to_lowercase();
+        if self.entries.remove(&hash).is_none() {
+            return Ok(false);
+        }
+        remove_file(&self.path(&hash))?;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Checks that the cached zip with the given level hash still has that hash
+    ///
+    /// Zips failing the check are removed.
+    pub fn verify(&mut self, hash: &str) -> Result<(), Box<dyn Error>> {
+        let result = fs::read(self.path(hash))
+            .map_err(|error| -> Box<dyn Error> { Box::new(error) })
+            .and_then(|data| verify(&data, hash));
+        if result.is_err() {
+            self.remove(hash)?;
+        }
+        result
+    }
+
+    fn touch(&mut self, hash: &str) {
+        self.counter += 1;
+        if let Some(entry) = self.entries.get_mut(hash) {
+            entry.last_used = self.counter;
+        }
+    }
+
+    /// Evicts the least recently used zips until the cache fits its size limit
+    fn evict(&mut self, kept: &str) -> Result<(), Box<dyn Error>> {
+        while self.size() > self.options.max_size {
+            let hash = match self
+                .entries
+                .values()
+                .filter(|entry| entry.hash != kept)
+                .min_by_key(|entry| entry.last_used)
+            {
+                Some(entry) => entry.hash.clone(),
+                None => break,
+            };
+            self.entries.remove(&hash);
+            remove_file(&self.path(&hash))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the access counters changed by reads to the index
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.dirty {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut entries: Vec<_> = self.entries.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.last_used);
+        let index = Index {
+            counter: self.counter,
+            entries,
+        };
+
+        // Write to a temporary file first, so that the index is never left truncated
+        let path = self.root.join(INDEX_FILENAME);
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(&index)?)?;
+        fs::rename(temp_path, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for DownloadCache {
+    /// Writes the access counters changed by reads, ignoring errors like a read-only cache
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Checks that the zip of a map has the given level hash
+pub fn verify(data: &[u8], hash: &str) -> Result<(), Box<dyn Error>> {
+    let computed = level_hash(&mut ZipSource::new(Cursor::new(data))?)?;
+    if !computed.eq_ignore_ascii_case(hash) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Zip has level hash {}, expected {}", computed, hash),
+        )));
+    }
+    Ok(())
+}
+
+/// Removes a file, ignoring files which don't exist
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DownloadCache, DownloadCacheOptions};
+    use crate::beatsaver::tests::test_zip;
+    use crate::source::{level_hash, ZipSource};
+    use std::fs;
+    use std::io::{Cursor, Write};
+
+    /// Returns the test map as a zip, with a different difficulty file for each name
+    fn named_zip(name: &str) -> (Vec<u8>, String) {
+        let mut archive = zip::ZipArchive::new(Cursor::new(test_zip())).unwrap();
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut file, &mut contents).unwrap();
+            if file.name() == "Expert.dat" {
+                contents.extend_from_slice(name.as_bytes());
+            }
+            zip.start_file(file.name(), zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(&contents).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+        let hash = level_hash(&mut ZipSource::new(Cursor::new(data.clone())).unwrap()).unwrap();
+        (data, hash)
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let root =
+            std::env::temp_dir().join(format!("beatmap-parser-downloads-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let (first, first_hash) = named_zip(" ");
+        let (second, second_hash) = named_zip("  ");
+        let (third, third_hash) = named_zip("   ");
+        let options = DownloadCacheOptions {
+            max_size: (first.len() + second.len() + third.len() - 1) as u64,
+            offline: false,
+        };
+
+        let mut cache = DownloadCache::open(&root, options).unwrap();
+        assert!(cache.insert("1", &second_hash, None, &first).is_err());
+        cache.insert("1", &first_hash, None, &first).unwrap();
+        cache.insert("2", &second_hash, None, &second).unwrap();
+        assert!(cache.get(&first_hash).is_some());
+
+        // The second zip is the least recently used one
+        cache.insert("3", &third_hash, None, &third).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.path(&second_hash).exists());
+
+        // The index is kept between openings
+        let mut cache = DownloadCache::open(&root, options).unwrap();
+        assert_eq!(cache.get_by_key("1").unwrap().hash, first_hash);
+        assert!(cache.get_by_key("2").is_none());
+
+        fs::write(cache.path(&third_hash), &first).unwrap();
+        assert!(cache.verify(&third_hash).is_err());
+        assert!(cache.get(&third_hash).is_none());
+        assert!(cache.verify(&first_hash).is_ok());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reads_keep_index() {
+        let root = std::env::temp_dir().join(format!(
+            "beatmap-parser-downloads-reads-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let (zip, hash) = named_zip(" ");
+
+        let mut cache = DownloadCache::open(&root, DownloadCacheOptions::default()).unwrap();
+        cache.insert("1", &hash, None, &zip).unwrap();
+        let index = fs::read(root.join("index.json")).unwrap();
+
+        // Reads only touch the index once the cache is dropped
+        assert!(cache.get(&hash).is_some());
+        assert_eq!(fs::read(root.join("index.json")).unwrap(), index);
+        drop(cache);
+        assert_ne!(fs::read(root.join("index.json")).unwrap(), index);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
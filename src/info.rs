@@ -3,54 +3,127 @@ extern crate serde;
 extern crate serde_repr;
 
 use semver::Version;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 /// Contains custom types used by Info
 pub mod info {
     use super::{Deserialize, Serialize};
 
     /// Represents a game environment
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
     pub enum Environment {
+        #[default]
         DefaultEnvironment,
-        BigMirrorEnvironment,
+        OriginsEnvironment,
         TriangleEnvironment,
         NiceEnvironment,
+        BigMirrorEnvironment,
+        DragonsEnvironment,
         KDAEnvironment,
         MonstercatEnvironment,
+        CrabRaveEnvironment,
+        PanicEnvironment,
+        RocketEnvironment,
+        GreenDayEnvironment,
+        GreenDayGrenadeEnvironment,
+        TimbalandEnvironment,
+        FitBeatEnvironment,
+        LinkinParkEnvironment,
+        BTSEnvironment,
+        KaleidoscopeEnvironment,
+        InterscopeEnvironment,
+        SkrillexEnvironment,
+        BillieEnvironment,
+        HalloweenEnvironment,
+        GagaEnvironment,
+        GlassDesertEnvironment,
+        WeaveEnvironment,
+        PyroEnvironment,
+        EDMEnvironment,
+        TheSecondEnvironment,
+        LizzoEnvironment,
+        TheWeekndEnvironment,
+        RockMixtapeEnvironment,
+        Dragons2Environment,
+        Panic2Environment,
+        QueenEnvironment,
+        LinkinPark2Environment,
+        TheRollingStonesEnvironment,
+        LatticeEnvironment,
+        DaftPunkEnvironment,
+        HipHopEnvironment,
+        ColliderEnvironment,
+        BritneyEnvironment,
+        Monstercat2Environment,
+        MetallicaEnvironment,
+        /// Environment unknown to this version, keeping its name
+        #[serde(untagged)]
+        Other(String),
     }
 
     /// Contains custom types used by info::CustomData
     pub mod custom_data {
         use super::{Deserialize, Serialize};
+        use crate::source::MapSource;
+        use std::io;
 
         /// Represents a contributor
-        #[derive(Serialize, Deserialize, Debug)]
+        #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
         pub struct Contributor {
             /// Role of the contributor
-            #[serde(rename = "_role")]
+            #[serde(rename = "_role", default)]
             pub role: String,
             /// Name of the contributor
             #[serde(rename = "_name")]
             pub name: String,
             /// Contributor icon file name
-            #[serde(rename = "_iconPath")]
+            #[serde(rename = "_iconPath", default)]
             pub icon_path: String,
         }
+
+        impl Contributor {
+            /// Returns whether the contributor has an icon
+            pub fn has_icon(&self) -> bool {
+                !self.icon_path.is_empty()
+            }
+
+            /// Reads the icon of the contributor from the files of the map
+            pub fn icon(&self, source: &mut dyn MapSource) -> io::Result<Vec<u8>> {
+                if !self.has_icon() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} has no icon", self.name),
+                    ));
+                }
+                source.read(&self.icon_path)
+            }
+        }
     }
 
     /// Represents custom data applying to all characteristics and difficulties
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Clone, Default, Debug)]
     pub struct CustomData {
         /// People who contributed to this map
-        #[serde(rename = "_contributors")]
+        #[serde(
+            rename = "_contributors",
+            default,
+            skip_serializing_if = "Vec::is_empty"
+        )]
         pub contributors: Vec<custom_data::Contributor>,
         /// Custom platform override, will use "environmentName" if CustomPlatforms isn't installed or is disabled
-        #[serde(rename = "_customEnvironment")]
-        pub custom_environment: String,
+        #[serde(
+            rename = "_customEnvironment",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub custom_environment: Option<String>,
         /// The hash found on ModelSaber, used to download missing platforms
-        #[serde(rename = "_customEnvironmentHash")]
-        pub custom_environment_hash: String,
+        #[serde(
+            rename = "_customEnvironmentHash",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub custom_environment_hash: Option<String>,
     }
 
     /// Contains custom types used by info::DifficultyBeatmapSet
@@ -58,22 +131,29 @@ pub mod info {
         use super::{Deserialize, Serialize};
 
         /// Represent a characteristic
-        #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Hash, Debug)]
+        #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Hash, Debug)]
         pub enum BeatmapCharacteristic {
             Standard,
             NoArrows,
             OneSaber,
             Lawless,
             Lightshow,
+            #[serde(rename = "90Degree")]
+            Degree90,
+            #[serde(rename = "360Degree")]
+            Degree360,
+            Legacy,
         }
 
         /// Contains custom types used by info::difficulty_beatmap_set::DifficultyBeatmap
         pub mod difficulty_beatmap {
             use super::{Deserialize, Serialize};
+            use crate::parse;
+            use serde::Deserializer;
             use serde_repr::*;
 
             /// Represents a difficulty name
-            #[derive(Serialize, Deserialize, Debug)]
+            #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Hash, Debug)]
             pub enum Difficulty {
                 Easy,
                 Normal,
@@ -83,9 +163,7 @@ pub mod info {
             }
 
             /// Represents a difficulty rank
-            #[derive(
-                Serialize_repr, Deserialize_repr, PartialEq, PartialOrd, Eq, Ord, Clone, Hash, Debug,
-            )]
+            #[derive(Serialize_repr, PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Hash, Debug)]
             #[repr(u8)]
             pub enum DifficultyRank {
                 Easy = 1,
@@ -95,12 +173,30 @@ pub mod info {
                 ExpertPlus = 9,
             }
 
+            impl<'de> Deserialize<'de> for DifficultyRank {
+                fn deserialize<D: Deserializer<'de>>(
+                    deserializer: D,
+                ) -> Result<DifficultyRank, D::Error> {
+                    match parse::integer(deserializer, "_difficultyRank")? {
+                        1 => Ok(DifficultyRank::Easy),
+                        3 => Ok(DifficultyRank::Normal),
+                        5 => Ok(DifficultyRank::Hard),
+                        7 => Ok(DifficultyRank::Expert),
+                        9 => Ok(DifficultyRank::ExpertPlus),
+                        value => Err(serde::de::Error::custom(format!(
+                            "invalid difficulty rank {}",
+                            value
+                        ))),
+                    }
+                }
+            }
+
             /// Contains custom types used by info::difficulty_beatmap_set::difficulty_beatmap::CustomData
             pub mod custom_data {
                 use super::{Deserialize, Serialize};
 
                 /// Represents aa RGB color
-                #[derive(Serialize, Deserialize, Debug)]
+                #[derive(Serialize, Deserialize, Clone, Debug)]
                 pub struct Color {
                     /// Amount of red [0-1]
                     pub r: f64,
@@ -123,38 +219,50 @@ pub mod info {
             }
 
             /// Represents custom data applying to a specific characteristic and difficulty
-            #[derive(Serialize, Deserialize, Debug)]
+            #[derive(Serialize, Deserialize, Clone, Default, Debug)]
             pub struct CustomData {
-                /// The name to display for the difficulty
-                #[serde(rename = "_difficultyLabel")]
-                pub difficulty_label: String,
-                #[serde(rename = "_editorOffset")]
+                /// The name to display for the difficulty, the difficulty name is used if absent
+                #[serde(
+                    rename = "_difficultyLabel",
+                    default,
+                    skip_serializing_if = "Option::is_none"
+                )]
+                pub difficulty_label: Option<String>,
+                #[serde(rename = "_editorOffset", default)]
                 pub editor_offset: i32,
-                #[serde(rename = "_editorOldOffset")]
+                #[serde(rename = "_editorOldOffset", default)]
                 pub editor_old_offset: i32,
                 /// Left side color override if CustomColors is installed and enabled
-                #[serde(rename = "_colorLeft")]
+                #[serde(
+                    rename = "_colorLeft",
+                    default,
+                    skip_serializing_if = "Option::is_none"
+                )]
                 pub color_left: Option<custom_data::Color>,
                 /// Right side color override if CustomColors is installed and enabled
-                #[serde(rename = "_colorRight")]
+                #[serde(
+                    rename = "_colorRight",
+                    default,
+                    skip_serializing_if = "Option::is_none"
+                )]
                 pub color_right: Option<custom_data::Color>,
                 /// Any warnings the player should be aware of before playing the song
-                #[serde(rename = "_warnings")]
+                #[serde(rename = "_warnings", default)]
                 pub warning: Vec<String>,
                 /// Any general information the player should be aware of before playing the song
-                #[serde(rename = "_information")]
+                #[serde(rename = "_information", default)]
                 pub information: Vec<String>,
                 /// Any mods the player is suggested to use for playing the song
-                #[serde(rename = "_suggestions")]
+                #[serde(rename = "_suggestions", default)]
                 pub suggestions: Vec<String>,
                 /// Any mods the player is required to use for playing the song
-                #[serde(rename = "_requirements")]
+                #[serde(rename = "_requirements", default)]
                 pub requirements: Vec<String>,
             }
         }
 
         /// Represents a single difficulty beatmap
-        #[derive(Serialize, Deserialize, Debug)]
+        #[derive(Serialize, Deserialize, Clone, Debug)]
         pub struct DifficultyBeatmap {
             /// Name of the difficulty
             #[serde(rename = "_difficulty")]
@@ -165,30 +273,66 @@ pub mod info {
             /// Beatmap file name
             #[serde(rename = "_beatmapFilename")]
             pub beatmap_filename: String,
-            #[serde(rename = "_noteJumpMovementSpeed")]
+            /// Note jump speed, the game default for the difficulty is used if 0
+            #[serde(rename = "_noteJumpMovementSpeed", default)]
             pub note_jump_movement_speed: f64,
-            #[serde(rename = "_noteJumpStartBeatOffset")]
+            #[serde(rename = "_noteJumpStartBeatOffset", default)]
             pub note_jump_start_beat_offset: f64,
             /// Custom data used by mods
-            #[serde(rename = "_customData")]
+            #[serde(rename = "_customData", default)]
             pub custom_data: difficulty_beatmap::CustomData,
         }
     }
 
     /// Represents a set of difficulty beatmaps for a specific characteristic
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Clone, Debug)]
     pub struct DifficultyBeatmapSet {
         /// Characteristic of the beatmap set
         #[serde(rename = "_beatmapCharacteristicName")]
         pub beatmap_characteristic_name: difficulty_beatmap_set::BeatmapCharacteristic,
         /// Set of difficulty beatmaps for the current characteristic
-        #[serde(rename = "_difficultyBeatmaps")]
+        #[serde(
+            rename = "_difficultyBeatmaps",
+            deserialize_with = "difficulty_beatmaps"
+        )]
         pub difficulty_beatmaps: Vec<difficulty_beatmap_set::DifficultyBeatmap>,
     }
+
+    fn difficulty_beatmaps<'de, D>(
+        deserializer: D,
+    ) -> Result<Vec<difficulty_beatmap_set::DifficultyBeatmap>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        crate::parse::list(deserializer, "_difficultyBeatmaps")
+    }
+}
+
+/// Shuffle period used by the editors when absent
+fn default_shuffle_period() -> f64 {
+    0.5
+}
+
+/// Preview length used by the game when absent, in seconds
+fn default_preview_duration() -> f64 {
+    10.0
+}
+
+fn beats_per_minute<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    crate::parse::float(deserializer, "_beatsPerMinute")
+}
+
+fn difficulty_beatmap_sets<'de, D>(
+    deserializer: D,
+) -> Result<Vec<info::DifficultyBeatmapSet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    crate::parse::list(deserializer, "_difficultyBeatmapSets")
 }
 
 /// Represents an `info.dat` file
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Info {
     /// Format version
     #[serde(rename = "_version")]
@@ -197,29 +341,29 @@ pub struct Info {
     #[serde(rename = "_songName")]
     pub song_name: String,
     /// Text rendered in smaller letters next to song name
-    #[serde(rename = "_songSubName")]
+    #[serde(rename = "_songSubName", default)]
     pub song_sub_name: String,
     /// Author of the song itself
-    #[serde(rename = "_songAuthorName")]
+    #[serde(rename = "_songAuthorName", default)]
     pub song_author_name: String,
     /// Mapper of the song
-    #[serde(rename = "_levelAuthorName")]
+    #[serde(rename = "_levelAuthorName", default)]
     pub level_author_name: String,
     /// BPM of the song
-    #[serde(rename = "_beatsPerMinute")]
+    #[serde(rename = "_beatsPerMinute", deserialize_with = "beats_per_minute")]
     pub beats_per_minute: f64,
     /// Offset playing the audio, in seconds
-    #[serde(rename = "_songTimeOffset")]
+    #[serde(rename = "_songTimeOffset", default)]
     pub song_time_offset: f64,
-    #[serde(rename = "_shuffle")]
+    #[serde(rename = "_shuffle", default)]
     pub shuffle: f64,
-    #[serde(rename = "_shufflePeriod")]
+    #[serde(rename = "_shufflePeriod", default = "default_shuffle_period")]
     pub shuffle_period: f64,
     /// Audio preview starting point, in seconds
-    #[serde(rename = "_previewStartTime")]
+    #[serde(rename = "_previewStartTime", default)]
     pub preview_start_time: f64,
     /// Audio preview length, in seconds
-    #[serde(rename = "_previewDuration")]
+    #[serde(rename = "_previewDuration", default = "default_preview_duration")]
     pub preview_duration: f64,
     /// Audio file name
     #[serde(rename = "_songFilename")]
@@ -228,12 +372,59 @@ pub struct Info {
     #[serde(rename = "_coverImageFilename")]
     pub cover_image_filename: String,
     /// Game environment to use
-    #[serde(rename = "_environmentName")]
+    #[serde(rename = "_environmentName", default)]
     pub environment_name: info::Environment,
     /// Custom data used by mods
-    #[serde(rename = "_customData")]
+    #[serde(rename = "_customData", default)]
     pub custom_data: info::CustomData,
     /// A set of maps for a given characteristic
-    #[serde(rename = "_difficultyBeatmapSets")]
+    #[serde(
+        rename = "_difficultyBeatmapSets",
+        deserialize_with = "difficulty_beatmap_sets"
+    )]
     pub difficulty_beatmap_sets: Vec<info::DifficultyBeatmapSet>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::info::difficulty_beatmap_set::BeatmapCharacteristic;
+    use super::info::{CustomData, Environment};
+
+    #[test]
+    fn contributor_without_icon() {
+        let custom_data: CustomData =
+            serde_json::from_str(r#"{"_contributors": [{"_name": "Mapper"}]}"#).unwrap();
+        let contributor = &custom_data.contributors[0];
+
+        assert_eq!(contributor.name, "Mapper");
+        assert_eq!(contributor.role, "");
+        assert!(!contributor.has_icon());
+    }
+
+    #[test]
+    fn environments() {
+        let environment: Environment = serde_json::from_str(r#""LinkinParkEnvironment""#).unwrap();
+        assert_eq!(environment, Environment::LinkinParkEnvironment);
+
+        // Unknown environments keep their name
+        let environment: Environment = serde_json::from_str(r#""FutureEnvironment""#).unwrap();
+        assert_eq!(
+            environment,
+            Environment::Other("FutureEnvironment".to_string())
+        );
+        assert_eq!(
+            serde_json::to_string(&environment).unwrap(),
+            r#""FutureEnvironment""#
+        );
+    }
+
+    #[test]
+    fn rotation_characteristics() {
+        let characteristic: BeatmapCharacteristic = serde_json::from_str(r#""360Degree""#).unwrap();
+        assert_eq!(characteristic, BeatmapCharacteristic::Degree360);
+        assert_eq!(
+            serde_json::to_string(&BeatmapCharacteristic::Degree90).unwrap(),
+            r#""90Degree""#
+        );
+    }
+}
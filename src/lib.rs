@@ -1,3 +1,5 @@
+#![allow(clippy::module_inception)]
+
 #[cfg(feature = "beatsaver")]
 extern crate reqwest;
 #[cfg(feature = "beatsaver")]
@@ -5,41 +7,255 @@ extern crate tempfile;
 #[cfg(feature = "beatsaver")]
 extern crate zip;
 
-#[cfg(feature = "audio")]
-extern crate ogg_metadata;
-
+use difficulty::difficulty::note::SaberColor;
 use difficulty::Difficulty;
 use info::info::difficulty_beatmap_set::{
-    difficulty_beatmap::DifficultyRank, BeatmapCharacteristic,
+    difficulty_beatmap::DifficultyRank, BeatmapCharacteristic, DifficultyBeatmap,
 };
+use info::info::DifficultyBeatmapSet;
 use info::Info;
-use std::collections::HashMap;
+use parse::{ParseMode, ParseWarning};
+use serde::Serialize;
+use sha1_smol::Sha1;
+use source::{DirectorySource, MapSource};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
+use std::fmt;
+use std::io;
 use std::path::Path;
 
+#[cfg(feature = "audio")]
+use audio::{AudioInfo, AudioIssue};
 #[cfg(feature = "beatsaver")]
-use std::io;
-#[cfg(feature = "beatsaver")]
-use std::io::Read;
-#[cfg(feature = "beatsaver")]
-use std::time::Duration;
+use beatsaver::{BeatSaverClient, BeatSaverInfo, BeatSaverRef};
 
+/// Contains audio file parsing
 #[cfg(feature = "audio")]
-use ogg_metadata::OggFormat;
-#[cfg(feature = "audio")]
-use std::fs::File;
-
+pub mod audio;
+/// Contains the BeatSaver API client
 #[cfg(feature = "beatsaver")]
-#[cfg(feature = "audio")]
-use std::io::{Seek, SeekFrom};
-
+pub mod beatsaver;
+/// Contains borrowed and streaming difficulty parsing
+pub mod borrowed;
+/// Contains the compact binary cache format
+#[cfg(feature = "cache")]
+pub mod cache;
+/// Contains cover image inspection and thumbnails
+#[cfg(feature = "cover")]
+pub mod cover;
+/// Contains audio decoding, waveform and onset extraction
+#[cfg(feature = "decode")]
+pub mod decode;
 /// Contains types related to the difficulty files
 pub mod difficulty;
+/// Contains the on-disk cache of BeatSaver downloads
+#[cfg(feature = "beatsaver")]
+pub mod downloads;
 /// Contains types related to the `info.dat` file
 pub mod info;
+/// Contains bulk loading of map folders
+pub mod library;
+/// Contains the automatic lightshow generator
+pub mod lighting;
+/// Contains the async loading API
+#[cfg(feature = "async")]
+pub mod nonblocking;
+/// Contains the strict and lenient parse modes
+pub mod parse;
+/// Contains preview clip extraction and encoding
+#[cfg(feature = "decode")]
+pub mod preview;
+/// Contains the sources map files can be read from
+pub mod source;
+/// Contains BPM and offset estimation from audio
+#[cfg(feature = "decode")]
+pub mod tempo;
+/// Contains the fixtures shared by the tests
+#[cfg(test)]
+pub(crate) mod test_util;
 
 type DifficultyHashMap = HashMap<BeatmapCharacteristic, HashMap<DifficultyRank, Difficulty>>;
 
+/// Represents the source a lazily loaded beatmap reads its difficulties from
+struct LazySource {
+    source: Box<dyn MapSource + Send>,
+    mode: ParseMode,
+}
+
+impl fmt::Debug for LazySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LazySource")
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses a difficulty file from a map source
+fn parse_difficulty(
+    source: &mut dyn MapSource,
+    filename: &str,
+    mode: ParseMode,
+    warnings: &mut Vec<ParseWarning>,
+) -> io::Result<Difficulty> {
+    let contents = source.read(filename)?;
+    parse::from_slice(&contents, filename, mode, warnings)
+}
+
+/// Splits the path of an `info.dat` file into the directory containing the map and the file name
+fn split_info_path(filename: &str) -> io::Result<(&Path, &str)> {
+    let path = Path::new(filename);
+    let beatmap_dir = path.parent().unwrap_or(Path::new("."));
+    let info_filename = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    Ok((beatmap_dir, info_filename))
+}
+
+/// Returns the name an enum variant is serialized as, like `ExpertPlus`
+fn serialized_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Represents a map being parsed, independently of how its files are read
+///
+/// The files are requested one at a time with `Loader::next_file` and handed back with
+/// `Loader::feed`, which lets the sync and async loaders share the parsing. Only the start and
+/// end of the audio file are needed, so its metadata is read separately and handed back with
+/// `Loader::set_audio`.
+struct Loader {
+    info_filename: String,
+    mode: ParseMode,
+    /// Whether the difficulties are parsed, otherwise only `info.dat` and the audio metadata are
+    eager: bool,
+    /// Whether the difficulty files are only hashed, without being parsed
+    hash_only: bool,
+    info: Option<Info>,
+    #[cfg(feature = "audio")]
+    audio: Option<AudioInfo>,
+    /// Difficulties left to parse, in `info.dat` order
+    pending: VecDeque<(BeatmapCharacteristic, DifficultyRank, String)>,
+    difficulties: DifficultyHashMap,
+    warnings: Vec<ParseWarning>,
+    hasher: Sha1,
+}
+
+impl Loader {
+    fn new(info_filename: &str, mode: ParseMode, eager: bool) -> Loader {
+        Loader {
+            info_filename: info_filename.to_string(),
+            mode,
+            eager,
+            hash_only: false,
+            info: None,
+            #[cfg(feature = "audio")]
+            audio: None,
+            pending: VecDeque::new(),
+            difficulties: HashMap::new(),
+            warnings: Vec::new(),
+            hasher: Sha1::new(),
+        }
+    }
+
+    /// Returns a new Loader only computing the level hash of the map
+    ///
+    /// `info.dat` is parsed leniently to find the difficulty files, which are hashed unparsed.
+    fn hashing(info_filename: &str) -> Loader {
+        Loader {
+            hash_only: true,
+            ..Loader::new(info_filename, ParseMode::Lenient, true)
+        }
+    }
+
+    /// Returns the name of the next file to read, if any
+    fn next_file(&self) -> Option<String> {
+        match &self.info {
+            None => Some(self.info_filename.clone()),
+            Some(_) => self
+                .pending
+                .front()
+                .map(|(_, _, filename)| filename.clone()),
+        }
+    }
+
+    /// Returns the name of the audio file, while its metadata is needed
+    #[cfg(feature = "audio")]
+    fn audio_filename(&self) -> Option<String> {
+        match (&self.info, &self.audio) {
+            (Some(info), None) => Some(info.song_filename.clone()),
+            _ => None,
+        }
+    }
+
+    /// Sets the metadata of the audio file
+    #[cfg(feature = "audio")]
+    fn set_audio(&mut self, audio: AudioInfo) {
+        self.audio = Some(audio);
+    }
+
+    /// Parses the contents of the file last returned by `Loader::next_file`
+    fn feed(&mut self, filename: &str, contents: &[u8]) -> io::Result<()> {
+        if self.info.is_none() {
+            self.hasher.update(contents);
+            let info: Info = parse::from_slice(contents, filename, self.mode, &mut self.warnings)?;
+            let eager = self.eager;
+            for set in info.difficulty_beatmap_sets.iter().filter(|_| eager) {
+                // Each set has an entry, even when it has no difficulty
+                let characteristic = set.beatmap_characteristic_name;
+                self.difficulties.insert(characteristic, HashMap::new());
+                self.pending
+                    .extend(set.difficulty_beatmaps.iter().map(|difficulty_beatmap| {
+                        (
+                            characteristic,
+                            difficulty_beatmap.difficulty_rank,
+                            difficulty_beatmap.beatmap_filename.clone(),
+                        )
+                    }));
+            }
+            self.info = Some(info);
+            return Ok(());
+        }
+
+        if let Some((characteristic, rank, _)) = self.pending.pop_front() {
+            self.hasher.update(contents);
+            if self.hash_only {
+                return Ok(());
+            }
+            let difficulty: Difficulty =
+                parse::from_slice(contents, filename, self.mode, &mut self.warnings)?;
+            self.difficulties
+                .entry(characteristic)
+                .or_default()
+                .insert(rank, difficulty);
+        }
+        Ok(())
+    }
+
+    /// Returns the level hash of the files fed so far, as lowercase hex
+    fn hash(&self) -> String {
+        self.hasher.digest().to_string()
+    }
+
+    /// Returns the parsed beatmap, once every requested file was fed
+    fn finish(self) -> Beatmap {
+        let hash = Some(self.hash()).filter(|_| self.eager);
+        Beatmap {
+            info: self.info.expect("info.dat wasn't fed to the loader"),
+            difficulties: self.difficulties,
+            warnings: self.warnings,
+            hash,
+            lazy: None,
+            #[cfg(feature = "beatsaver")]
+            beatsaver: None,
+            #[cfg(feature = "audio")]
+            audio: self.audio.expect("The audio file wasn't fed to the loader"),
+        }
+    }
+}
+
 /// Represents a Beat Saber map
 #[derive(Debug)]
 pub struct Beatmap {
@@ -47,183 +263,448 @@ pub struct Beatmap {
     pub info: Info,
     /// Beatmap difficulty sets
     pub difficulties: DifficultyHashMap,
-    /// BeatSaver key
+    /// Problems found while parsing, like coerced numbers or values dropped in lenient mode
+    pub warnings: Vec<ParseWarning>,
+    /// SHA1 of `info.dat` followed by the difficulty files, as lowercase hex
+    ///
+    /// Only computed when every difficulty is parsed up front.
+    pub hash: Option<String>,
+    /// Source of the difficulties not loaded yet, for lazily loaded beatmaps
+    lazy: Option<LazySource>,
+    /// BeatSaver metadata, for beatmaps fetched through a `BeatSaverClient`
     #[cfg(feature = "beatsaver")]
-    pub key: Option<String>,
-    /// Audio file length, in seconds
+    pub beatsaver: Option<BeatSaverInfo>,
+    /// Audio file metadata
     #[cfg(feature = "audio")]
-    pub length: f64,
+    pub audio: AudioInfo,
 }
 
 impl Beatmap {
-    #[cfg(feature = "audio")]
-    fn calculate_ogg_length(formats: Vec<OggFormat>, mut length: f64) -> f64 {
-        for format in formats {
-            if let OggFormat::Vorbis(metadata) = format {
-                length = (metadata.length_in_samples.unwrap_or(1) as f64
-                    / metadata.sample_rate as f64)
-                    / metadata.channels as f64;
-                length *= 2.0;
-                break;
+    /// Applies a transform to every difficulty
+    ///
+    /// The difficulties of lazily loaded beatmaps are all loaded first, so that none is left
+    /// untransformed.
+    fn map_difficulties(
+        &mut self,
+        f: impl Fn(&Difficulty) -> Difficulty,
+    ) -> Result<(), Box<dyn Error>> {
+        self.load_difficulties()?;
+        for sub_difficulties in self.difficulties.values_mut() {
+            for difficulty in sub_difficulties.values_mut() {
+                *difficulty = f(difficulty);
             }
         }
-        length
+        Ok(())
     }
 
-    /// Returns a new Beatmap instance from an `info.dat` file
-    pub fn from_file_dat(filename: &str) -> Result<Beatmap, Box<dyn Error>> {
-        // Get Info from info.dat
-        let info_contents = std::fs::read_to_string(filename)?;
-        let info: Info = serde_json::from_str(&info_contents)?;
-
-        // Get the directory containing the map
-        let beatmap_dir = Path::new(filename).parent().unwrap_or(Path::new("."));
-
-        let mut difficulties: DifficultyHashMap = HashMap::new();
-        // For each characteristic, get the difficulty ranks
-        for difficulty_beatmap_set in &info.difficulty_beatmap_sets {
-            let mut sub_difficulties = HashMap::new();
-            // For each difficulty rank, get the difficulty from its file
-            for difficulty_beatmap in &difficulty_beatmap_set.difficulty_beatmaps {
-                let difficulty_filename =
-                    Path::new(beatmap_dir).join(&difficulty_beatmap.beatmap_filename);
-                let difficulty_contents = std::fs::read_to_string(difficulty_filename)?;
-                let difficulty: Difficulty = serde_json::from_str(&difficulty_contents)?;
-
-                sub_difficulties.insert(difficulty_beatmap.difficulty_rank.clone(), difficulty);
-            }
+    /// Moves every object of every difficulty by the given amount of beats
+    pub fn shift(&mut self, beats: f64) -> Result<(), Box<dyn Error>> {
+        self.map_difficulties(|difficulty| difficulty.shifted(beats))
+    }
+
+    /// Multiplies the BPM by the given factor, keeping every object at the same time in seconds
+    pub fn rescale_tempo(&mut self, factor: f64) -> Result<(), Box<dyn Error>> {
+        self.map_difficulties(|difficulty| difficulty.rescaled(factor))?;
+        self.info.beats_per_minute *= factor;
+        Ok(())
+    }
+
+    /// Keeps only the objects between the given beats in every difficulty
+    pub fn trim(&mut self, start: f64, end: f64) -> Result<(), Box<dyn Error>> {
+        self.map_difficulties(|difficulty| difficulty.trimmed(start, end))
+    }
 
-            difficulties.insert(
-                difficulty_beatmap_set.beatmap_characteristic_name.clone(),
-                sub_difficulties,
-            );
+    /// Adds a difficulty beatmap set generated from the difficulties of another set
+    ///
+    /// Only the difficulty of the given rank is used when one is provided.
+    fn add_generated_set(
+        &mut self,
+        characteristic: BeatmapCharacteristic,
+        source: BeatmapCharacteristic,
+        rank: Option<DifficultyRank>,
+        f: impl Fn(&Difficulty) -> Difficulty,
+    ) -> Result<(), Box<dyn Error>> {
+        // Load the source difficulties of lazily loaded beatmaps
+        let source_ranks: Vec<_> = self
+            .info
+            .difficulty_beatmap_sets
+            .iter()
+            .filter(|set| set.beatmap_characteristic_name == source)
+            .flat_map(|set| &set.difficulty_beatmaps)
+            .map(|difficulty_beatmap| difficulty_beatmap.difficulty_rank)
+            .filter(|&source_rank| rank.is_none_or(|rank| rank == source_rank))
+            .collect();
+        for source_rank in source_ranks {
+            self.difficulty(source, source_rank)?;
         }
 
-        // Calculate the audio file length
-        #[cfg(feature = "audio")]
-        let mut length = 0.0;
-        #[cfg(feature = "audio")]
+        let sets = &self.info.difficulty_beatmap_sets;
+        if sets
+            .iter()
+            .any(|set| set.beatmap_characteristic_name == characteristic)
         {
-            let audio_filename = Path::new(beatmap_dir).join(&info.song_filename);
-            let mut audio_file = File::open(audio_filename)?;
-            let formats = ogg_metadata::read_format(&mut audio_file)?;
-
-            length = Beatmap::calculate_ogg_length(formats, length);
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Beatmap already has a {:?} set", characteristic),
+            )));
+        }
+        let source_set = sets
+            .iter()
+            .find(|set| set.beatmap_characteristic_name == source)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Beatmap has no {:?} set", source),
+                )
+            })?;
+
+        let mut difficulty_beatmaps = Vec::new();
+        let mut sub_difficulties = HashMap::new();
+        for difficulty_beatmap in &source_set.difficulty_beatmaps {
+            if rank.is_some_and(|rank| rank != difficulty_beatmap.difficulty_rank) {
+                continue;
+            }
+            let difficulty = match self.difficulties.get(&source).and_then(|sub_difficulties| {
+                sub_difficulties.get(&difficulty_beatmap.difficulty_rank)
+            }) {
+                Some(difficulty) => difficulty,
+                None => continue,
+            };
+
+            sub_difficulties.insert(difficulty_beatmap.difficulty_rank, f(difficulty));
+            difficulty_beatmaps.push(DifficultyBeatmap {
+                beatmap_filename: format!(
+                    "{}{}.dat",
+                    serialized_name(&characteristic),
+                    serialized_name(&difficulty_beatmap.difficulty)
+                ),
+                ..difficulty_beatmap.clone()
+            });
+        }
+        if difficulty_beatmaps.is_empty() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No {:?} difficulty to generate from", source),
+            )));
         }
 
-        // Create the Beatmap and return it
-        Ok(Beatmap {
-            info,
-            difficulties,
-            #[cfg(feature = "beatsaver")]
-            key: None,
-            #[cfg(feature = "audio")]
-            length,
-        })
+        self.info
+            .difficulty_beatmap_sets
+            .push(DifficultyBeatmapSet {
+                beatmap_characteristic_name: characteristic,
+                difficulty_beatmaps,
+            });
+        self.difficulties.insert(characteristic, sub_difficulties);
+        Ok(())
     }
 
-    /// Returns a new Beatmap instance from a BeatSaver key
-    #[cfg(feature = "beatsaver")]
-    pub fn from_beatsaver_key(key: &str) -> Result<Beatmap, Box<dyn Error>> {
-        // Download the file and store it temporarly
-        let mut response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(120))
-            .build()?
-            .get(&format!("https://beatsaver.com/api/download/key/{}", key))
-            .send()?;
-        let mut temp_file = tempfile::tempfile()?;
-        io::copy(&mut response, &mut temp_file)?;
-
-        // Create the zip archive object
-        let mut archive = zip::ZipArchive::new(temp_file)?;
-
-        let info: Info = {
-            // Get Info from info.dat
-            let mut info_file = archive.by_name("info.dat")?;
-            let mut info_contents = String::new();
-            info_file.read_to_string(&mut info_contents)?;
-
-            serde_json::from_str(&info_contents)?
-        };
+    /// Adds a OneSaber set generated from the Standard difficulties
+    ///
+    /// Difficulty files are named after the characteristic and difficulty, like
+    /// `OneSaberExpert.dat`. See `Difficulty::one_saber` for how notes are converted.
+    pub fn add_one_saber(&mut self, kept: SaberColor, remap: bool) -> Result<(), Box<dyn Error>> {
+        self.add_generated_set(
+            BeatmapCharacteristic::OneSaber,
+            BeatmapCharacteristic::Standard,
+            None,
+            |difficulty| difficulty.one_saber(kept, remap),
+        )
+    }
 
-        let mut difficulties: DifficultyHashMap = HashMap::new();
-        // For each characteristic, get the difficulty ranks
-        for difficulty_beatmap_set in &info.difficulty_beatmap_sets {
-            let mut sub_difficulties = HashMap::new();
-            // For each difficulty rank, get the difficulty from its file
-            for difficulty_beatmap in &difficulty_beatmap_set.difficulty_beatmaps {
-                let mut difficulty_file = archive.by_name(&difficulty_beatmap.beatmap_filename)?;
-                let mut difficulty_contents = String::new();
-                difficulty_file.read_to_string(&mut difficulty_contents)?;
-                let difficulty: Difficulty = serde_json::from_str(&difficulty_contents)?;
-
-                sub_difficulties.insert(difficulty_beatmap.difficulty_rank.clone(), difficulty);
-            }
+    /// Adds a NoArrows set generated from the Standard difficulties
+    ///
+    /// Difficulty files are named after the characteristic and difficulty, like
+    /// `NoArrowsExpert.dat`. See `Difficulty::no_arrows` for how notes are converted.
+    pub fn add_no_arrows(&mut self) -> Result<(), Box<dyn Error>> {
+        self.add_generated_set(
+            BeatmapCharacteristic::NoArrows,
+            BeatmapCharacteristic::Standard,
+            None,
+            Difficulty::no_arrows,
+        )
+    }
+
+    /// Adds a Lightshow set generated from the given difficulty
+    ///
+    /// The difficulty file is named after the characteristic and difficulty, like
+    /// `LightshowExpert.dat`. See `Difficulty::lightshow` for what is kept.
+    pub fn add_lightshow(
+        &mut self,
+        characteristic: BeatmapCharacteristic,
+        rank: DifficultyRank,
+    ) -> Result<(), Box<dyn Error>> {
+        self.add_generated_set(
+            BeatmapCharacteristic::Lightshow,
+            characteristic,
+            Some(rank),
+            Difficulty::lightshow,
+        )
+    }
+
+    /// Copies the lighting of the given difficulty onto every other difficulty
+    ///
+    /// See `Difficulty::with_lighting_from` for which events are copied.
+    pub fn share_lighting(
+        &mut self,
+        characteristic: BeatmapCharacteristic,
+        rank: DifficultyRank,
+    ) -> Result<(), Box<dyn Error>> {
+        let source = self
+            .difficulty(characteristic, rank)?
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Beatmap has no {:?} {:?} difficulty", characteristic, rank),
+                )
+            })?;
 
-            difficulties.insert(
-                difficulty_beatmap_set.beatmap_characteristic_name.clone(),
-                sub_difficulties,
-            );
+        self.map_difficulties(|difficulty| difficulty.with_lighting_from(&source))
+    }
+
+    /// Returns the inconsistencies between the beatmap and its audio file
+    ///
+    /// Checks that the preview and the last object of each difficulty end before the audio.
+    #[cfg(feature = "audio")]
+    pub fn audio_issues(&self) -> Vec<AudioIssue> {
+        let duration = self.audio.duration;
+        let mut issues = Vec::new();
+
+        let preview_end = self.info.preview_start_time + self.info.preview_duration;
+        if preview_end > duration {
+            issues.push(AudioIssue::PreviewOutOfBounds {
+                preview_end,
+                duration,
+            });
         }
 
-        // Calculate the audio file length
-        #[cfg(feature = "audio")]
-        let mut length = 0.0;
+        for (&characteristic, sub_difficulties) in &self.difficulties {
+            for (&rank, difficulty) in sub_difficulties {
+                let time = match difficulty.last_object_beat() {
+                    Some(beat) => difficulty.beat_to_seconds(beat, self.info.beats_per_minute),
+                    None => continue,
+                };
+                if time > duration {
+                    issues.push(AudioIssue::ObjectAfterEnd {
+                        characteristic,
+                        rank,
+                        time,
+                        duration,
+                    });
+                }
+            }
+        }
+        issues
+    }
+
+    /// Returns a new Beatmap instance from a map source
+    pub fn from_source<S: MapSource>(source: S) -> Result<Beatmap, Box<dyn Error>> {
+        Beatmap::from_source_with_mode(source, ParseMode::Strict)
+    }
+
+    /// Returns a new Beatmap instance from a map source, parsed with the given mode
+    pub fn from_source_with_mode<S: MapSource>(
+        mut source: S,
+        mode: ParseMode,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        let info_filename = source.info_filename()?;
+        Beatmap::parse(&mut source, &info_filename, mode)
+    }
+
+    /// Returns a new Beatmap instance from a map source, deferring the difficulties
+    ///
+    /// Only `info.dat` is parsed, each difficulty is parsed when first accessed with
+    /// `Beatmap::difficulty`. Transforms like `Beatmap::shift` load every difficulty first.
+    pub fn from_source_lazy<S: MapSource + Send + 'static>(
+        mut source: S,
+        mode: ParseMode,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        let info_filename = source.info_filename()?;
+        Beatmap::parse_lazy(source, &info_filename, mode)
+    }
+
+    /// Parses `info.dat` from a source and keeps the source to load the difficulties later
+    fn parse_lazy<S: MapSource + Send + 'static>(
+        mut source: S,
+        info_filename: &str,
+        mode: ParseMode,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        let mut beatmap = Beatmap::parse_info(&mut source, info_filename, mode)?;
+        beatmap.lazy = Some(LazySource {
+            source: Box::new(source),
+            mode,
+        });
+        Ok(beatmap)
+    }
+
+    /// Parses a map from a source, given the name of its `info.dat` file
+    fn parse(
+        source: &mut dyn MapSource,
+        info_filename: &str,
+        mode: ParseMode,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        Beatmap::load(source, Loader::new(info_filename, mode, true))
+    }
+
+    /// Parses `info.dat` and the audio file metadata of a map, without its difficulties
+    fn parse_info(
+        source: &mut dyn MapSource,
+        info_filename: &str,
+        mode: ParseMode,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        Beatmap::load(source, Loader::new(info_filename, mode, false))
+    }
+
+    /// Reads the files requested by a loader from a source
+    fn load(source: &mut dyn MapSource, mut loader: Loader) -> Result<Beatmap, Box<dyn Error>> {
+        while let Some(filename) = loader.next_file() {
+            let contents = source.read(&filename)?;
+            loader.feed(&filename, &contents)?;
+        }
         #[cfg(feature = "audio")]
         {
-            let mut audio_file = archive.by_name(&info.song_filename)?;
-            let mut temp_audio_file = tempfile::tempfile()?;
-            io::copy(&mut audio_file, &mut temp_audio_file)?;
-            temp_audio_file.seek(SeekFrom::Start(0))?;
-            let formats = ogg_metadata::read_format(&mut temp_audio_file)?;
+            if let Some(filename) = loader.audio_filename() {
+                loader.set_audio(AudioInfo::from_source(source, &filename)?);
+            }
+        }
+        Ok(loader.finish())
+    }
 
-            length = Beatmap::calculate_ogg_length(formats, length);
+    /// Returns the difficulty of the given characteristic and rank
+    ///
+    /// For lazily loaded beatmaps, the difficulty is parsed on first access and kept for the
+    /// next ones.
+    pub fn difficulty(
+        &mut self,
+        characteristic: BeatmapCharacteristic,
+        rank: DifficultyRank,
+    ) -> Result<Option<&Difficulty>, Box<dyn Error>> {
+        let loaded = self
+            .difficulties
+            .get(&characteristic)
+            .is_some_and(|sub_difficulties| sub_difficulties.contains_key(&rank));
+
+        if let (false, Some(lazy)) = (loaded, &mut self.lazy) {
+            let difficulty_beatmap = self
+                .info
+                .difficulty_beatmap_sets
+                .iter()
+                .filter(|set| set.beatmap_characteristic_name == characteristic)
+                .flat_map(|set| &set.difficulty_beatmaps)
+                .find(|difficulty_beatmap| difficulty_beatmap.difficulty_rank == rank);
+
+            if let Some(difficulty_beatmap) = difficulty_beatmap {
+                let difficulty = parse_difficulty(
+                    &mut lazy.source,
+                    &difficulty_beatmap.beatmap_filename,
+                    lazy.mode,
+                    &mut self.warnings,
+                )?;
+                self.difficulties
+                    .entry(characteristic)
+                    .or_default()
+                    .insert(rank, difficulty);
+            }
         }
 
-        // Create the Beatmap and return it
-        Ok(Beatmap {
-            info,
-            difficulties,
-            #[cfg(feature = "beatsaver")]
-            key: Some(String::from(key)),
-            #[cfg(feature = "audio")]
-            length,
-        })
+        Ok(self
+            .difficulties
+            .get(&characteristic)
+            .and_then(|sub_difficulties| sub_difficulties.get(&rank)))
+    }
+
+    /// Loads every difficulty not loaded yet, for lazily loaded beatmaps
+    pub fn load_difficulties(&mut self) -> Result<(), Box<dyn Error>> {
+        let pending: Vec<_> = self
+            .info
+            .difficulty_beatmap_sets
+            .iter()
+            .flat_map(|set| {
+                set.difficulty_beatmaps
+                    .iter()
+                    .map(move |difficulty_beatmap| {
+                        (
+                            set.beatmap_characteristic_name,
+                            difficulty_beatmap.difficulty_rank,
+                        )
+                    })
+            })
+            .collect();
+        for (characteristic, rank) in pending {
+            self.difficulty(characteristic, rank)?;
+        }
+
+        // Every difficulty is loaded, the source isn't needed anymore
+        self.lazy = None;
+        Ok(())
+    }
+
+    /// Returns whether every difficulty listed in `info.dat` is loaded
+    pub fn is_loaded(&self) -> bool {
+        self.lazy.is_none()
+            || self.info.difficulty_beatmap_sets.iter().all(|set| {
+                set.difficulty_beatmaps.iter().all(|difficulty_beatmap| {
+                    self.difficulties
+                        .get(&set.beatmap_characteristic_name)
+                        .is_some_and(|sub_difficulties| {
+                            sub_difficulties.contains_key(&difficulty_beatmap.difficulty_rank)
+                        })
+                })
+            })
+    }
+
+    /// Returns a new Beatmap instance from an `info.dat` file
+    pub fn from_file_dat(filename: &str) -> Result<Beatmap, Box<dyn Error>> {
+        Beatmap::from_file_dat_with_mode(filename, ParseMode::Strict)
+    }
+
+    /// Returns a new Beatmap instance from an `info.dat` file, parsed with the given mode
+    pub fn from_file_dat_with_mode(
+        filename: &str,
+        mode: ParseMode,
+    ) -> Result<Beatmap, Box<dyn Error>> {
+        let (beatmap_dir, info_filename) = split_info_path(filename)?;
+        Beatmap::parse(&mut DirectorySource::new(beatmap_dir), info_filename, mode)
+    }
+
+    /// Returns a new Beatmap instance from an `info.dat` file, deferring the difficulties
+    ///
+    /// See `Beatmap::from_source_lazy` for how the difficulties are loaded.
+    pub fn from_file_dat_lazy(filename: &str, mode: ParseMode) -> Result<Beatmap, Box<dyn Error>> {
+        let (beatmap_dir, info_filename) = split_info_path(filename)?;
+        Beatmap::parse_lazy(DirectorySource::new(beatmap_dir), info_filename, mode)
+    }
+
+    /// Returns a new Beatmap instance from a BeatSaver key
+    ///
+    /// Uses a client with the default options shared between calls, see `BeatSaverClient` to
+    /// configure it.
+    #[cfg(feature = "beatsaver")]
+    pub fn from_beatsaver_key(key: &str) -> Result<Beatmap, Box<dyn Error>> {
+        BeatSaverClient::shared()?.beatmap_by_id(key)
     }
 
     /// Returns a new Beatmap instance from a BeatSaver url
+    ///
+    /// See `BeatSaverRef::parse` for the accepted urls, hashes are accepted too. Uses the same
+    /// client as `Beatmap::from_beatsaver_key`.
     #[cfg(feature = "beatsaver")]
     pub fn from_beatsaver_url(url: &str) -> Result<Beatmap, Box<dyn Error>> {
-        let url_string = String::from(url);
-        if url_string.starts_with("https://beatsaver.com/api/download/key/")
-            || url_string.starts_with("https://beatsaver.com/beatmap/")
-            || url_string.starts_with("beatsaver://")
-        {
-            let mut key = String::from(url_string.split("/").last().unwrap_or("invalid"));
-            if key == "invalid" {
-                return Err(Box::new(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Can't extract key from url",
-                )));
-            }
-            if key.ends_with("/") {
-                key.pop();
-            }
-
-            Beatmap::from_beatsaver_key(&key)
-        } else {
-            Err(Box::new(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid url",
-            )))
-        }
+        let reference = BeatSaverRef::parse(url)?;
+        BeatSaverClient::shared()?.beatmap_by_ref(&reference)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Beatmap;
+    use super::{
+        Beatmap, BeatmapCharacteristic, DifficultyRank, DirectorySource, MapSource, ParseMode,
+        SaberColor,
+    };
+    use std::io;
     use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn from_file_dat() {
@@ -232,19 +713,180 @@ mod tests {
 
         let result = Beatmap::from_file_dat(filename.to_str().unwrap()).unwrap();
         println!("{:#?}", result);
+
+        let custom_data = &result.info.custom_data;
+        assert_eq!(custom_data.contributors[0].icon_path, "icon.png");
+        assert_eq!(custom_data.custom_environment, None);
     }
 
-    #[cfg(feature = "beatsaver")]
+    #[cfg(feature = "audio")]
     #[test]
-    fn from_beatsaver_key() {
-        let result = Beatmap::from_beatsaver_key("3cf5").unwrap();
-        println!("{:#?}", result);
+    fn audio_info() {
+        let mut filename = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filename.push("resources/test/info.dat");
+
+        let mut result = Beatmap::from_file_dat(filename.to_str().unwrap()).unwrap();
+        assert_eq!(result.audio.duration, 10.0);
+        assert_eq!(result.audio.title(), Some("Test Song"));
+        assert!(result.audio_issues().is_empty());
+
+        result.info.preview_duration = 10.0;
+        result.shift(20.0).unwrap();
+        assert_eq!(result.audio_issues().len(), 2);
+    }
+
+    #[test]
+    fn rescale_tempo() {
+        let mut filename = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filename.push("resources/test/info.dat");
+
+        let mut beatmap = Beatmap::from_file_dat(filename.to_str().unwrap()).unwrap();
+        beatmap.rescale_tempo(1.5).unwrap();
+        let difficulty =
+            &beatmap.difficulties[&BeatmapCharacteristic::Standard][&DifficultyRank::Expert];
+
+        assert_eq!(beatmap.info.beats_per_minute, 180.0);
+        // Still 0.5 second in
+        assert_eq!(difficulty.notes[0].time, 1.5);
+    }
+
+    #[test]
+    fn add_generated_sets() {
+        let mut filename = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filename.push("resources/test/info.dat");
+
+        let mut beatmap = Beatmap::from_file_dat(filename.to_str().unwrap()).unwrap();
+        beatmap.add_one_saber(SaberColor::Blue, true).unwrap();
+        beatmap.add_no_arrows().unwrap();
+        assert!(beatmap.add_no_arrows().is_err());
+
+        let sets = &beatmap.info.difficulty_beatmap_sets;
+        assert_eq!(sets.len(), 3);
+        assert_eq!(
+            sets[1].beatmap_characteristic_name,
+            BeatmapCharacteristic::OneSaber
+        );
+        assert_eq!(
+            sets[1].difficulty_beatmaps[0].beatmap_filename,
+            "OneSaberExpert.dat"
+        );
+        assert!(beatmap.difficulties[&BeatmapCharacteristic::NoArrows]
+            .contains_key(&DifficultyRank::Expert));
+    }
+
+    #[test]
+    fn add_lightshow() {
+        let mut filename = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filename.push("resources/test/info.dat");
+
+        let mut beatmap = Beatmap::from_file_dat(filename.to_str().unwrap()).unwrap();
+        assert!(beatmap
+            .add_lightshow(BeatmapCharacteristic::Standard, DifficultyRank::Easy)
+            .is_err());
+        beatmap
+            .add_lightshow(BeatmapCharacteristic::Standard, DifficultyRank::Expert)
+            .unwrap();
+
+        let lightshow =
+            &beatmap.difficulties[&BeatmapCharacteristic::Lightshow][&DifficultyRank::Expert];
+        assert!(lightshow.notes.is_empty());
+        assert_eq!(lightshow.events.len(), 2);
+    }
+
+    #[test]
+    fn lazy() {
+        /// Counts the files read from the test map
+        struct CountingSource {
+            inner: DirectorySource,
+            reads: Arc<AtomicUsize>,
+        }
+
+        impl MapSource for CountingSource {
+            fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.inner.read(name)
+            }
+
+            fn exists(&mut self, name: &str) -> bool {
+                self.inner.exists(name)
+            }
+        }
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let source = CountingSource {
+            inner: DirectorySource::new(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test")),
+            reads: reads.clone(),
+        };
+        let mut beatmap = Beatmap::from_source_lazy(source, ParseMode::Strict).unwrap();
+        let info_reads = reads.load(Ordering::SeqCst);
+        assert!(beatmap.difficulties.is_empty());
+        assert!(!beatmap.is_loaded());
+
+        let standard = BeatmapCharacteristic::Standard;
+        let expert = beatmap
+            .difficulty(standard, DifficultyRank::Expert)
+            .unwrap();
+        assert_eq!(expert.unwrap().notes.len(), 7);
+        beatmap
+            .difficulty(standard, DifficultyRank::Expert)
+            .unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), info_reads + 1);
+
+        assert!(beatmap
+            .difficulty(standard, DifficultyRank::Easy)
+            .unwrap()
+            .is_none());
+        assert!(beatmap.is_loaded());
+        beatmap.load_difficulties().unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), info_reads + 1);
+    }
+
+    #[test]
+    fn lazy_rescale_tempo() {
+        let mut filename = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filename.push("resources/test/info.dat");
+
+        let mut beatmap =
+            Beatmap::from_file_dat_lazy(filename.to_str().unwrap(), ParseMode::Strict).unwrap();
+        beatmap.rescale_tempo(1.5).unwrap();
+        assert!(beatmap.is_loaded());
+
+        let difficulty = beatmap
+            .difficulty(BeatmapCharacteristic::Standard, DifficultyRank::Expert)
+            .unwrap()
+            .unwrap();
+        assert_eq!(difficulty.notes[0].time, 1.5);
+        assert_eq!(beatmap.info.beats_per_minute, 180.0);
     }
 
     #[cfg(feature = "beatsaver")]
     #[test]
-    fn from_beatsaver_url() {
-        let result = Beatmap::from_beatsaver_url("https://beatsaver.com/beatmap/1fef").unwrap();
-        println!("{:#?}", result);
+    fn from_beatsaver_refs() {
+        use crate::beatsaver::tests::{fixture, mock_server, test_options, test_zip};
+        use crate::beatsaver::{BeatSaverClient, BeatSaverRef};
+
+        let url = mock_server(|request| match request.url().to_lowercase().as_str() {
+            "/maps/id/3cf5" | "/maps/hash/5812661bf5320a04e14933d861b56fa4d9706104" => {
+                (200, fixture("map.json", request))
+            }
+            "/cdn/5812661bf5320a04e14933d861b56fa4d9706104.zip" => (200, test_zip()),
+            _ => (404, Vec::new()),
+        });
+        let client = BeatSaverClient::new(test_options(&url)).unwrap();
+
+        for reference in &[
+            "3cf5",
+            "https://beatsaver.com/beatmap/3cf5",
+            "https://beatsaver.com/maps/3cf5",
+            "5812661BF5320A04E14933D861B56FA4D9706104",
+        ] {
+            let reference = BeatSaverRef::parse(reference).unwrap();
+            let beatmap = client.beatmap_by_ref(&reference).unwrap();
+            assert_eq!(beatmap.info.song_name, "Test Song");
+            assert_eq!(beatmap.beatsaver.unwrap().key, "3cf5");
+        }
+
+        let reference = BeatSaverRef::parse("https://beatsaver.com/maps/1fef").unwrap();
+        assert!(client.beatmap_by_ref(&reference).is_err());
     }
 }
@@ -0,0 +1,396 @@
+use crate::parse::ParseMode;
+use crate::source::DirectorySource;
+use crate::Beatmap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "zip")]
+use crate::source::ZipSource;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use std::sync::mpsc::{self, Receiver};
+
+/// Number of loaded maps kept waiting for a slow consumer of a parallel scan
+#[cfg(feature = "parallel")]
+const SCAN_BUFFER: usize = 16;
+
+/// Result of loading a single map of a library
+pub type MapResult = Result<Beatmap, Box<dyn Error + Send + Sync>>;
+
+/// Represents the options used to load the maps of a library
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub struct ScanOptions {
+    /// Parse mode of every map
+    pub mode: ParseMode,
+    /// Whether to defer the difficulties, see `Beatmap::from_source_lazy`
+    pub lazy: bool,
+}
+
+/// Represents the counts of a scan
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub struct ScanSummary {
+    /// Number of maps discovered
+    pub total: usize,
+    /// Number of maps loaded successfully so far
+    pub loaded: usize,
+    /// Number of maps which failed to load so far
+    pub failed: usize,
+    /// Number of subfolders which couldn't be read, not counted as maps
+    pub unreadable: usize,
+}
+
+/// Represents a folder of maps, like `CustomLevels`
+#[derive(Clone, Debug)]
+pub struct Library {
+    /// Folder the maps were discovered in
+    pub root: PathBuf,
+    /// Map directories and zip archives, sorted by path
+    pub maps: Vec<PathBuf>,
+    /// Subfolders which couldn't be read, with the error message, sorted by path
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl Library {
+    /// Discovers the maps in a folder and its subfolders
+    ///
+    /// A map is either a directory containing an `info.dat` file, or a zip archive when the `zip`
+    /// feature is enabled. Only a root which can't be read fails the discovery, the subfolders
+    /// which can't be read are added to the errors. Symlinked folders are followed once.
+    pub fn discover<P: AsRef<Path>>(root: P) -> io::Result<Library> {
+        let root = root.as_ref().to_path_buf();
+        let mut discovery = Discovery::default();
+        discovery.visited.insert(root.canonicalize()?);
+        for entry in std::fs::read_dir(&root)? {
+            match entry {
+                Ok(entry) => discovery.add(entry.path()),
+                Err(error) => discovery.errors.push((root.clone(), error.to_string())),
+            }
+        }
+        discovery.maps.sort();
+        discovery.errors.sort();
+
+        Ok(Library {
+            root,
+            maps: discovery.maps,
+            errors: discovery.errors,
+        })
+    }
+
+    /// Discovers and loads the maps in a folder with the default options
+    pub fn scan<P: AsRef<Path>>(root: P) -> io::Result<Scan> {
+        Ok(Library::discover(root)?.load(ScanOptions::default()))
+    }
+
+    /// Loads every map of the library
+    ///
+    /// The subfolders which couldn't be read are yielded first, as failures. Maps are loaded in
+    /// parallel when the `parallel` feature is enabled, in which case they are yielded in
+    /// completion order.
+    pub fn load(self, options: ScanOptions) -> Scan {
+        let summary = ScanSummary {
+            total: self.maps.len(),
+            unreadable: self.errors.len(),
+            ..ScanSummary::default()
+        };
+
+        #[cfg(feature = "parallel")]
+        let results = {
+            let (sender, receiver) = mpsc::sync_channel(SCAN_BUFFER);
+            let maps = self.maps;
+            std::thread::spawn(move || {
+                // Stops early once the scan is dropped
+                let load = || {
+                    let _ = maps
+                        .into_par_iter()
+                        .try_for_each_with(sender, |sender, path| {
+                            let result = load_map(&path, options);
+                            sender.send((path, result)).map_err(drop)
+                        });
+                };
+                // Workers wait on the consumer, so they must not be the ones it may rely on
+                match rayon::ThreadPoolBuilder::new().build() {
+                    Ok(pool) => pool.install(load),
+                    Err(_) => load(),
+                }
+            });
+            receiver
+        };
+        #[cfg(not(feature = "parallel"))]
+        let results = self.maps.into_iter();
+
+        Scan {
+            errors: self.errors.into_iter(),
+            results,
+            options,
+            summary,
+        }
+    }
+}
+
+/// Represents an ongoing scan, yielding each map along with its path
+pub struct Scan {
+    errors: std::vec::IntoIter<(PathBuf, String)>,
+    #[cfg(feature = "parallel")]
+    results: Receiver<(PathBuf, MapResult)>,
+    #[cfg(not(feature = "parallel"))]
+    results: std::vec::IntoIter<PathBuf>,
+    #[cfg_attr(feature = "parallel", allow(dead_code))]
+    options: ScanOptions,
+    summary: ScanSummary,
+}
+
+impl Scan {
+    /// Returns the counts of the maps yielded so far
+    pub fn summary(&self) -> ScanSummary {
+        self.summary
+    }
+}
+
+impl Iterator for Scan {
+    type Item = (PathBuf, MapResult);
+
+    fn next(&mut self) -> Option<(PathBuf, MapResult)> {
+        if let Some((path, error)) = self.errors.next() {
+            return Some((path, Err(error.into())));
+        }
+
+        #[cfg(feature = "parallel")]
+        let (path, result) = self.results.recv().ok()?;
+        #[cfg(not(feature = "parallel"))]
+        let (path, result) = {
+            let path = self.results.next()?;
+            let result = load_map(&path, self.options);
+            (path, result)
+        };
+
+        match result {
+            Ok(_) => self.summary.loaded += 1,
+            Err(_) => self.summary.failed += 1,
+        }
+        Some((path, result))
+    }
+}
+
+/// Represents the state of a discovery
+#[derive(Default)]
+struct Discovery {
+    maps: Vec<PathBuf>,
+    errors: Vec<(PathBuf, String)>,
+    /// Canonical paths of the folders already searched, so that symlink cycles end
+    visited: HashSet<PathBuf>,
+}
+
+impl Discovery {
+    /// Adds the path if it's a map, otherwise the maps found in it if it's a folder
+    fn add(&mut self, path: PathBuf) {
+        if path.is_dir() {
+            if ["Info.dat", "info.dat"]
+                .iter()
+                .any(|name| path.join(name).is_file())
+            {
+                self.maps.push(path);
+            } else if let Err(error) = self.add_folder(&path) {
+                self.errors.push((path, error.to_string()));
+            }
+        } else if cfg!(feature = "zip") && is_zip(&path) {
+            self.maps.push(path);
+        }
+    }
+
+    /// Adds the maps found in a folder and its subfolders, unless it was already searched
+    fn add_folder(&mut self, dir: &Path) -> io::Result<()> {
+        if !self.visited.insert(dir.canonicalize()?) {
+            return Ok(());
+        }
+        for entry in std::fs::read_dir(dir)? {
+            self.add(entry?.path());
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether a path has a zip extension, ignoring case
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Loads a map directory or zip archive
+fn load_map(path: &Path, options: ScanOptions) -> MapResult {
+    let result = if path.is_dir() {
+        let source = DirectorySource::new(path);
+        if options.lazy {
+            Beatmap::from_source_lazy(source, options.mode)
+        } else {
+            Beatmap::from_source_with_mode(source, options.mode)
+        }
+    } else {
+        load_zip(path, options)
+    };
+    result.map_err(|error| error.to_string().into())
+}
+
+#[cfg(feature = "zip")]
+fn load_zip(path: &Path, options: ScanOptions) -> Result<Beatmap, Box<dyn Error>> {
+    let source = ZipSource::new(std::fs::File::open(path)?)?;
+    if options.lazy {
+        Beatmap::from_source_lazy(source, options.mode)
+    } else {
+        Beatmap::from_source_with_mode(source, options.mode)
+    }
+}
+
+#[cfg(not(feature = "zip"))]
+fn load_zip(_: &Path, _: ScanOptions) -> Result<Beatmap, Box<dyn Error>> {
+    Err(Box::new(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Zip archives require the zip feature",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Library, ScanOptions, ScanSummary};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Copies the test map to the given directory
+    fn copy_test_map(dir: &Path) {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test");
+        fs::create_dir_all(dir).unwrap();
+        for name in &["info.dat", "Expert.dat", "song.egg"] {
+            fs::copy(resources.join(name), dir.join(name)).unwrap();
+        }
+    }
+
+    fn test_library(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("beatmap-parser-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        copy_test_map(&root.join("1 (Song - Mapper)"));
+        copy_test_map(&root.join("Pack/2 (Song - Mapper)"));
+        copy_test_map(&root.join("3 (Broken - Mapper)"));
+        fs::write(root.join("3 (Broken - Mapper)/Expert.dat"), "{").unwrap();
+        fs::create_dir_all(root.join("Empty")).unwrap();
+        root
+    }
+
+    #[test]
+    fn scan() {
+        let root = test_library("scan");
+        let library = Library::discover(&root).unwrap();
+        assert_eq!(library.maps.len(), 3);
+        assert!(library.errors.is_empty());
+
+        let mut scan = library.load(ScanOptions::default());
+        let mut failed: Vec<_> = scan
+            .by_ref()
+            .filter(|(_, result)| result.is_err())
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(failed.pop(), Some(root.join("3 (Broken - Mapper)")));
+        assert_eq!(
+            scan.summary(),
+            ScanSummary {
+                total: 3,
+                loaded: 2,
+                failed: 1,
+                unreadable: 0
+            }
+        );
+
+        // The broken difficulty isn't read until accessed
+        let options = ScanOptions {
+            lazy: true,
+            ..ScanOptions::default()
+        };
+        let mut scan = Library::discover(&root).unwrap().load(options);
+        assert_eq!(scan.by_ref().count(), 3);
+        assert_eq!(scan.summary().loaded, 3);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn scan_zip() {
+        use std::io::Write;
+
+        let root = test_library("scan-zip");
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test");
+        let mut zip = zip::ZipWriter::new(fs::File::create(root.join("4.zip")).unwrap());
+        for name in &["info.dat", "Expert.dat", "song.egg"] {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(&fs::read(resources.join(name)).unwrap())
+                .unwrap();
+        }
+        zip.finish().unwrap();
+
+        let summary = {
+            let mut scan = Library::scan(&root).unwrap();
+            scan.by_ref().for_each(drop);
+            scan.summary()
+        };
+        assert_eq!(summary.total, 4);
+        assert_eq!(summary.loaded, 3);
+        assert_eq!(summary.failed, 1);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn discover_symlink_cycle() {
+        let root = test_library("symlink-cycle");
+        std::os::unix::fs::symlink(&root, root.join("Pack/Loop")).unwrap();
+
+        let library = Library::discover(&root).unwrap();
+        assert_eq!(library.maps.len(), 3);
+        assert!(library.errors.is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn discover_unreadable_folder() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = test_library("unreadable");
+        let locked = root.join("Locked");
+        fs::create_dir_all(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        // Privileged users can still read the folder
+        if fs::read_dir(&locked).is_err() {
+            let library = Library::discover(&root).unwrap();
+            assert_eq!(library.maps.len(), 3);
+            assert_eq!(library.errors.len(), 1);
+            assert_eq!(library.errors[0].0, locked);
+
+            let mut scan = library.load(ScanOptions::default());
+            let (path, result) = scan.next().unwrap();
+            assert_eq!(path, locked);
+            assert!(result.is_err());
+            scan.by_ref().for_each(drop);
+            assert_eq!(
+                scan.summary(),
+                ScanSummary {
+                    total: 3,
+                    loaded: 2,
+                    failed: 1,
+                    unreadable: 1
+                }
+            );
+        }
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
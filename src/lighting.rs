@@ -0,0 +1,283 @@
+use crate::difficulty::difficulty::event::{EventType, LightValue};
+use crate::difficulty::difficulty::note::NoteType;
+use crate::difficulty::difficulty::{Event, Note};
+use crate::difficulty::Difficulty;
+use std::cmp::Ordering;
+
+/// Represents a lightshow style preset
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum LightingStyle {
+    /// Fading lights, few ring spins and boost only on very dense sections
+    Subtle,
+    /// Balanced lightshow
+    Standard,
+    /// Flashing lights, ring spins on every half beat and fast lasers
+    Intense,
+}
+
+impl LightingStyle {
+    /// Returns the light value used for a note of the given color
+    fn light_value(self, note_type: NoteType) -> LightValue {
+        match (self, note_type) {
+            (LightingStyle::Subtle, NoteType::Red) => LightValue::RedFade,
+            (LightingStyle::Subtle, _) => LightValue::BlueFade,
+            (_, NoteType::Red) => LightValue::RedFlash,
+            (_, _) => LightValue::BlueFlash,
+        }
+    }
+
+    /// Returns the interval between ring spins, in beats
+    fn ring_spin_interval(self) -> f64 {
+        match self {
+            LightingStyle::Subtle => 4.0,
+            LightingStyle::Standard => 1.0,
+            LightingStyle::Intense => 0.5,
+        }
+    }
+
+    /// Returns the note density above which boost colors are enabled, in notes per beat
+    fn boost_density(self) -> f64 {
+        match self {
+            LightingStyle::Subtle => 4.0,
+            LightingStyle::Standard => 3.0,
+            LightingStyle::Intense => 2.0,
+        }
+    }
+
+    /// Returns the maximum laser speed
+    fn max_laser_speed(self) -> u32 {
+        match self {
+            LightingStyle::Subtle => 2,
+            LightingStyle::Standard => 4,
+            LightingStyle::Intense => 8,
+        }
+    }
+}
+
+/// Represents the options of the lightshow generator
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub struct LightingOptions {
+    /// Seed of the random choices, the same seed always generates the same lightshow
+    pub seed: u64,
+    /// Style preset
+    pub style: LightingStyle,
+}
+
+impl Default for LightingOptions {
+    fn default() -> LightingOptions {
+        LightingOptions {
+            seed: 0,
+            style: LightingStyle::Standard,
+        }
+    }
+}
+
+/// Notes closer than this to the previous one, in beats, are part of a stream
+const STREAM_GAP: f64 = 0.25;
+/// Length of the window used to compute the note density, in beats
+const DENSITY_WINDOW: f64 = 4.0;
+/// Maximum number of ring spins generated, so far away notes can't blow up the event list
+const MAX_RING_SPINS: usize = 100_000;
+
+/// SplitMix64 generator, small and good enough for picking lights
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `[min, max]`
+    fn range(&mut self, min: u32, max: u32) -> u32 {
+        min + (self.next() % u64::from(max - min + 1)) as u32
+    }
+}
+
+/// Generates a lightshow from the notes of a difficulty
+///
+/// Lasers flash in the color of the first note of each stream, the back lasers follow isolated
+/// notes, rings spin at a regular interval and boost colors are enabled on dense sections. Every
+/// light is turned off one beat after the last note. Events are returned sorted by time.
+pub fn generate(difficulty: &Difficulty, options: &LightingOptions) -> Vec<Event> {
+    let style = options.style;
+    let mut rng = Rng(options.seed);
+    let notes: Vec<Note> = difficulty
+        .sorted_notes()
+        .into_iter()
+        .filter(|note| note.note_type != NoteType::Bomb && note.time.is_finite())
+        .collect();
+    let (first, last) = match (notes.first(), notes.last()) {
+        (Some(first), Some(last)) => (first.time, last.time),
+        _ => return Vec::new(),
+    };
+
+    let mut events = Vec::new();
+
+    // Lasers on stream starts, back lasers on isolated notes
+    let mut previous_time = None;
+    for (i, note) in notes.iter().enumerate() {
+        let gap_before = previous_time.map(|previous| note.time - previous);
+        previous_time = Some(note.time);
+        if gap_before.is_some_and(|gap| gap <= STREAM_GAP) {
+            continue;
+        }
+        let value = style.light_value(note.note_type);
+
+        let starts_stream = notes
+            .get(i + 1)
+            .is_some_and(|next| next.time - note.time <= STREAM_GAP);
+        if starts_stream {
+            let (lasers, speed) = match note.note_type {
+                NoteType::Red => (EventType::LeftLasers, EventType::LeftLasersSpeed),
+                _ => (EventType::RightLasers, EventType::RightLasersSpeed),
+            };
+            let speed_value = rng.range(1, style.max_laser_speed());
+            events.push(Event::new(note.time, speed, speed_value));
+            events.push(Event::light(note.time, lasers, value));
+        } else {
+            events.push(Event::light(note.time, EventType::BackLasers, value));
+        }
+    }
+
+    // Ring spins and center lights on beats
+    let interval = style.ring_spin_interval();
+    let start = (first / interval).floor() * interval;
+    let spins = (((last - start) / interval).floor() as usize).min(MAX_RING_SPINS - 1) + 1;
+    for i in 0..spins {
+        let beat = start + i as f64 * interval;
+        events.push(Event::new(beat, EventType::RingSpin, 0));
+        if beat % 4.0 == 0.0 {
+            let value = if rng.next() & 1 == 0 {
+                style.light_value(NoteType::Red)
+            } else {
+                style.light_value(NoteType::Blue)
+            };
+            events.push(Event::light(beat, EventType::CenterLights, value));
+        }
+    }
+
+    // Boost on dense sections
+    let mut boosted = false;
+    let mut window_start = 0;
+    for (i, note) in notes.iter().enumerate() {
+        while notes[window_start].time < note.time - DENSITY_WINDOW {
+            window_start += 1;
+        }
+        let density = (i - window_start + 1) as f64 / DENSITY_WINDOW;
+        let dense = density >= style.boost_density();
+        if dense != boosted {
+            boosted = dense;
+            events.push(Event::new(
+                note.time,
+                EventType::BoostColors,
+                u32::from(dense),
+            ));
+        }
+    }
+    if boosted {
+        events.push(Event::new(last + 1.0, EventType::BoostColors, 0));
+    }
+
+    // Everything off at the end
+    for event_type in &[
+        EventType::BackLasers,
+        EventType::RingLights,
+        EventType::LeftLasers,
+        EventType::RightLasers,
+        EventType::CenterLights,
+    ] {
+        events.push(Event::light(last + 1.0, *event_type, LightValue::Off));
+    }
+
+    events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, LightingOptions, LightingStyle};
+    use crate::difficulty::difficulty::event::EventType;
+    use crate::difficulty::difficulty::Note;
+    use crate::test_util::test_difficulty;
+
+    #[test]
+    fn deterministic() {
+        let difficulty = test_difficulty();
+        let options = LightingOptions {
+            seed: 42,
+            style: LightingStyle::Intense,
+        };
+
+        assert_eq!(
+            generate(&difficulty, &options),
+            generate(&difficulty, &options)
+        );
+
+        let other_seed = LightingOptions {
+            seed: 43,
+            ..options
+        };
+        assert_ne!(
+            generate(&difficulty, &options),
+            generate(&difficulty, &other_seed)
+        );
+    }
+
+    #[test]
+    fn ring_spins_on_beats() {
+        let events = generate(&test_difficulty(), &LightingOptions::default());
+        let spins: Vec<f64> = events
+            .iter()
+            .filter(|event| event.typed() == Some(EventType::RingSpin))
+            .map(|event| event.time)
+            .collect();
+
+        assert_eq!(spins, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn boost_on_dense_sections() {
+        let mut difficulty = test_difficulty();
+        let stream: Vec<Note> = (0..32)
+            .map(|i| {
+                let mut note = difficulty.notes[i % 2].clone();
+                note.time = 8.0 + i as f64 * 0.25;
+                note
+            })
+            .collect();
+        difficulty.notes.extend(stream);
+        let events = generate(&difficulty, &LightingOptions::default());
+        let boosts: Vec<u32> = events
+            .iter()
+            .filter(|event| event.typed() == Some(EventType::BoostColors))
+            .map(|event| event.value)
+            .collect();
+
+        assert_eq!(boosts, vec![1, 0]);
+        assert!(events
+            .iter()
+            .any(|event| event.time == 8.0 && event.typed() == Some(EventType::LeftLasers)));
+    }
+
+    #[test]
+    fn far_notes_are_bounded() {
+        let mut difficulty = test_difficulty();
+        let mut far = difficulty.notes[0].clone();
+        far.time = 1e12;
+        let mut nan = difficulty.notes[0].clone();
+        nan.time = f64::NAN;
+        difficulty.notes.extend(vec![far, nan]);
+        let events = generate(&difficulty, &LightingOptions::default());
+        let spins = events
+            .iter()
+            .filter(|event| event.typed() == Some(EventType::RingSpin))
+            .count();
+
+        assert_eq!(spins, super::MAX_RING_SPINS);
+        assert!(events.iter().all(|event| event.time.is_finite()));
+    }
+}
@@ -0,0 +1,539 @@
+use crate::parse::ParseMode;
+use crate::source::{slice_range, DirectorySource, MapSource, MemorySource};
+use crate::{Beatmap, Loader};
+use std::future::{self, Future};
+use std::io::{self, SeekFrom};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+#[cfg(feature = "audio")]
+use crate::audio::{self, AudioInfo};
+
+#[cfg(feature = "beatsaver")]
+use crate::beatsaver::{
+    check_level_hash, is_retryable, BeatSaverInfo, BeatSaverRef, ClientOptions, Cursor, Listing,
+    MapDetail, Page, SearchQuery,
+};
+#[cfg(feature = "beatsaver")]
+use crate::source::ZipError;
+#[cfg(feature = "zip")]
+use crate::source::ZipSource;
+#[cfg(feature = "beatsaver")]
+use std::error::Error;
+
+/// Represents a place the files of a map can be read from asynchronously
+pub trait AsyncMapSource: Send {
+    /// Reads the file with the given name, relative to the map root
+    fn read(&mut self, name: &str) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+
+    /// Returns whether the file with the given name exists
+    fn exists(&mut self, name: &str) -> impl Future<Output = bool> + Send;
+
+    /// Returns the size of the file with the given name, in bytes
+    fn size(&mut self, name: &str) -> impl Future<Output = io::Result<u64>> + Send {
+        async move { Ok(self.read(name).await?.len() as u64) }
+    }
+
+    /// Reads at most `length` bytes of the file with the given name, starting at `offset`
+    fn read_range(
+        &mut self,
+        name: &str,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        async move {
+            let contents = self.read(name).await?;
+            Ok(slice_range(&contents, offset, length).to_vec())
+        }
+    }
+
+    /// Returns the name of the `info.dat` file, which may be capitalized
+    fn info_filename(&mut self) -> impl Future<Output = io::Result<String>> + Send {
+        async move {
+            for name in &["Info.dat", "info.dat"] {
+                if self.exists(name).await {
+                    return Ok(name.to_string());
+                }
+            }
+            Err(io::Error::new(io::ErrorKind::NotFound, "No info.dat file"))
+        }
+    }
+}
+
+impl AsyncMapSource for DirectorySource {
+    fn read(&mut self, name: &str) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        let path = self.path(name);
+        async move { tokio::fs::read(path?).await }
+    }
+
+    fn exists(&mut self, name: &str) -> impl Future<Output = bool> + Send {
+        let path = self.path(name);
+        async move {
+            match path {
+                Ok(path) => tokio::fs::metadata(path)
+                    .await
+                    .is_ok_and(|metadata| metadata.is_file()),
+                Err(_) => false,
+            }
+        }
+    }
+
+    fn size(&mut self, name: &str) -> impl Future<Output = io::Result<u64>> + Send {
+        let path = self.path(name);
+        async move { Ok(tokio::fs::metadata(path?).await?.len()) }
+    }
+
+    fn read_range(
+        &mut self,
+        name: &str,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        let path = self.path(name);
+        async move {
+            let mut file = tokio::fs::File::open(path?).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            let mut contents = Vec::new();
+            file.take(length).read_to_end(&mut contents).await?;
+            Ok(contents)
+        }
+    }
+}
+
+impl AsyncMapSource for MemorySource {
+    fn read(&mut self, name: &str) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        future::ready(MapSource::read(self, name))
+    }
+
+    fn exists(&mut self, name: &str) -> impl Future<Output = bool> + Send {
+        future::ready(MapSource::exists(self, name))
+    }
+
+    fn size(&mut self, name: &str) -> impl Future<Output = io::Result<u64>> + Send {
+        future::ready(MapSource::size(self, name))
+    }
+
+    fn read_range(
+        &mut self,
+        name: &str,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        future::ready(MapSource::read_range(self, name, offset, length))
+    }
+}
+
+/// Archives held in memory are read without blocking
+#[cfg(feature = "zip")]
+impl<T: AsRef<[u8]> + Send> AsyncMapSource for ZipSource<io::Cursor<T>> {
+    fn read(&mut self, name: &str) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        future::ready(MapSource::read(self, name))
+    }
+
+    fn exists(&mut self, name: &str) -> impl Future<Output = bool> + Send {
+        future::ready(MapSource::exists(self, name))
+    }
+
+    fn size(&mut self, name: &str) -> impl Future<Output = io::Result<u64>> + Send {
+        future::ready(MapSource::size(self, name))
+    }
+
+    fn read_range(
+        &mut self,
+        name: &str,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        future::ready(MapSource::read_range(self, name, offset, length))
+    }
+}
+
+impl<S: AsyncMapSource + ?Sized> AsyncMapSource for &mut S {
+    fn read(&mut self, name: &str) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        (**self).read(name)
+    }
+
+    fn exists(&mut self, name: &str) -> impl Future<Output = bool> + Send {
+        (**self).exists(name)
+    }
+
+    fn size(&mut self, name: &str) -> impl Future<Output = io::Result<u64>> + Send {
+        (**self).size(name)
+    }
+
+    fn read_range(
+        &mut self,
+        name: &str,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        (**self).read_range(name, offset, length)
+    }
+}
+
+impl Beatmap {
+    /// Returns a new Beatmap instance from an async map source, parsed with the given mode
+    ///
+    /// The files are read asynchronously and parsed the same way as `Beatmap::from_source_with_mode`,
+    /// on the blocking thread pool of the tokio runtime so large difficulties don't stall it.
+    pub async fn load_async<S: AsyncMapSource>(
+        mut source: S,
+        mode: ParseMode,
+    ) -> io::Result<Beatmap> {
+        let info_filename = source.info_filename().await?;
+        let mut loader = Loader::new(&info_filename, mode, true);
+        while let Some(filename) = loader.next_file() {
+            let contents = source.read(&filename).await?;
+            loader = tokio::task::spawn_blocking(move || {
+                loader.feed(&filename, &contents)?;
+                Ok::<_, io::Error>(loader)
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+        #[cfg(feature = "audio")]
+        {
+            if let Some(filename) = loader.audio_filename() {
+                loader.set_audio(audio_info(&mut source, &filename).await?);
+            }
+        }
+        Ok(loader.finish())
+    }
+}
+
+/// Reads the metadata of an audio file, reading only its start and end
+///
+/// See `AudioInfo::from_source`.
+#[cfg(feature = "audio")]
+async fn audio_info<S: AsyncMapSource>(source: &mut S, name: &str) -> io::Result<AudioInfo> {
+    let file_size = source.size(name).await?;
+    let mut head = source.read_range(name, 0, audio::HEAD_SIZE).await?;
+    while let Some(length) = audio::next_head_length(&head, file_size) {
+        head = source.read_range(name, 0, length).await?;
+    }
+    let tail_offset = file_size.saturating_sub(audio::TAIL_SIZE);
+    let tail = source
+        .read_range(name, tail_offset, audio::TAIL_SIZE)
+        .await?;
+    AudioInfo::from_parts(&head, &tail, file_size).map_err(audio::into_io_error)
+}
+
+/// Represents an async client of the BeatSaver API
+///
+/// Behaves like `BeatSaverClient`, without the download cache.
+#[cfg(feature = "beatsaver")]
+#[derive(Clone, Debug)]
+pub struct AsyncBeatSaverClient {
+    options: ClientOptions,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "beatsaver")]
+impl AsyncBeatSaverClient {
+    /// Returns a new AsyncBeatSaverClient with the given options
+    pub fn new(
+        options: ClientOptions,
+    ) -> Result<AsyncBeatSaverClient, Box<dyn Error + Send + Sync>> {
+        let client = reqwest::Client::builder()
+            .user_agent(options.user_agent.as_str())
+            .timeout(options.timeout)
+            .build()?;
+
+        Ok(AsyncBeatSaverClient { options, client })
+    }
+
+    /// Returns the options of the client
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// Returns the map with the given key
+    pub async fn map_by_id(&self, id: &str) -> Result<MapDetail, Box<dyn Error + Send + Sync>> {
+        let response = self.get(&self.options.map_url(id), &[]).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Returns the map with a version of the given level hash
+    pub async fn map_by_hash(&self, hash: &str) -> Result<MapDetail, Box<dyn Error + Send + Sync>> {
+        let response = self.get(&self.options.map_hash_url(hash), &[]).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Downloads a zip from the given URL
+    ///
+    /// The download stops once it's larger than the archive size limit of the client.
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let max = self.options.zip_limits.max_archive_size;
+        let mut response = self.get(url, &[]).await?;
+        if response.content_length().is_some_and(|length| length > max) {
+            return Err(Box::new(ZipError::ArchiveTooLarge { max }));
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() as u64 + chunk.len() as u64 > max {
+                return Err(Box::new(ZipError::ArchiveTooLarge { max }));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    /// Downloads and parses the zip at the given URL
+    pub async fn beatmap_from_url(
+        &self,
+        url: &str,
+    ) -> Result<Beatmap, Box<dyn Error + Send + Sync>> {
+        let source = ZipSource::with_limits(
+            io::Cursor::new(self.download(url).await?),
+            self.options.zip_limits,
+        )?;
+        Ok(Beatmap::load_async(source, ParseMode::Strict).await?)
+    }
+
+    /// Downloads and parses the latest version of a map
+    pub async fn beatmap(&self, map: &MapDetail) -> Result<Beatmap, Box<dyn Error + Send + Sync>> {
+        let version = map.latest_version().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Map {} has no version", map.id),
+            )
+        })?;
+
+        let mut beatmap = self.beatmap_from_url(&version.download_url).await?;
+        check_level_hash(&beatmap, &version.hash)?;
+        beatmap.beatsaver = Some(BeatSaverInfo {
+            key: map.id.clone(),
+            hash: version.hash.clone(),
+            map: map.clone(),
+        });
+        Ok(beatmap)
+    }
+
+    /// Downloads and parses the latest version of the map with the given key
+    pub async fn beatmap_by_id(&self, id: &str) -> Result<Beatmap, Box<dyn Error + Send + Sync>> {
+        self.beatmap(&self.map_by_id(id).await?).await
+    }
+
+    /// Downloads and parses the version of the map with the given level hash
+    pub async fn beatmap_by_hash(
+        &self,
+        hash: &str,
+    ) -> Result<Beatmap, Box<dyn Error + Send + Sync>> {
+        let map = self.map_by_hash(hash).await?;
+        let mut beatmap = self
+            .beatmap_from_url(&self.options.download_url(hash))
+            .await?;
+        check_level_hash(&beatmap, hash)?;
+        beatmap.beatsaver = Some(BeatSaverInfo {
+            key: map.id.clone(),
+            hash: hash.to_lowercase(),
+            map,
+        });
+        Ok(beatmap)
+    }
+
+    /// Downloads and parses the map referenced by a key, a level hash or a URL
+    pub async fn beatmap_by_ref(
+        &self,
+        reference: &BeatSaverRef,
+    ) -> Result<Beatmap, Box<dyn Error + Send + Sync>> {
+        match reference {
+            BeatSaverRef::Key(key) => self.beatmap_by_id(key).await,
+            BeatSaverRef::Hash(hash) => self.beatmap_by_hash(hash).await,
+        }
+    }
+
+    /// Returns a page of the maps matching a search
+    ///
+    /// The cursor of the next page is returned by `Page::next_cursor`, and so for every listing.
+    pub async fn search(
+        &self,
+        query: &SearchQuery,
+        page: u32,
+    ) -> Result<Page, Box<dyn Error + Send + Sync>> {
+        self.page(&Listing::Search(query.clone()), &Cursor::Page(page))
+            .await
+    }
+
+    /// Returns a page of the latest maps, uploaded before the given date when provided
+    pub async fn latest(&self, before: Option<&str>) -> Result<Page, Box<dyn Error + Send + Sync>> {
+        let cursor = Cursor::Before(before.map(String::from));
+        self.page(&Listing::Latest, &cursor).await
+    }
+
+    /// Returns a page of the maps uploaded by the given user
+    pub async fn maps_by_uploader(
+        &self,
+        user_id: u64,
+        page: u32,
+    ) -> Result<Page, Box<dyn Error + Send + Sync>> {
+        self.page(&Listing::Uploader(user_id), &Cursor::Page(page))
+            .await
+    }
+
+    /// Returns a page of the maps the given user collaborated on, uploaded before the given
+    /// date when provided
+    pub async fn collaborations(
+        &self,
+        user_id: u64,
+        before: Option<&str>,
+    ) -> Result<Page, Box<dyn Error + Send + Sync>> {
+        let cursor = Cursor::Before(before.map(String::from));
+        self.page(&Listing::Collaborations(user_id), &cursor).await
+    }
+
+    /// Requests the page of a listing at the given cursor
+    async fn page(
+        &self,
+        listing: &Listing,
+        cursor: &Cursor,
+    ) -> Result<Page, Box<dyn Error + Send + Sync>> {
+        let (url, parameters) = listing.request(&self.options.base_url, cursor);
+        Ok(self.get(&url, &parameters).await?.json().await?)
+    }
+
+    /// Sends a GET request with the given query string parameters, retrying it according to the
+    /// retry policy
+    async fn get(
+        &self,
+        url: &str,
+        parameters: &[(&str, String)],
+    ) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+        let mut backoff = self.options.retry.backoff;
+        let mut retries = 0;
+        loop {
+            let result = self.client.get(url).query(parameters).send().await;
+            let status = result.as_ref().map(reqwest::Response::status);
+            if !is_retryable(status) || retries >= self.options.retry.max_retries {
+                return Ok(result?.error_for_status()?);
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            retries += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncMapSource;
+    use crate::parse::ParseMode;
+    use crate::source::{DirectorySource, MemorySource};
+    use crate::test_util::test_dir;
+    use crate::Beatmap;
+
+    /// Fails to compile if the future can't be moved to another thread
+    fn assert_send<T: Send>(value: T) -> T {
+        value
+    }
+
+    #[tokio::test]
+    async fn load_async() {
+        let source = DirectorySource::new(test_dir());
+        let beatmap = assert_send(Beatmap::load_async(source, ParseMode::Strict))
+            .await
+            .unwrap();
+        let expected = Beatmap::from_source(DirectorySource::new(test_dir())).unwrap();
+
+        assert_eq!(beatmap.hash, expected.hash);
+        assert_eq!(beatmap.difficulties, expected.difficulties);
+        assert_eq!(beatmap.warnings, expected.warnings);
+
+        let mut source = MemorySource::new();
+        assert!(source.info_filename().await.is_err());
+        source.insert("Info.dat", b"{".to_vec());
+        let error = Beatmap::load_async(&mut source, ParseMode::Strict)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn directory_unsafe_paths() {
+        let mut source = DirectorySource::new(test_dir());
+        assert!(source.exists("info.dat").await);
+
+        for name in &["../test/info.dat", "/etc/hostname"] {
+            assert!(!source.exists(name).await);
+            let error = source.read(name).await.unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(source.size(name).await.is_err());
+            assert!(source.read_range(name, 0, 1).await.is_err());
+        }
+    }
+
+    #[cfg(feature = "beatsaver")]
+    #[tokio::test]
+    async fn async_client() {
+        use super::AsyncBeatSaverClient;
+        use crate::beatsaver::tests::{fixture, mock_server, test_options, test_zip};
+        use crate::beatsaver::{BeatSaverRef, Cursor, SearchQuery};
+
+        let url = mock_server(|request| match request.url() {
+            "/maps/id/3cf5" => (200, fixture("map.json", request)),
+            "/cdn/5812661bf5320a04e14933d861b56fa4d9706104.zip" => (200, test_zip()),
+            url if url.starts_with("/search/text/1?") => (200, fixture("search_1.json", request)),
+            _ => (404, Vec::new()),
+        });
+        let client = AsyncBeatSaverClient::new(test_options(&url)).unwrap();
+
+        let reference = BeatSaverRef::parse("https://beatsaver.com/maps/3cf5/").unwrap();
+        let beatmap = assert_send(client.beatmap_by_ref(&reference))
+            .await
+            .unwrap();
+        assert_eq!(beatmap.beatsaver.unwrap().map.uploader.name, "Mapper");
+        assert_eq!(
+            beatmap.hash.as_deref(),
+            Some("5812661bf5320a04e14933d861b56fa4d9706104")
+        );
+
+        let page = client.search(&SearchQuery::default(), 1).await.unwrap();
+        assert_eq!(page.docs[0].id, "1a2b");
+        assert_eq!(page.next_cursor(&Cursor::Page(1)), None);
+        assert!(client.map_by_id("0000").await.is_err());
+    }
+
+    #[cfg(feature = "beatsaver")]
+    #[tokio::test]
+    async fn async_hash_mismatch() {
+        use super::AsyncBeatSaverClient;
+        use crate::beatsaver::tests::{fixture, mock_server, test_options, test_zip};
+
+        // Every hash is answered with the test map
+        let url = mock_server(|request| {
+            if request.url().starts_with("/maps/hash/") {
+                (200, fixture("map.json", request))
+            } else {
+                (200, test_zip())
+            }
+        });
+        let client = AsyncBeatSaverClient::new(test_options(&url)).unwrap();
+
+        let hash = "5812661bf5320a04e14933d861b56fa4d9706104";
+        assert!(client.beatmap_by_hash(hash).await.is_ok());
+        let error = client.beatmap_by_hash(&"0".repeat(40)).await.unwrap_err();
+        assert!(error.to_string().contains(hash));
+    }
+
+    #[cfg(feature = "beatsaver")]
+    #[tokio::test]
+    async fn async_download_limit() {
+        use super::AsyncBeatSaverClient;
+        use crate::beatsaver::tests::{mock_server, test_options, test_zip};
+        use crate::source::ZipError;
+
+        let url = mock_server(|_| (200, test_zip()));
+        let mut options = test_options(&url);
+        options.zip_limits.max_archive_size = 64;
+        let client = AsyncBeatSaverClient::new(options).unwrap();
+
+        let error = client
+            .download(&format!("{}/cdn/map.zip", url))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ZipError>(),
+            Some(ZipError::ArchiveTooLarge { max: 64 })
+        ));
+    }
+}
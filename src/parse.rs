@@ -0,0 +1,562 @@
+use serde::de::{self, DeserializeOwned, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use serde_path_to_error::Segment;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+
+thread_local! {
+    /// Position and coercions of the file being parsed by `from_slice`
+    static TRACKING: RefCell<Option<Tracking>> = const { RefCell::new(None) };
+}
+
+/// Represents the state of a tracked parse
+#[derive(Default)]
+struct Tracking {
+    /// Whether list elements failing the parse are repaired or dropped instead of failing it
+    lenient: bool,
+    /// Elements of the lists currently being parsed, like `_notes[3]`
+    path: Vec<String>,
+    /// Path and description of each coerced value
+    coercions: Vec<(String, String)>,
+    /// Path and description of each value removed in lenient mode
+    removals: Vec<(String, String)>,
+}
+
+/// Represents how map files are parsed
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub enum ParseMode {
+    /// Any missing or wrongly-typed field fails the parse
+    #[default]
+    Strict,
+    /// Missing or wrongly-typed fields are dropped and reported as warnings
+    ///
+    /// Optional fields fall back to their default, and objects missing a required field are
+    /// dropped from the list containing them. The parse only fails when `info.dat` or a
+    /// difficulty file can't be repaired this way.
+    Lenient,
+}
+
+/// Represents a problem found while parsing a map
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ParseWarning {
+    /// File the problem was found in
+    pub file: String,
+    /// Path of the offending value in the file, like `_notes[3]._lineIndex`
+    pub path: String,
+    /// Description of the problem
+    pub message: String,
+}
+
+/// Parses a JSON file of a map
+///
+/// Coerced numbers are added to the warnings. In lenient mode, list elements failing the parse
+/// are repaired or dropped as they are read, and the other values failing it are removed one at
+/// a time until it succeeds. Each removal is added to the warnings.
+pub(crate) fn from_slice<T: DeserializeOwned>(
+    data: &[u8],
+    file: &str,
+    mode: ParseMode,
+    warnings: &mut Vec<ParseWarning>,
+) -> io::Result<T> {
+    let invalid_data = |error: serde_path_to_error::Error<serde_json::Error>| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, error))
+    };
+    let add_warnings = |warnings: &mut Vec<ParseWarning>, found: Vec<(String, String)>| {
+        warnings.extend(found.into_iter().map(|(path, message)| ParseWarning {
+            file: file.to_string(),
+            path,
+            message,
+        }))
+    };
+
+    if mode == ParseMode::Strict {
+        let deserializer = &mut serde_json::Deserializer::from_slice(data);
+        let (parsed, tracking) = tracked(false, || serde_path_to_error::deserialize(deserializer));
+        let parsed = parsed.map_err(invalid_data)?;
+        add_warnings(warnings, tracking.coercions);
+        return Ok(parsed);
+    }
+
+    let mut value: Value = serde_json::from_slice(data)?;
+    let mut removals = Vec::new();
+    loop {
+        let error = match tracked(true, || serde_path_to_error::deserialize(&value)) {
+            (Ok(parsed), tracking) => {
+                add_warnings(warnings, removals);
+                add_warnings(warnings, tracking.removals);
+                add_warnings(warnings, tracking.coercions);
+                return Ok(parsed);
+            }
+            (Err(error), _) => error,
+        };
+        let path: Vec<_> = error.path().iter().collect();
+        if !remove(&mut value, &path) {
+            return Err(invalid_data(error));
+        }
+        removals.push((error.path().to_string(), error.inner().to_string()));
+    }
+}
+
+/// Runs a parse, returning its result along with the values it coerced or removed
+fn tracked<T>(lenient: bool, parse: impl FnOnce() -> T) -> (T, Tracking) {
+    TRACKING.with(|tracking| {
+        *tracking.borrow_mut() = Some(Tracking {
+            lenient,
+            ..Tracking::default()
+        })
+    });
+    let result = parse();
+    let tracking = TRACKING.with(|tracking| tracking.borrow_mut().take());
+    (result, tracking.unwrap_or_default())
+}
+
+/// Returns the path of the given field within the list element being parsed
+fn field_path(tracking: &Tracking, field: &str) -> String {
+    let mut path = tracking.path.join(".");
+    if !path.is_empty() && !field.is_empty() {
+        path.push('.');
+    }
+    path.push_str(field);
+    path
+}
+
+/// Records a coerced value of the given field, if the parse is tracked
+fn record(field: &str, message: String) {
+    TRACKING.with(|tracking| {
+        if let Some(tracking) = tracking.borrow_mut().as_mut() {
+            let path = field_path(tracking, field);
+            tracking.coercions.push((path, message));
+        }
+    });
+}
+
+/// Parses a list element in lenient mode
+///
+/// Fields failing the parse are removed one at a time until it succeeds, and the element is
+/// dropped with a single warning describing its first error when it can't be repaired this
+/// way. Values coerced or removed by failed attempts are forgotten.
+fn lenient_element<'de, T: Deserialize<'de>>(mut value: Value) -> Option<T> {
+    let lengths = || {
+        TRACKING.with(|tracking| {
+            tracking.borrow().as_ref().map_or((0, 0), |tracking| {
+                (tracking.coercions.len(), tracking.removals.len())
+            })
+        })
+    };
+    let rollback = |(coercions, removals): (usize, usize)| {
+        TRACKING.with(|tracking| {
+            if let Some(tracking) = tracking.borrow_mut().as_mut() {
+                tracking.coercions.truncate(coercions);
+                tracking.removals.truncate(removals);
+            }
+        })
+    };
+    let record_removals = |removals: Vec<(String, String)>| {
+        TRACKING.with(|tracking| {
+            if let Some(tracking) = tracking.borrow_mut().as_mut() {
+                for (field, message) in removals {
+                    let path = field_path(tracking, &field);
+                    tracking.removals.push((path, message));
+                }
+            }
+        })
+    };
+
+    let start = lengths();
+    let mut removals = Vec::new();
+    let mut first_error = None;
+    loop {
+        let error = match serde_path_to_error::deserialize(value.clone()) {
+            Ok(element) => {
+                record_removals(removals);
+                return Some(element);
+            }
+            Err(error) => error,
+        };
+        rollback(start);
+        let first_error = first_error.get_or_insert_with(|| error.to_string());
+        let path: Vec<_> = error.path().iter().collect();
+        if !remove(&mut value, &path) {
+            record_removals(vec![(String::new(), first_error.clone())]);
+            return None;
+        }
+        removals.push((error.path().to_string(), error.inner().to_string()));
+    }
+}
+
+/// Updates the path of the tracked parse, if any
+fn with_path(update: impl FnOnce(&mut Vec<String>)) {
+    TRACKING.with(|tracking| {
+        if let Some(tracking) = tracking.borrow_mut().as_mut() {
+            update(&mut tracking.path);
+        }
+    });
+}
+
+/// Deserializes a list, keeping track of the element being parsed for coercion warnings
+pub(crate) fn list<'de, D, T>(deserializer: D, name: &'static str) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct ListVisitor<T> {
+        name: &'static str,
+        element: PhantomData<T>,
+    }
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for ListVisitor<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a list")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+            let lenient = TRACKING.with(|tracking| {
+                tracking
+                    .borrow()
+                    .as_ref()
+                    .is_some_and(|tracking| tracking.lenient)
+            });
+            let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            let mut index = 0;
+            loop {
+                with_path(|path| path.push(format!("{}[{}]", self.name, index)));
+                let element = if lenient {
+                    seq.next_element::<Value>()?.map(lenient_element)
+                } else {
+                    seq.next_element()?.map(Some)
+                };
+                with_path(|path| {
+                    path.pop();
+                });
+                match element {
+                    Some(Some(element)) => elements.push(element),
+                    Some(None) => {}
+                    None => return Ok(elements),
+                }
+                index += 1;
+            }
+        }
+    }
+
+    deserializer.deserialize_seq(ListVisitor {
+        name,
+        element: PhantomData,
+    })
+}
+
+/// Represents a number as written in a map file
+enum RawNumber {
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl fmt::Display for RawNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawNumber::Integer(integer) => write!(f, "{}", integer),
+            RawNumber::Float(float) => write!(f, "{:?}", float),
+            RawNumber::String(string) => write!(f, "{:?}", string),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RawNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RawNumber, D::Error> {
+        struct NumberVisitor;
+
+        impl Visitor<'_> for NumberVisitor {
+            type Value = RawNumber;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<RawNumber, E> {
+                Ok(RawNumber::Integer(value))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<RawNumber, E> {
+                Ok(i64::try_from(value)
+                    .map(RawNumber::Integer)
+                    .unwrap_or(RawNumber::Float(value as f64)))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<RawNumber, E> {
+                Ok(RawNumber::Float(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<RawNumber, E> {
+                Ok(RawNumber::String(value.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(NumberVisitor)
+    }
+}
+
+/// Deserializes an integer, coercing integral floats and numeric strings
+pub(crate) fn integer<'de, D, T>(deserializer: D, field: &str) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    let integral = |value: f64| {
+        if value.fract() == 0.0 && value.abs() <= i64::MAX as f64 {
+            Some(value as i64)
+        } else {
+            None
+        }
+    };
+
+    let value = RawNumber::deserialize(deserializer)?;
+    let integer = match &value {
+        RawNumber::Integer(integer) => Some(*integer),
+        RawNumber::Float(float) => integral(*float),
+        RawNumber::String(string) => string.trim().parse().ok().and_then(integral),
+    }
+    .ok_or_else(|| de::Error::custom(format!("invalid integer {}", value)))?;
+
+    let result = T::try_from(integer)
+        .map_err(|_| de::Error::custom(format!("integer {} out of range", integer)))?;
+    if !matches!(value, RawNumber::Integer(_)) {
+        record(field, format!("coerced {} to {}", value, integer));
+    }
+    Ok(result)
+}
+
+/// Deserializes a float, coercing numeric strings
+pub(crate) fn float<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    field: &str,
+) -> Result<f64, D::Error> {
+    match RawNumber::deserialize(deserializer)? {
+        RawNumber::Integer(integer) => Ok(integer as f64),
+        RawNumber::Float(float) => Ok(float),
+        RawNumber::String(string) => {
+            let float = string
+                .trim()
+                .parse()
+                .map_err(|_| de::Error::custom(format!("invalid number {:?}", string)))?;
+            record(field, format!("coerced {:?} to {}", string, float));
+            Ok(float)
+        }
+    }
+}
+
+/// Removes the value at the given path, returning whether it existed
+fn remove(value: &mut Value, path: &[&Segment]) -> bool {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    match (value, segment) {
+        (Value::Array(values), Segment::Seq { index }) if *index < values.len() => {
+            if rest.is_empty() {
+                values.remove(*index);
+                true
+            } else {
+                remove(&mut values[*index], rest)
+            }
+        }
+        (Value::Object(values), Segment::Map { key }) if values.contains_key(key) => {
+            if rest.is_empty() {
+                values.remove(key);
+                true
+            } else {
+                remove(&mut values[key.as_str()], rest)
+            }
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_slice, ParseMode};
+    use crate::difficulty::Difficulty;
+    use crate::info::Info;
+
+    const DIFFICULTY: &str = r#"{
+        "_version": "2.0.0",
+        "_events": [],
+        "_notes": [
+            { "_time": 1, "_lineIndex": 1, "_lineLayer": 0, "_type": 0, "_cutDirection": 1 },
+            { "_time": "2", "_lineIndex": 2, "_lineLayer": 0, "_type": 1, "_cutDirection": 1 },
+            { "_time": 3, "_lineIndex": 2, "_lineLayer": 0, "_type": 7, "_cutDirection": 1 }
+        ],
+        "_obstacles": []
+    }"#;
+
+    #[test]
+    fn optional_fields() {
+        let info: Info = serde_json::from_str(
+            r#"{
+                "_version": "2.0.0",
+                "_songName": "Song",
+                "_beatsPerMinute": 120,
+                "_songFilename": "song.egg",
+                "_coverImageFilename": "cover.jpg",
+                "_difficultyBeatmapSets": [{
+                    "_beatmapCharacteristicName": "Standard",
+                    "_difficultyBeatmaps": [{
+                        "_difficulty": "Easy",
+                        "_difficultyRank": 1,
+                        "_beatmapFilename": "Easy.dat"
+                    }]
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(info.shuffle_period, 0.5);
+        assert!(info.custom_data.contributors.is_empty());
+
+        let difficulty: Difficulty = serde_json::from_str(
+            r#"{ "_version": "2.0.0", "_events": [], "_notes": [], "_obstacles": [] }"#,
+        )
+        .unwrap();
+        assert!(difficulty.bpm_changes.is_empty());
+        assert!(difficulty.bookmarks.is_empty());
+    }
+
+    #[test]
+    fn strict() {
+        let mut warnings = Vec::new();
+        let error = from_slice::<Difficulty>(
+            DIFFICULTY.as_bytes(),
+            "Easy.dat",
+            ParseMode::Strict,
+            &mut warnings,
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().starts_with("Easy.dat: _notes[1]._time"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn lenient() {
+        let mut warnings = Vec::new();
+        let difficulty = from_slice::<Difficulty>(
+            DIFFICULTY.as_bytes(),
+            "Easy.dat",
+            ParseMode::Lenient,
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert_eq!(difficulty.notes.len(), 1);
+        let paths: Vec<_> = warnings
+            .iter()
+            .map(|warning| warning.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["_notes[1]", "_notes[2]"]);
+        assert!(warnings[0].message.starts_with("_time: "));
+
+        // Optional fields are dropped without dropping their object
+        warnings.clear();
+        let difficulty = from_slice::<Difficulty>(
+            br#"{
+                "_version": "2.0.0",
+                "_events": [],
+                "_notes": [],
+                "_obstacles": [],
+                "_bookmarks": [{ "_time": 1, "_name": 5 }]
+            }"#,
+            "Easy.dat",
+            ParseMode::Lenient,
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(difficulty.bookmarks.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].path, "_bookmarks[0]._name");
+
+        // The version can't be dropped
+        let result = from_slice::<Difficulty>(
+            br#"{ "_version": 2, "_events": [], "_notes": [], "_obstacles": [] }"#,
+            "Easy.dat",
+            ParseMode::Lenient,
+            &mut warnings,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn lenient_many_bad_objects() {
+        let notes = vec![r#"{ "_time": "bad" }"#; 20000].join(",");
+        let data = format!(
+            r#"{{ "_version": "2.0.0", "_events": [], "_notes": [{}], "_obstacles": [] }}"#,
+            notes
+        );
+
+        let mut warnings = Vec::new();
+        let difficulty = from_slice::<Difficulty>(
+            data.as_bytes(),
+            "Easy.dat",
+            ParseMode::Lenient,
+            &mut warnings,
+        )
+        .unwrap();
+        assert!(difficulty.notes.is_empty());
+        assert_eq!(warnings.len(), 20000);
+        assert_eq!(warnings[19999].path, "_notes[19999]");
+    }
+
+    #[test]
+    fn coercions() {
+        let mut warnings = Vec::new();
+        let difficulty = from_slice::<Difficulty>(
+            br#"{
+                "_version": "2.0.0",
+                "_BPMChanges": [{ "_BPM": "150", "_time": 4 }],
+                "_events": [{ "_time": 0, "_type": 1, "_value": 5.0 }],
+                "_notes": [
+                    { "_time": 1, "_lineIndex": 2.0, "_lineLayer": 0, "_type": 1, "_cutDirection": 1 }
+                ],
+                "_obstacles": [
+                    { "_time": 2, "_lineIndex": 3, "_type": 0, "_duration": 1, "_width": -1 }
+                ]
+            }"#,
+            "Easy.dat",
+            ParseMode::Strict,
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert_eq!(difficulty.bpm_changes[0].bpm, 150.0);
+        assert_eq!(difficulty.events[0].value, 5);
+        assert_eq!(difficulty.notes[0].line_index.to_raw(), 2);
+        assert_eq!(difficulty.obstacles[0].width, -1);
+
+        let paths: Vec<_> = warnings
+            .iter()
+            .map(|warning| warning.path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "_BPMChanges[0]._BPM",
+                "_events[0]._value",
+                "_notes[0]._lineIndex"
+            ]
+        );
+        assert_eq!(warnings[1].message, "coerced 5.0 to 5");
+
+        // Fractional values aren't compatible with integers
+        let result = from_slice::<Difficulty>(
+            br#"{ "_version": "2.0.0", "_events": [{ "_time": 0, "_type": 1, "_value": 1.5 }], "_notes": [], "_obstacles": [] }"#,
+            "Easy.dat",
+            ParseMode::Strict,
+            &mut warnings,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::decode::DecodedAudio;
+use crate::info::Info;
+use std::error::Error;
+use std::io::Cursor;
+
+/// Represents the options of the preview extraction
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct PreviewOptions {
+    /// Length of the fade-in, in seconds
+    pub fade_in: f64,
+    /// Length of the fade-out, in seconds
+    pub fade_out: f64,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> PreviewOptions {
+        PreviewOptions {
+            fade_in: 0.5,
+            fade_out: 1.0,
+        }
+    }
+}
+
+/// Extracts the preview window described by `info.dat` from decoded audio
+///
+/// The window is clipped to the audio, so the preview may be shorter than requested.
+pub fn extract(audio: &DecodedAudio, info: &Info, options: &PreviewOptions) -> DecodedAudio {
+    let sample_rate = f64::from(audio.sample_rate);
+    let to_sample =
+        |seconds: f64| ((seconds.max(0.0) * sample_rate) as usize).min(audio.samples.len());
+
+    let start = to_sample(info.preview_start_time);
+    let end = to_sample(info.preview_start_time + info.preview_duration).max(start);
+    let mut samples = audio.samples[start..end].to_vec();
+
+    let length = samples.len();
+    let fade_in = to_sample(options.fade_in).min(length);
+    let fade_out = to_sample(options.fade_out).min(length);
+    for (i, sample) in samples.iter_mut().enumerate() {
+        if i < fade_in {
+            *sample *= i as f32 / fade_in as f32;
+        }
+        if length - i <= fade_out {
+            *sample *= (length - i - 1) as f32 / fade_out as f32;
+        }
+    }
+
+    DecodedAudio {
+        samples,
+        sample_rate: audio.sample_rate,
+    }
+}
+
+/// Decodes a song and extracts the preview window described by `info.dat`
+pub fn extract_from_bytes(
+    song: &[u8],
+    info: &Info,
+    options: &PreviewOptions,
+) -> Result<DecodedAudio, Box<dyn Error>> {
+    Ok(extract(&DecodedAudio::from_bytes(song)?, info, options))
+}
+
+/// Encodes audio to a mono 16-bit WAV file
+pub fn encode_wav(audio: &DecodedAudio) -> Result<Vec<u8>, Box<dyn Error>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: audio.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut data = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut data, spec)?;
+    for &sample in &audio.samples {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * 32767.0) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(data.into_inner())
+}
+
+/// Encodes audio to a mono OGG Vorbis file
+#[cfg(feature = "encode")]
+pub fn encode_ogg(audio: &DecodedAudio) -> Result<Vec<u8>, Box<dyn Error>> {
+    use crate::audio::invalid_data;
+    use std::num::{NonZeroU32, NonZeroU8};
+    use vorbis_rs::VorbisEncoderBuilder;
+
+    let sample_rate =
+        NonZeroU32::new(audio.sample_rate).ok_or_else(|| invalid_data("Invalid sample rate"))?;
+    let channels = NonZeroU8::new(1).ok_or_else(|| invalid_data("Invalid channel count"))?;
+    let mut encoder =
+        VorbisEncoderBuilder::new_with_serial(sample_rate, channels, Vec::new(), 1).build()?;
+    for block in audio.samples.chunks(4096) {
+        encoder.encode_audio_block([block])?;
+    }
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_wav, extract, PreviewOptions};
+    use crate::decode::DecodedAudio;
+    use crate::test_util::test_info;
+
+    fn constant_audio(duration: f64) -> DecodedAudio {
+        DecodedAudio {
+            samples: vec![0.5; (duration * 8000.0) as usize],
+            sample_rate: 8000,
+        }
+    }
+
+    #[test]
+    fn extract_preview() {
+        // The preview starts at 2 seconds and lasts 4
+        let preview = extract(
+            &constant_audio(10.0),
+            &test_info(),
+            &PreviewOptions::default(),
+        );
+
+        assert_eq!(preview.duration(), 4.0);
+        assert_eq!(preview.samples[0], 0.0);
+        assert_eq!(preview.samples[8000], 0.5);
+        assert_eq!(*preview.samples.last().unwrap(), 0.0);
+        assert!(preview.samples[preview.samples.len() - 4000] < 0.5);
+
+        // Clipped to the end of the audio
+        let preview = extract(
+            &constant_audio(3.0),
+            &test_info(),
+            &PreviewOptions::default(),
+        );
+        assert_eq!(preview.duration(), 1.0);
+    }
+
+    #[test]
+    fn wav_round_trip() {
+        let preview = extract(
+            &constant_audio(10.0),
+            &test_info(),
+            &PreviewOptions::default(),
+        );
+        let decoded = DecodedAudio::from_bytes(&encode_wav(&preview).unwrap()).unwrap();
+
+        assert_eq!(decoded.sample_rate, 8000);
+        assert_eq!(decoded.samples.len(), preview.samples.len());
+    }
+
+    #[cfg(feature = "encode")]
+    #[test]
+    fn ogg_round_trip() {
+        use super::encode_ogg;
+        use crate::audio::AudioInfo;
+
+        let audio = DecodedAudio {
+            samples: (0..44100 * 2)
+                .map(|i| (i as f32 * 0.05).sin() * 0.5)
+                .collect(),
+            sample_rate: 44100,
+        };
+        let ogg = encode_ogg(&audio).unwrap();
+
+        let info = AudioInfo::from_bytes(&ogg).unwrap();
+        assert_eq!(info.channels, 1);
+        assert!((info.duration - 2.0).abs() < 0.01);
+
+        let decoded = DecodedAudio::from_bytes(&ogg).unwrap();
+        assert!((decoded.duration() - 2.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::Loader;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// Represents a place the files of a map can be read from
+pub trait MapSource {
+    /// Reads the file with the given name, relative to the map root
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>>;
+
+    /// Returns whether the file with the given name exists
+    fn exists(&mut self, name: &str) -> bool;
+
+    /// Returns the size of the file with the given name, in bytes
+    fn size(&mut self, name: &str) -> io::Result<u64> {
+        Ok(self.read(name)?.len() as u64)
+    }
+
+    /// Reads at most `length` bytes of the file with the given name, starting at `offset`
+    fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        Ok(slice_range(&self.read(name)?, offset, length).to_vec())
+    }
+
+    /// Returns the name of the `info.dat` file, which may be capitalized
+    fn info_filename(&mut self) -> io::Result<String> {
+        ["Info.dat", "info.dat"]
+            .iter()
+            .find(|name| self.exists(name))
+            .map(|name| name.to_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No info.dat file"))
+    }
+}
+
+/// Represents a map stored in a directory
+#[derive(Clone, Debug)]
+pub struct DirectorySource {
+    root: PathBuf,
+}
+
+impl DirectorySource {
+    /// Returns a new DirectorySource reading from the given directory
+    pub fn new<P: AsRef<Path>>(root: P) -> DirectorySource {
+        DirectorySource {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the directory the map is read from
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the path of the file with the given name
+    ///
+    /// Names which are absolute or go out of the root are rejected with an `UnsafePath` error,
+    /// as they come from `info.dat`.
+    pub fn path(&self, name: &str) -> io::Result<PathBuf> {
+        match enclosed_path(name) {
+            Some(path) => Ok(self.root.join(path)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                UnsafePath(name.to_string()),
+            )),
+        }
+    }
+}
+
+impl MapSource for DirectorySource {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.path(name)?)
+    }
+
+    fn exists(&mut self, name: &str) -> bool {
+        self.path(name).is_ok_and(|path| path.is_file())
+    }
+
+    fn size(&mut self, name: &str) -> io::Result<u64> {
+        Ok(std::fs::metadata(self.path(name)?)?.len())
+    }
+
+    fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut file = std::fs::File::open(self.path(name)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut contents = Vec::new();
+        file.take(length).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+}
+
+/// Represents a file name rejected for being absolute or going out of the map root
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct UnsafePath(pub String);
+
+impl fmt::Display for UnsafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File {} has an unsafe path", self.0)
+    }
+}
+
+impl Error for UnsafePath {}
+
+/// Uncompressed size above which the compression ratio of a zip entry is checked
+#[cfg(feature = "zip")]
+const RATIO_THRESHOLD: u64 = 1024 * 1024;
+
+/// Represents the limits a zip archive must fit in to be read
+#[cfg(feature = "zip")]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ZipLimits {
+    /// Largest size of the archive itself, in bytes, also enforced while downloading it
+    pub max_archive_size: u64,
+    /// Largest total uncompressed size of the entries, in bytes
+    pub max_size: u64,
+    /// Largest number of entries
+    pub max_entries: usize,
+    /// Largest ratio between the uncompressed and compressed size of an entry, only checked
+    /// for entries over 1 MiB
+    pub max_ratio: u64,
+}
+
+#[cfg(feature = "zip")]
+impl Default for ZipLimits {
+    fn default() -> ZipLimits {
+        ZipLimits {
+            max_archive_size: 64 * 1024 * 1024,
+            max_size: 256 * 1024 * 1024,
+            max_entries: 1024,
+            max_ratio: 100,
+        }
+    }
+}
+
+/// Represents a zip archive rejected for being unreadable or unsafe
+#[cfg(feature = "zip")]
+#[derive(Debug)]
+pub enum ZipError {
+    /// The archive couldn't be read
+    Io(io::Error),
+    /// The archive is invalid
+    Archive(String),
+    /// The archive itself is larger than allowed
+    ArchiveTooLarge { max: u64 },
+    /// The archive has more entries than allowed
+    TooManyEntries { count: usize, max: usize },
+    /// The entries are larger than allowed once uncompressed
+    TooLarge { size: u64, max: u64 },
+    /// An entry is compressed more than allowed, as zip bombs are
+    CompressionRatio {
+        name: String,
+        size: u64,
+        compressed_size: u64,
+    },
+    /// An entry is larger than the size declared in the archive
+    SizeMismatch { name: String, declared: u64 },
+    /// An entry has an absolute path or a path going out of the archive
+    UnsafePath(String),
+}
+
+#[cfg(feature = "zip")]
+impl fmt::Display for ZipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZipError::Io(error) => write!(f, "Couldn't read zip: {}", error),
+            ZipError::Archive(message) => write!(f, "Invalid zip: {}", message),
+            ZipError::ArchiveTooLarge { max } => {
+                write!(f, "Zip is larger than the {} bytes allowed", max)
+            }
+            ZipError::TooManyEntries { count, max } => {
+                write!(f, "Zip has {} entries, at most {} are allowed", count, max)
+            }
+            ZipError::TooLarge { size, max } => write!(
+                f,
+                "Zip uncompresses to {} bytes, at most {} are allowed",
+                size, max
+            ),
+            ZipError::CompressionRatio {
+                name,
+                size,
+                compressed_size,
+            } => write!(
+                f,
+                "Zip entry {} uncompresses from {} to {} bytes",
+                name, compressed_size, size
+            ),
+            ZipError::SizeMismatch { name, declared } => write!(
+                f,
+                "Zip entry {} is larger than its declared {} bytes",
+                name, declared
+            ),
+            ZipError::UnsafePath(name) => write!(f, "Zip entry {} has an unsafe path", name),
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+impl Error for ZipError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ZipError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<io::Error> for ZipError {
+    fn from(error: io::Error) -> ZipError {
+        ZipError::Io(error)
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for ZipError {
+    fn from(error: zip::result::ZipError) -> ZipError {
+        match error {
+            zip::result::ZipError::Io(error) => ZipError::Io(error),
+            error => ZipError::Archive(error.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<ZipError> for io::Error {
+    fn from(error: ZipError) -> io::Error {
+        match error {
+            ZipError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+/// Represents a map stored in a zip archive
+///
+/// Archives are checked against their limits when opened, and entries are never read past
+/// their declared size.
+#[cfg(feature = "zip")]
+pub struct ZipSource<R: Read + Seek> {
+    archive: zip::ZipArchive<R>,
+    limits: ZipLimits,
+}
+
+#[cfg(feature = "zip")]
+impl<R: Read + Seek> ZipSource<R> {
+    /// Returns a new ZipSource reading from the given archive, with the default limits
+    pub fn new(reader: R) -> Result<ZipSource<R>, ZipError> {
+        ZipSource::with_limits(reader, ZipLimits::default())
+    }
+
+    /// Returns a new ZipSource reading from the given archive, rejecting it if it doesn't fit
+    /// in the given limits or has an unsafe path
+    pub fn with_limits(mut reader: R, limits: ZipLimits) -> Result<ZipSource<R>, ZipError> {
+        if reader.seek(SeekFrom::End(0))? > limits.max_archive_size {
+            return Err(ZipError::ArchiveTooLarge {
+                max: limits.max_archive_size,
+            });
+        }
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut archive = zip::ZipArchive::new(reader)?;
+        if archive.len() > limits.max_entries {
+            return Err(ZipError::TooManyEntries {
+                count: archive.len(),
+                max: limits.max_entries,
+            });
+        }
+
+        let mut total_size: u64 = 0;
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i)?;
+            if enclosed_path(file.name()).is_none() {
+                return Err(ZipError::UnsafePath(file.name().to_string()));
+            }
+
+            let (size, compressed_size) = (file.size(), file.compressed_size());
+            if size > RATIO_THRESHOLD && size > compressed_size.saturating_mul(limits.max_ratio) {
+                return Err(ZipError::CompressionRatio {
+                    name: file.name().to_string(),
+                    size,
+                    compressed_size,
+                });
+            }
+            total_size = total_size.saturating_add(size);
+        }
+        if total_size > limits.max_size {
+            return Err(ZipError::TooLarge {
+                size: total_size,
+                max: limits.max_size,
+            });
+        }
+
+        Ok(ZipSource { archive, limits })
+    }
+
+    /// Returns the limits the archive was checked against
+    pub fn limits(&self) -> &ZipLimits {
+        &self.limits
+    }
+
+    /// Extracts the files of the archive to the given directory, returning their paths
+    ///
+    /// Every file is written inside the directory, as entries with unsafe paths were rejected
+    /// when opening the archive.
+    pub fn extract<P: AsRef<Path>>(&mut self, directory: P) -> Result<Vec<PathBuf>, ZipError> {
+        let directory = directory.as_ref();
+        let mut paths = Vec::new();
+        for i in 0..self.archive.len() {
+            let file = self.archive.by_index(i)?;
+            let path = match enclosed_path(file.name()) {
+                Some(path) => directory.join(path),
+                None => return Err(ZipError::UnsafePath(file.name().to_string())),
+            };
+
+            if file.is_dir() {
+                std::fs::create_dir_all(&path)?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, read_entry(file)?)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(feature = "zip")]
+impl<R: Read + Seek> MapSource for ZipSource<R> {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        Ok(read_entry(self.archive.by_name(name)?)?)
+    }
+
+    fn exists(&mut self, name: &str) -> bool {
+        self.archive.by_name(name).is_ok()
+    }
+
+    fn size(&mut self, name: &str) -> io::Result<u64> {
+        Ok(self.archive.by_name(name)?.size())
+    }
+
+    fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        // Entries can't be seeked, the bytes before the range are decompressed and skipped
+        let mut file = self.archive.by_name(name)?;
+        io::copy(&mut file.by_ref().take(offset), &mut io::sink())?;
+        let mut contents = Vec::new();
+        file.take(length).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+}
+
+/// Returns the path of a zip entry or map file relative to the root, if it stays inside of it
+fn enclosed_path(name: &str) -> Option<PathBuf> {
+    // Backslashes are separators for zips made on Windows
+    let name = name.replace('\\', "/");
+    if name.starts_with('/') || name.contains('\0') {
+        return None;
+    }
+
+    let mut path = PathBuf::new();
+    for component in Path::new(&name).components() {
+        match component {
+            Component::Normal(part) if !part.to_string_lossy().contains(':') => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Reads a zip entry, rejecting it if it's larger than its declared size
+#[cfg(feature = "zip")]
+fn read_entry(file: zip::read::ZipFile) -> Result<Vec<u8>, ZipError> {
+    let declared = file.size();
+    let name = file.name().to_string();
+    let mut contents = Vec::with_capacity(declared.min(RATIO_THRESHOLD) as usize);
+    file.take(declared + 1).read_to_end(&mut contents)?;
+    if contents.len() as u64 > declared {
+        return Err(ZipError::SizeMismatch { name, declared });
+    }
+    Ok(contents)
+}
+
+/// Represents a map held in memory, like an upload
+#[derive(Clone, Default, Debug)]
+pub struct MemorySource {
+    /// Contents of each file, by name
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+impl MemorySource {
+    /// Returns a new empty MemorySource
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    /// Adds a file to the map
+    pub fn insert<N: Into<String>, C: Into<Vec<u8>>>(&mut self, name: N, contents: C) {
+        self.files.insert(name.into(), contents.into());
+    }
+}
+
+impl MapSource for MemorySource {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        self.files.get(name).cloned().ok_or_else(|| not_found(name))
+    }
+
+    fn exists(&mut self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+
+    fn size(&mut self, name: &str) -> io::Result<u64> {
+        self.files
+            .get(name)
+            .map(|contents| contents.len() as u64)
+            .ok_or_else(|| not_found(name))
+    }
+
+    fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        self.files
+            .get(name)
+            .map(|contents| slice_range(contents, offset, length).to_vec())
+            .ok_or_else(|| not_found(name))
+    }
+}
+
+/// Returns an error for a file missing from a map held in memory
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No file {}", name))
+}
+
+/// Returns at most `length` bytes of the data, starting at `offset`
+pub(crate) fn slice_range(data: &[u8], offset: u64, length: u64) -> &[u8] {
+    let start = offset.min(data.len() as u64) as usize;
+    let end = offset.saturating_add(length).min(data.len() as u64) as usize;
+    &data[start..end]
+}
+
+/// Returns the level hash of a map, as found in `Beatmap::hash`
+///
+/// The level hash is the SHA1 of `info.dat` followed by the difficulty files, as used by
+/// BeatSaver. Only `info.dat` is parsed, to find the difficulty files.
+pub fn level_hash(source: &mut dyn MapSource) -> Result<String, Box<dyn Error>> {
+    let mut loader = Loader::hashing(&source.info_filename()?);
+    while let Some(filename) = loader.next_file() {
+        let contents = source.read(&filename)?;
+        loader.feed(&filename, &contents)?;
+    }
+    Ok(loader.hash())
+}
+
+impl<S: MapSource + ?Sized> MapSource for &mut S {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        (**self).read(name)
+    }
+
+    fn exists(&mut self, name: &str) -> bool {
+        (**self).exists(name)
+    }
+
+    fn size(&mut self, name: &str) -> io::Result<u64> {
+        (**self).size(name)
+    }
+
+    fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        (**self).read_range(name, offset, length)
+    }
+}
+
+impl<S: MapSource + ?Sized> MapSource for Box<S> {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        (**self).read(name)
+    }
+
+    fn exists(&mut self, name: &str) -> bool {
+        (**self).exists(name)
+    }
+
+    fn size(&mut self, name: &str) -> io::Result<u64> {
+        (**self).size(name)
+    }
+
+    fn read_range(&mut self, name: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        (**self).read_range(name, offset, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirectorySource, MapSource, UnsafePath};
+    use crate::test_util::test_dir;
+
+    #[cfg(feature = "zip")]
+    use super::{ZipError, ZipLimits, ZipSource};
+    #[cfg(feature = "zip")]
+    use crate::Beatmap;
+    #[cfg(feature = "zip")]
+    use std::fs;
+    #[cfg(feature = "zip")]
+    use std::io::{Cursor, Write};
+    #[cfg(feature = "zip")]
+    use std::path::PathBuf;
+    #[cfg(feature = "zip")]
+    use zip::write::FileOptions;
+
+    #[test]
+    fn directory_unsafe_paths() {
+        let mut source = DirectorySource::new(test_dir());
+        assert!(source.exists("info.dat"));
+        assert!(source.exists("./info.dat"));
+
+        for name in &[
+            "../test/info.dat",
+            "../../Cargo.toml",
+            "/etc/hostname",
+            "..\\test\\info.dat",
+        ] {
+            assert!(!source.exists(name));
+            let error = source.read(name).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            let path = error.get_ref().unwrap().downcast_ref::<UnsafePath>();
+            assert_eq!(path, Some(&UnsafePath(name.to_string())));
+            assert!(source.size(name).is_err());
+            assert!(source.read_range(name, 0, 1).is_err());
+        }
+    }
+
+    /// Returns a zip of the given files
+    #[cfg(feature = "zip")]
+    fn zip_of(files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        Cursor::new(zip.finish().unwrap().into_inner())
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_limits() {
+        let files: &[(&str, &[u8])] = &[("a.dat", b"{}"), ("b.dat", b"{}"), ("c.dat", b"{}")];
+        let limits = ZipLimits {
+            max_entries: 2,
+            ..ZipLimits::default()
+        };
+        assert!(matches!(
+            ZipSource::with_limits(zip_of(files), limits),
+            Err(ZipError::TooManyEntries { count: 3, max: 2 })
+        ));
+
+        let limits = ZipLimits {
+            max_archive_size: 16,
+            ..ZipLimits::default()
+        };
+        assert!(matches!(
+            ZipSource::with_limits(zip_of(files), limits),
+            Err(ZipError::ArchiveTooLarge { max: 16 })
+        ));
+
+        let limits = ZipLimits {
+            max_size: 5,
+            ..ZipLimits::default()
+        };
+        assert!(matches!(
+            ZipSource::with_limits(zip_of(files), limits),
+            Err(ZipError::TooLarge { size: 6, max: 5 })
+        ));
+
+        let zeros = vec![0; 4 * 1024 * 1024];
+        assert!(matches!(
+            ZipSource::new(zip_of(&[("bomb.dat", &zeros)])),
+            Err(ZipError::CompressionRatio { .. })
+        ));
+
+        for name in &[
+            "../Info.dat",
+            "maps/../../Info.dat",
+            "/Info.dat",
+            "..\\Info.dat",
+            "C:/Info.dat",
+        ] {
+            match ZipSource::new(zip_of(&[(name, b"{}")])) {
+                Err(ZipError::UnsafePath(path)) => assert_eq!(&path, name),
+                _ => panic!("{} wasn't rejected", name),
+            }
+        }
+
+        let error: std::io::Error = ZipSource::new(Cursor::new(b"{}".to_vec()))
+            .err()
+            .unwrap()
+            .into();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn extract() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test");
+        let names = ["info.dat", "Expert.dat", "song.egg"];
+        let contents: Vec<_> = names
+            .iter()
+            .map(|name| fs::read(resources.join(name)).unwrap())
+            .collect();
+        let files: Vec<_> = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, contents)| (*name, contents.as_slice()))
+            .collect();
+        let mut source = ZipSource::new(zip_of(&files)).unwrap();
+
+        let root =
+            std::env::temp_dir().join(format!("beatmap-parser-extract-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let paths = source.extract(&root).unwrap();
+        let expected: Vec<_> = names.iter().map(|name| root.join(name)).collect();
+        assert_eq!(paths, expected);
+
+        let extracted = Beatmap::from_source(super::DirectorySource::new(&root)).unwrap();
+        let expected = Beatmap::from_source(source).unwrap();
+        assert_eq!(extracted.hash, expected.hash);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+use crate::audio::invalid_data;
+use crate::decode::{DecodedAudio, OnsetEnvelope};
+use crate::difficulty::Difficulty;
+use crate::info::Info;
+use std::cmp::Ordering;
+use std::error::Error;
+
+/// Lowest BPM considered by the estimator
+const MIN_BPM: f64 = 60.0;
+/// Highest BPM considered by the estimator
+const MAX_BPM: f64 = 200.0;
+/// Step of the fine BPM search
+const BPM_STEP: f64 = 0.01;
+/// Number of phase bins used when folding the onset envelope over a beat
+const PHASE_BINS: usize = 64;
+/// Highest number of beats compared by the drift measurement
+const MAX_DRIFT_BEATS: usize = 100_000;
+
+/// Represents an estimated tempo
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct TempoEstimate {
+    /// Estimated BPM
+    pub bpm: f64,
+    /// Time of the first beat, in seconds, between 0 and the length of a beat
+    pub offset: f64,
+    /// Ratio of the onset energy falling on the estimated beats, between 0 and 1
+    pub confidence: f64,
+}
+
+/// Represents the difference between a declared beat and the detected one
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct DriftPoint {
+    /// Declared beat
+    pub beat: f64,
+    /// Time of the declared beat, in seconds
+    pub expected: f64,
+    /// Time of the closest onset, in seconds
+    pub detected: f64,
+}
+
+impl DriftPoint {
+    /// Returns the drift in seconds, positive when the music is late
+    pub fn drift(&self) -> f64 {
+        self.detected - self.expected
+    }
+}
+
+/// Represents the drift between the declared timing of a difficulty and its song
+#[derive(PartialEq, Clone, Debug)]
+pub struct DriftReport {
+    /// Declared beats having an onset close enough
+    pub points: Vec<DriftPoint>,
+    /// Mean drift, in seconds
+    pub mean_drift: f64,
+    /// Largest absolute drift, in seconds
+    pub max_drift: f64,
+    /// Change of the drift over time, in seconds per second
+    ///
+    /// A positive rate means the declared BPM is too high.
+    pub drift_rate: f64,
+}
+
+/// Estimates the tempo and beat phase of decoded audio
+pub fn estimate(audio: &DecodedAudio) -> Option<TempoEstimate> {
+    estimate_from_envelope(&audio.onset_envelope())
+}
+
+/// Estimates the tempo and beat phase from an onset envelope
+///
+/// The period is first found by autocorrelation, then refined by folding the envelope over
+/// candidate beat lengths and keeping the one concentrating the most energy on a single phase.
+pub fn estimate_from_envelope(envelope: &OnsetEnvelope) -> Option<TempoEstimate> {
+    let mean = envelope.values.iter().sum::<f32>() / envelope.values.len().max(1) as f32;
+    let values: Vec<f64> = envelope
+        .values
+        .iter()
+        .map(|&value| f64::from((value - mean).max(0.0)))
+        .collect();
+    let frame_rate = envelope.frame_rate;
+
+    // Coarse period from the autocorrelation
+    let min_lag = (60.0 * frame_rate / MAX_BPM).floor().max(1.0) as usize;
+    let max_lag = (60.0 * frame_rate / MIN_BPM).ceil() as usize;
+    if values.len() <= max_lag * 2 {
+        return None;
+    }
+    let autocorrelation = |lag: usize| -> f64 {
+        values
+            .iter()
+            .zip(values[lag..].iter())
+            .map(|(a, b)| a * b)
+            .sum()
+    };
+    let lag = (min_lag..=max_lag).max_by(|&a, &b| {
+        autocorrelation(a)
+            .partial_cmp(&autocorrelation(b))
+            .unwrap_or(Ordering::Equal)
+    })?;
+    let coarse_bpm = 60.0 * frame_rate / lag as f64;
+
+    // Fine period and phase by folding
+    let coarse_step = coarse_bpm - 60.0 * frame_rate / (lag as f64 + 1.0);
+    let steps = (coarse_step / BPM_STEP).ceil() as i64;
+    let total: f64 = values.iter().sum();
+    let (bpm, phase, energy) = (-steps..=steps)
+        .map(|step| {
+            let bpm = coarse_bpm + step as f64 * BPM_STEP;
+            let (phase, energy) = fold(&values, 60.0 * frame_rate / bpm);
+            (bpm, phase, energy)
+        })
+        .max_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal))?;
+
+    let beat_length = 60.0 / bpm;
+    Some(TempoEstimate {
+        bpm,
+        offset: (envelope.offset + phase / frame_rate).rem_euclid(beat_length),
+        confidence: if total > 0.0 { energy / total } else { 0.0 },
+    })
+}
+
+/// Folds values over a period, returning the phase in frames and the energy of the best bin
+fn fold(values: &[f64], period: f64) -> (f64, f64) {
+    let mut bins = [0.0; PHASE_BINS];
+    for (i, value) in values.iter().enumerate() {
+        let phase = (i as f64 / period).fract();
+        bins[(phase * PHASE_BINS as f64) as usize % PHASE_BINS] += value;
+    }
+
+    (0..PHASE_BINS)
+        .map(|bin| {
+            let energy = bins[(bin + PHASE_BINS - 1) % PHASE_BINS]
+                + bins[bin]
+                + bins[(bin + 1) % PHASE_BINS];
+            let phase = (bin as f64 + 0.5) / PHASE_BINS as f64 * period;
+            (phase, energy)
+        })
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+        .unwrap_or((0.0, 0.0))
+}
+
+/// Compares the declared beats of a difficulty with the detected onsets
+///
+/// Every whole beat until the end of the song is matched with the closest onset less than a
+/// quarter of a beat away, following the BPM changes of the difficulty. Beats are placed in the
+/// audio after the song time offset of `info.dat`, and at most 100000 beats are compared.
+pub fn measure_drift(
+    onsets: &[f64],
+    difficulty: &Difficulty,
+    info: &Info,
+    duration: f64,
+) -> Result<DriftReport, Box<dyn Error>> {
+    let bpm = info.beats_per_minute;
+    if !(bpm > 0.0 && bpm.is_finite()) {
+        return Err(invalid_data("Invalid BPM"));
+    }
+    if !duration.is_finite() {
+        return Err(invalid_data("Invalid duration"));
+    }
+    if !info.song_time_offset.is_finite() {
+        return Err(invalid_data("Invalid song time offset"));
+    }
+    let mut onsets: Vec<f64> = onsets
+        .iter()
+        .cloned()
+        .filter(|onset| onset.is_finite())
+        .collect();
+    onsets.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    let to_seconds = |beat: f64| info.song_time_offset + difficulty.beat_to_seconds(beat, bpm);
+    let mut points = Vec::new();
+    for beat in (0..MAX_DRIFT_BEATS).map(|beat| beat as f64) {
+        let expected = to_seconds(beat);
+        if !expected.is_finite() || expected > duration {
+            break;
+        }
+        let tolerance = (to_seconds(beat + 1.0) - expected).abs() / 4.0;
+
+        let index = onsets.partition_point(|&onset| onset < expected);
+        let detected = [index.checked_sub(1), Some(index)]
+            .iter()
+            .filter_map(|&i| onsets.get(i?))
+            .cloned()
+            .filter(|onset| (onset - expected).abs() <= tolerance)
+            .min_by(|a, b| {
+                (a - expected)
+                    .abs()
+                    .partial_cmp(&(b - expected).abs())
+                    .unwrap_or(Ordering::Equal)
+            });
+        if let Some(detected) = detected {
+            points.push(DriftPoint {
+                beat,
+                expected,
+                detected,
+            });
+        }
+    }
+
+    let count = points.len() as f64;
+    let mean_drift = if points.is_empty() {
+        0.0
+    } else {
+        points.iter().map(DriftPoint::drift).sum::<f64>() / count
+    };
+    let max_drift = points
+        .iter()
+        .map(|point| point.drift().abs())
+        .fold(0.0, f64::max);
+
+    // Least squares slope of the drift over time
+    let mean_time = points.iter().map(|point| point.expected).sum::<f64>() / count.max(1.0);
+    let (covariance, variance) = points.iter().fold((0.0, 0.0), |(c, v), point| {
+        let dt = point.expected - mean_time;
+        (c + dt * (point.drift() - mean_drift), v + dt * dt)
+    });
+
+    Ok(DriftReport {
+        points,
+        mean_drift,
+        max_drift,
+        drift_rate: if variance > 0.0 {
+            covariance / variance
+        } else {
+            0.0
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate, measure_drift};
+    use crate::decode::tests::clicks_wav;
+    use crate::decode::DecodedAudio;
+    use crate::difficulty::difficulty::BPMChange;
+    use crate::test_util::{test_difficulty, test_info};
+
+    fn clicks(bpm: f64, offset: f64, duration: f64) -> Vec<f64> {
+        let beat_length = 60.0 / bpm;
+        (0..)
+            .map(|beat| offset + f64::from(beat) * beat_length)
+            .take_while(|&time| time < duration - 0.1)
+            .collect()
+    }
+
+    #[test]
+    fn estimate_tempo() {
+        let wav = clicks_wav(22050, 12.0, &clicks(128.0, 0.2, 12.0));
+        let audio = DecodedAudio::from_bytes(&wav).unwrap();
+        let tempo = estimate(&audio).unwrap();
+
+        assert!((tempo.bpm - 128.0).abs() < 0.5, "{}", tempo.bpm);
+        assert!((tempo.offset - 0.2).abs() < 0.03, "{}", tempo.offset);
+        assert!(tempo.confidence > 0.5);
+    }
+
+    #[test]
+    fn drift() {
+        let difficulty = test_difficulty();
+        let mut info = test_info();
+        info.beats_per_minute = 120.0;
+
+        let onsets = clicks(120.0, 0.0, 30.0);
+        let report = measure_drift(&onsets, &difficulty, &info, 30.0).unwrap();
+        assert_eq!(report.points.len(), onsets.len());
+        assert!(report.max_drift < 1e-9);
+
+        // Declared too fast, the music falls behind
+        info.beats_per_minute = 121.0;
+        let report = measure_drift(&onsets, &difficulty, &info, 30.0).unwrap();
+        assert!(report.drift_rate > 0.0);
+        assert!(report.max_drift > 0.1);
+
+        // The song time offset delays every beat
+        let onsets = clicks(120.0, 0.1, 30.0);
+        info.beats_per_minute = 120.0;
+        info.song_time_offset = 0.1;
+        let report = measure_drift(&onsets, &difficulty, &info, 30.0).unwrap();
+        assert_eq!(report.points.len(), onsets.len());
+        assert!(report.max_drift < 1e-9);
+    }
+
+    #[test]
+    fn drift_invalid_timing() {
+        let mut difficulty = test_difficulty();
+        let mut info = test_info();
+
+        info.beats_per_minute = -120.0;
+        assert!(measure_drift(&[], &difficulty, &info, 30.0).is_err());
+        info.beats_per_minute = 120.0;
+        assert!(measure_drift(&[], &difficulty, &info, f64::NAN).is_err());
+
+        // A negative BPM change walks backwards and must still end
+        difficulty.bpm_changes.push(BPMChange {
+            bpm: -120.0,
+            time: 4.0,
+            beats_per_bar: 4,
+            metronome_offset: 4,
+        });
+        assert!(measure_drift(&[], &difficulty, &info, 30.0).is_ok());
+    }
+}
@@ -0,0 +1,25 @@
+use crate::difficulty::Difficulty;
+#[cfg(any(feature = "cover", feature = "decode"))]
+use crate::info::Info;
+use std::path::PathBuf;
+
+/// Returns the directory of the test map
+pub(crate) fn test_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test")
+}
+
+/// Returns the contents of the Expert difficulty file of the test map
+pub(crate) fn test_difficulty_bytes() -> Vec<u8> {
+    std::fs::read(test_dir().join("Expert.dat")).unwrap()
+}
+
+/// Returns the Expert difficulty of the test map
+pub(crate) fn test_difficulty() -> Difficulty {
+    serde_json::from_slice(&test_difficulty_bytes()).unwrap()
+}
+
+/// Returns the `info.dat` file of the test map
+#[cfg(any(feature = "cover", feature = "decode"))]
+pub(crate) fn test_info() -> Info {
+    serde_json::from_slice(&std::fs::read(test_dir().join("info.dat")).unwrap()).unwrap()
+}